@@ -0,0 +1,678 @@
+//! The home page: saved playlists, where to import more from, duplicates and stats.
+
+use crate::view::{self, el, Component, Html, Link, VElement};
+use crate::{
+    button, demo_alert, fetch, fetch_scores, js_error, open, query_with_body, spotify_alert, table,
+    Client, Page,
+};
+use regex::Regex;
+use songsort::api::{
+    self, Action, ActionQuery, Api, ApiError, ImportSource, PlaylistQuery, PublishQuery,
+    SavedItems, TimeRange,
+};
+use songsort::dedupe::{DuplicateGroup, Duplicates, MergeRequest};
+use songsort::stats::Leaderboard;
+use songsort::{Library, Playlist, Playlists, ProviderId, RatingScope};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::{FileList, FormData};
+
+pub struct HomePage {
+    client: Client,
+    /// What's typed into the import box
+    import: String,
+    /// Whether imports get ratings of their own
+    separate: bool,
+    /// The files chosen for upload
+    files: Option<FileList>,
+    /// Bumped after an upload, which replaces the file input to clear it
+    uploads: u32,
+    playlists: Option<Playlists>,
+    /// What each saved playlist's Go button does, if something other than a random match was
+    /// chosen
+    actions: HashMap<String, String>,
+    /// What can be imported from Spotify, as its name and link
+    spotify: Vec<(String, String, ImportSource)>,
+    library: Option<Library>,
+    /// Only found when asked for
+    duplicates: Option<Duplicates>,
+    artists: Leaderboard,
+    albums: Leaderboard,
+}
+
+impl HomePage {
+    pub fn new(client: Client) -> HomePage {
+        HomePage {
+            client,
+            import: String::from(
+                "https://open.spotify.com/playlist/37i9dQZF1DX49jUV2NfGku?si=379bbc586c78450a",
+            ),
+            separate: false,
+            files: None,
+            uploads: 0,
+            playlists: None,
+            actions: HashMap::new(),
+            spotify: Vec::new(),
+            library: None,
+            duplicates: None,
+            artists: Leaderboard::default(),
+            albums: Leaderboard::default(),
+        }
+    }
+
+    fn import_form(&self, link: &Link<HomePage>) -> Html {
+        let (link, client) = (link.clone(), self.client.clone());
+        let input = el("input")
+            .attr("type", "text")
+            .id("input")
+            .class("col-7")
+            .value(&self.import)
+            .on("input", {
+                let link = link.clone();
+                move |event| {
+                    let value = view::target_value(&event);
+                    link.set(|page| page.import = value);
+                }
+            });
+        let separate = el("input")
+            .attr("type", "checkbox")
+            .id("separate")
+            .class("form-check-input")
+            .checked(self.separate)
+            .on("change", {
+                let link = link.clone();
+                move |event| {
+                    let checked = view::target_input(&event).map_or(false, |i| i.checked());
+                    link.set(|page| page.separate = checked);
+                }
+            });
+        let save_button = button("col-1 btn btn-success", "Save").spawn_on("click", {
+            let (link, client) = (link.clone(), client.clone());
+            move || import(link.clone(), client.clone())
+        });
+        let files = el("input")
+            .key(format!("upload{}", self.uploads))
+            .attr("type", "file")
+            .id("upload")
+            .attr("accept", ".m3u,.m3u8,.pls,.xspf")
+            .flag("multiple", true)
+            .class("col-7 form-control-file")
+            .on("change", {
+                let link = link.clone();
+                move |event| {
+                    let files = view::target_input(&event).and_then(|i| i.files());
+                    link.set(|page| page.files = files);
+                }
+            });
+        let upload_button = button("col-1 offset-2 btn btn-success", "Upload")
+            .spawn_on("click", move || upload(link.clone(), client.clone()));
+        el("form")
+            .child(
+                el("div")
+                    .class("row")
+                    .child(input)
+                    .child(
+                        el("div").class("col-2 form-check").child(separate).child(
+                            el("label")
+                                .class("form-check-label")
+                                .attr("for", "separate")
+                                .text("Rate separately"),
+                        ),
+                    )
+                    .child(save_button),
+            )
+            .child(
+                el("div")
+                    .class("row mt-2")
+                    .child(files)
+                    .child(upload_button),
+            )
+            .into()
+    }
+
+    fn playlist_rows(&self, link: &Link<HomePage>) -> Vec<Html> {
+        match &self.playlists {
+            None => Vec::new(),
+            Some(playlists) if playlists.items.is_empty() => vec![el("p")
+                .text("Import a playlist and choose an option to start sorting songs!")
+                .into()],
+            Some(playlists) => playlists
+                .items
+                .iter()
+                .map(|p| self.playlist_row(link, p))
+                .collect(),
+        }
+    }
+
+    fn playlist_row(&self, link: &Link<HomePage>, playlist: &Playlist) -> Html {
+        let id = playlist.id.clone();
+        let mut name = el("a").text(&playlist.name);
+        if let Some(url) = ProviderId::parse(&playlist.playlist_id).spotify_url() {
+            name = name.attr("href", url);
+        }
+        let action = self.actions.get(&id).map_or("random", String::as_str);
+        let select = el("select")
+            .class("form-select")
+            .child(el("option").attr("value", "random").text("Random match"))
+            .child(
+                el("option")
+                    .attr("value", "spotify")
+                    .text("Save ranking to Spotify"),
+            )
+            .value(action)
+            .on("change", {
+                let (link, id) = (link.clone(), id.clone());
+                move |event| {
+                    let (id, value) = (id.clone(), view::target_value(&event));
+                    link.set(|page| {
+                        page.actions.insert(id, value);
+                    });
+                }
+            });
+        let (link, client) = (link.clone(), self.client.clone());
+        let go_button = button("btn btn-success col-1 me-2", "Go").spawn_on("click", {
+            let (link, client, id) = (link.clone(), client.clone(), id.clone());
+            move || go(link.clone(), client.clone(), id.clone())
+        });
+        let sync_button = button("btn btn-secondary col-1 me-2", "Sync").spawn_on("click", {
+            let (client, id) = (client.clone(), id.clone());
+            move || sync(client.clone(), id.clone())
+        });
+        let unsave_button = button("btn btn-danger col-1", "Unsave").spawn_on("click", {
+            let id = id.clone();
+            move || unsave(link.clone(), client.clone(), id.clone())
+        });
+        el("div")
+            .class("row")
+            .key(id)
+            .child(el("label").class("col-6").child(name))
+            .child(el("div").class("col-2").child(select))
+            .child(go_button)
+            .child(sync_button)
+            .child(unsave_button)
+            .into()
+    }
+
+    fn spotify_rows(&self, link: &Link<HomePage>) -> Vec<Html> {
+        self.spotify
+            .iter()
+            .map(|(name, href, source)| {
+                let label = if href.is_empty() {
+                    el("label").class("col-9").text(name)
+                } else {
+                    el("label")
+                        .class("col-9")
+                        .child(el("a").attr("href", href).text(name))
+                };
+                el("div")
+                    .class("row")
+                    .child(label)
+                    .child(self.save_button(link, source))
+                    .into()
+            })
+            .collect()
+    }
+
+    fn library_rows(&self, link: &Link<HomePage>) -> Vec<Html> {
+        let library = match &self.library {
+            Some(library) => library,
+            None => return Vec::new(),
+        };
+        // Albums are imported as albums and folders as playlists
+        let sources = library
+            .albums
+            .iter()
+            .map(|a| (a, ImportSource::Album(a.id.clone())))
+            .chain(
+                library
+                    .folders
+                    .iter()
+                    .map(|f| (f, ImportSource::Playlist(f.id.clone()))),
+            );
+        sources
+            .map(|(entry, source)| {
+                let name = match &entry.artist {
+                    Some(artist) => format!("{} - {}", artist, entry.name),
+                    None => format!("{}/", entry.name),
+                };
+                el("div")
+                    .class("row")
+                    .key(&entry.id)
+                    .child(
+                        el("label")
+                            .class("col-9 truncate")
+                            .text(format!("{} ({} songs)", name, entry.tracks)),
+                    )
+                    .child(self.save_button(link, &source))
+                    .into()
+            })
+            .collect()
+    }
+
+    fn save_button(&self, link: &Link<HomePage>, source: &ImportSource) -> VElement {
+        let (link, client, source) = (link.clone(), self.client.clone(), source.clone());
+        button("btn btn-success col-1", "Save").spawn_on("click", move || {
+            save(link.clone(), client.clone(), source.clone())
+        })
+    }
+
+    fn duplicate_rows(&self, link: &Link<HomePage>) -> Vec<Html> {
+        match &self.duplicates {
+            None => Vec::new(),
+            Some(duplicates) if duplicates.groups.is_empty() => {
+                vec![el("p").text("No duplicates found").into()]
+            }
+            Some(duplicates) => duplicates
+                .groups
+                .iter()
+                .map(|group| self.duplicate_row(link, group))
+                .collect(),
+        }
+    }
+
+    /// A group of scores that look like the same song, with a button that merges them into the
+    /// most played one.
+    fn duplicate_row(&self, link: &Link<HomePage>, group: &DuplicateGroup) -> Html {
+        let keep = &group.scores[0];
+        let releases: Vec<_> = group
+            .scores
+            .iter()
+            .map(|s| format!("{} ({}, {}-{})", s.album, s.score, s.wins, s.losses))
+            .collect();
+        let message = format!(
+            "Merge {} songs into {} from {}? Their records and ratings will be combined.",
+            group.scores.len(),
+            keep.track,
+            keep.album
+        );
+        let merge = MergeRequest {
+            keep: keep.id.clone(),
+            merge: group.scores[1..].iter().map(|s| s.id.clone()).collect(),
+        };
+        let (link, client) = (link.clone(), self.client.clone());
+        el("div")
+            .class("row")
+            .key(&keep.id)
+            .child(el("label").class("col-9 truncate").text(format!(
+                "{} - {}: {}",
+                keep.artists.join(", "),
+                keep.track,
+                releases.join(" / ")
+            )))
+            .child(
+                button("btn btn-warning col-1", "Merge").spawn_on("click", move || {
+                    merge_scores(link.clone(), client.clone(), message.clone(), merge.clone())
+                }),
+            )
+            .into()
+    }
+}
+
+impl Component for HomePage {
+    fn init(&mut self, link: &Link<HomePage>) -> Result<(), JsValue> {
+        let (link, client) = (link.clone(), self.client.clone());
+        wasm_bindgen_futures::spawn_local(async move { refresh(&link, &client).await.unwrap() });
+        Ok(())
+    }
+
+    fn view(&self, link: &Link<HomePage>) -> Html {
+        let library_empty = self
+            .library
+            .as_ref()
+            .map_or(true, |l| l.albums.is_empty() && l.folders.is_empty());
+        let find =
+            button("col-2 btn btn-outline-secondary", "Find duplicates").spawn_on("click", {
+                let (link, client) = (link.clone(), self.client.clone());
+                move || {
+                    let (link, client) = (link.clone(), client.clone());
+                    async move { load_duplicates(&link, &client).await }
+                }
+            });
+        el("div")
+            .id("home")
+            .child(el("h1").text("Saved Playlists"))
+            .child(el("div").id("playlists").children(self.playlist_rows(link)))
+            .child(el("div").class("row").child(self.import_form(link)))
+            .child(el("h1").text("My Spotify Playlists"))
+            .child(el("div").id("spotify").children(self.spotify_rows(link)))
+            // Only shown if the server has a music library
+            .child(
+                el("h1")
+                    .id("library-header")
+                    .flag("hidden", library_empty)
+                    .text("Music Library"),
+            )
+            .child(el("div").id("library").children(self.library_rows(link)))
+            .child(el("h1").text("Duplicates"))
+            .child(el("div").class("row").child(find))
+            .child(
+                el("div")
+                    .id("duplicates")
+                    .children(self.duplicate_rows(link)),
+            )
+            .child(el("h1").text("Stats"))
+            .child(
+                el("div")
+                    .class("row")
+                    .child(
+                        el("div")
+                            .class("col-6")
+                            .child(el("h2").text("Artists"))
+                            .child(leaderboard(&self.artists, "Artist")),
+                    )
+                    .child(
+                        el("div")
+                            .class("col-6")
+                            .child(el("h2").text("Albums"))
+                            .child(leaderboard(&self.albums, "Album")),
+                    ),
+            )
+            .into()
+    }
+}
+
+/// Rank artists or albums in a stats table, with their details shown on hover.
+fn leaderboard(leaderboard: &Leaderboard, kind: &str) -> Html {
+    let rows = leaderboard
+        .entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let name = match &entry.artist {
+                Some(artist) => format!("{} ({})", entry.name, artist),
+                None => entry.name.clone(),
+            };
+            let details = format!(
+                "{} tracks, {} matches, mean {}, median {}",
+                entry.tracks, entry.games, entry.mean, entry.median
+            );
+            el("tr")
+                .child(el("th").text((i + 1).to_string()))
+                .child(el("td").attr("title", details).text(name))
+                .child(el("td").text(entry.weighted.to_string()))
+                .into()
+        })
+        .collect();
+    table(&[("col-1", "#"), ("col-8", kind), ("", "Score")], rows)
+}
+
+/// Load everything on the page except duplicates, which are only found when asked for.
+async fn refresh(link: &Link<HomePage>, client: &Client) -> Result<(), JsValue> {
+    load_playlists(link, client).await?;
+    load_spotify(link, client).await?;
+    load_library(link, client).await?;
+    let query = PlaylistQuery::default();
+    let artists = client.get_artist_stats(&query).await.map_err(js_error)?;
+    let albums = client.get_album_stats(&query).await.map_err(js_error)?;
+    link.update(|page| {
+        page.artists = artists;
+        page.albums = albums;
+    })
+}
+
+async fn load_playlists(link: &Link<HomePage>, client: &Client) -> Result<(), JsValue> {
+    let playlists = client.get_playlists().await.map_err(js_error)?;
+    link.update(|page| page.playlists = Some(playlists))
+}
+
+async fn load_spotify(link: &Link<HomePage>, client: &Client) -> Result<(), JsValue> {
+    let playlists = match client.get_spotify_playlists().await {
+        Ok(playlists) => playlists,
+        Err(ApiError::Status(403)) => {
+            web_sys::console::log_1(&JsValue::from("Not supported in demo"));
+            return Ok(());
+        }
+        // The rest of the page still works without Spotify
+        Err(ApiError::Status(api::SPOTIFY_UNAUTHORIZED)) => {
+            web_sys::console::log_1(&JsValue::from("Spotify refused the user's authorization"));
+            return Ok(());
+        }
+        Err(e) => return Err(js_error(e)),
+    };
+    let mut sources = vec![
+        (
+            String::from("Liked Songs"),
+            String::from("https://open.spotify.com/collection/tracks"),
+            ImportSource::Saved(SavedItems::Tracks),
+        ),
+        (
+            String::from("Top Tracks (4 Weeks)"),
+            String::new(),
+            ImportSource::Top(TimeRange::ShortTerm),
+        ),
+        (
+            String::from("Top Tracks (6 Months)"),
+            String::new(),
+            ImportSource::Top(TimeRange::MediumTerm),
+        ),
+        (
+            String::from("Top Tracks (All Time)"),
+            String::new(),
+            ImportSource::Top(TimeRange::LongTerm),
+        ),
+    ];
+    sources.extend(playlists.items.into_iter().map(|p| {
+        (
+            p.name,
+            format!("https://open.spotify.com/playlist/{}", p.playlist_id),
+            ImportSource::Playlist(p.playlist_id),
+        )
+    }));
+    link.update(|page| page.spotify = sources)
+}
+
+async fn load_library(link: &Link<HomePage>, client: &Client) -> Result<(), JsValue> {
+    let library = match client.get_library().await {
+        Ok(library) => library,
+        // Servers without a music library don't have one to list
+        Err(ApiError::Status(_)) => return Ok(()),
+        Err(e) => return Err(js_error(e)),
+    };
+    link.update(|page| page.library = Some(library))
+}
+
+async fn load_duplicates(link: &Link<HomePage>, client: &Client) -> Result<(), JsValue> {
+    let duplicates = client.get_duplicates().await.map_err(js_error)?;
+    link.update(|page| page.duplicates = Some(duplicates))
+}
+
+/// Import what's typed into the import box, which can be a link to a Spotify playlist, album or
+/// artist.
+async fn import(link: Link<HomePage>, client: Client) -> Result<(), JsValue> {
+    let input = match link.with(|page| page.import.clone()) {
+        Some(input) => input,
+        None => return Ok(()),
+    };
+    let playlist_re = Regex::new(r"https://open.spotify.com/playlist/([[:alnum:]]*)").unwrap();
+    let album_re = Regex::new(r"https://open.spotify.com/album/([[:alnum:]]*)").unwrap();
+    let artist_re = Regex::new(r"https://open.spotify.com/artist/([[:alnum:]]*)").unwrap();
+    let source = if let Some(id) = playlist_re.captures_iter(&input).next() {
+        ImportSource::Playlist(id[1].to_owned())
+    } else if let Some(id) = album_re.captures_iter(&input).next() {
+        ImportSource::Album(id[1].to_owned())
+    } else if let Some(id) = artist_re.captures_iter(&input).next() {
+        ImportSource::Artist(id[1].to_owned())
+    } else {
+        let window = web_sys::window().expect("no global `window` exists");
+        return window.alert_with_message("Enter a link to a Spotify playlist, album or artist");
+    };
+    save(link, client, source).await
+}
+
+/// Import with ratings of their own if "Rate separately" is ticked. Otherwise tracks share their
+/// ratings with every other playlist.
+async fn save(link: Link<HomePage>, client: Client, source: ImportSource) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    let separate = link.with(|page| page.separate).unwrap_or(false);
+    let query = ActionQuery {
+        action: Action::Import,
+        scope: if separate {
+            RatingScope::Playlist
+        } else {
+            RatingScope::Global
+        },
+        source,
+    };
+    match client.import(&query).await {
+        Ok(()) => load_playlists(&link, &client).await?,
+        Err(ApiError::Status(403)) => demo_alert(&window)?,
+        Err(ApiError::Status(api::SPOTIFY_UNAUTHORIZED)) => spotify_alert(&window)?,
+        // TODO: error handling
+        Err(ApiError::Status(_)) => {}
+        Err(e) => return Err(js_error(e)),
+    }
+    Ok(())
+}
+
+async fn upload(link: Link<HomePage>, client: Client) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    let files = match link.with(|page| page.files.clone()).flatten() {
+        Some(files) if files.length() > 0 => files,
+        _ => return Ok(()),
+    };
+    let form = FormData::new()?;
+    for i in 0..files.length() {
+        let file = files.item(i).unwrap();
+        form.append_with_blob_and_filename("file", &file, &file.name())?;
+    }
+    // Forms aren't JSON, so they're sent without the API client
+    let request = query_with_body(
+        "/api/uploads",
+        "POST",
+        client.auth.as_deref(),
+        Some(form.as_ref()),
+    )?;
+    let resp = fetch(&window, &request).await?;
+    match resp.status() {
+        403 => {
+            demo_alert(&window)?;
+        }
+        400 => {
+            window.alert_with_message("Only M3U, PLS and XSPF playlists can be uploaded")?;
+        }
+        201 => {
+            link.update(|page| {
+                page.files = None;
+                page.uploads += 1;
+            })?;
+            load_playlists(&link, &client).await?;
+        }
+        // TODO: error handling
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Start a random match in a playlist, or write its ranking to Spotify if that was chosen.
+async fn go(link: Link<HomePage>, client: Client, id: String) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    let action = link.with(|page| page.actions.get(&id).cloned()).flatten();
+    if action.as_deref() == Some("spotify") {
+        return publish_playlist(&client, &id).await;
+    }
+    let scores = fetch_scores(&client, &id).await?;
+    if scores.scores.len() < 2 {
+        window.alert_with_message("Playlist has less than 2 songs")
+    } else {
+        open(&Page::RandomMatch(id))
+    }
+}
+
+/// Write the ranking to a Spotify playlist, asking how many songs to include.
+async fn publish_playlist(client: &Client, id: &str) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    let limit = match window.prompt_with_message("How many songs? Leave empty for all")? {
+        // Cancelled
+        None => return Ok(()),
+        Some(limit) if limit.trim().is_empty() => None,
+        Some(limit) => match limit.trim().parse::<usize>() {
+            Ok(limit) => Some(limit),
+            Err(_) => {
+                return window.alert_with_message("Enter a number of songs");
+            }
+        },
+    };
+    let query = PublishQuery { limit, name: None };
+    match client.publish_playlist(id, &query).await {
+        Ok(published) => {
+            window.open_with_url_and_target(&published.url, "_blank")?;
+        }
+        Err(ApiError::Status(api::SPOTIFY_UNAUTHORIZED)) => {
+            spotify_alert(&window)?;
+        }
+        Err(ApiError::Status(403)) => {
+            demo_alert(&window)?;
+        }
+        // TODO: error handling
+        Err(ApiError::Status(_)) => {}
+        Err(e) => return Err(js_error(e)),
+    }
+    Ok(())
+}
+
+async fn sync(client: Client, id: String) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    match client.sync_playlist(&id).await {
+        Ok(sync) => {
+            window.alert_with_message(&format!(
+                "Added {} songs and archived {} songs",
+                sync.added.len(),
+                sync.removed.len()
+            ))?;
+        }
+        Err(ApiError::Status(403)) => {
+            demo_alert(&window)?;
+        }
+        Err(ApiError::Status(api::SPOTIFY_UNAUTHORIZED)) => {
+            spotify_alert(&window)?;
+        }
+        Err(ApiError::Status(400)) | Err(ApiError::Status(404)) => {
+            window.alert_with_message("Only Spotify playlists can be synced")?;
+        }
+        // TODO: error handling
+        Err(ApiError::Status(_)) => {}
+        Err(e) => return Err(js_error(e)),
+    }
+    Ok(())
+}
+
+async fn unsave(link: Link<HomePage>, client: Client, id: String) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    match client.delete_playlist(&id).await {
+        Ok(()) => {
+            load_playlists(&link, &client).await?;
+        }
+        Err(ApiError::Status(403)) => {
+            demo_alert(&window)?;
+        }
+        // TODO: error handling
+        Err(ApiError::Status(_)) => {}
+        Err(e) => return Err(js_error(e)),
+    }
+    Ok(())
+}
+
+async fn merge_scores(
+    link: Link<HomePage>,
+    client: Client,
+    message: String,
+    merge: MergeRequest,
+) -> Result<(), JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    if !window.confirm_with_message(&message)? {
+        return Ok(());
+    }
+    match client.merge_scores(&merge).await {
+        Ok(_) => {
+            refresh(&link, &client).await?;
+            load_duplicates(&link, &client).await?;
+        }
+        Err(ApiError::Status(403)) => {
+            demo_alert(&window)?;
+        }
+        // TODO: error handling
+        Err(ApiError::Status(_)) => {}
+        Err(e) => return Err(js_error(e)),
+    }
+    Ok(())
+}
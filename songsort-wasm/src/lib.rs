@@ -1,32 +1,97 @@
 #![feature(async_closure)]
-use rand::prelude::SliceRandom;
-use regex::Regex;
-use songsort::{Playlists, Score, Scores};
+use async_trait::async_trait;
+use home::HomePage;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use random::RandomPage;
+use songsort::api::{self, Api, ApiError, ScoresQuery, SortKey};
+use songsort::{ProviderId, Score, Scores};
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::rc::Rc;
+use track::TrackPage;
+use view::{el, Html, VElement, View};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    Document, Element, HtmlAnchorElement, HtmlButtonElement, HtmlIFrameElement, HtmlInputElement,
-    Request, RequestInit, RequestMode, Response, UrlSearchParams, Window,
+    HtmlAnchorElement, HtmlButtonElement, Request, RequestInit, RequestMode, Response,
+    UrlSearchParams, Window,
 };
 
+mod home;
+mod random;
+mod track;
+mod view;
+
+/// Whether the user has a session. The session itself is in a cookie that scripts can't read.
+const LOGGED_IN_KEY: &str = "songsort-logged-in";
+
+/// Where earlier versions kept the credential itself.
+const OLD_AUTH_KEY: &str = "songsort-auth";
+
 struct State {
     current_page: Page,
-    playlist: Option<String>, // TODO: do we still need this?
-    auth: String,
-    home: Option<Element>,
-    random_match: Option<Element>,
-    queued_scores: Vec<Score>,
+    /// The page that's shown. Dropping it removes the page along with its event listeners.
+    view: Option<View>,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum Page {
     Login,
     Home,
     RandomMatch(String),
+    /// A track's rating, in a playlist's scope if it has one
+    Track {
+        id: String,
+        playlist: Option<String>,
+    },
+}
+
+impl Page {
+    /// The URL fragment that links to this page.
+    fn hash(&self) -> String {
+        match self {
+            Page::Login | Page::Home => String::from("#/"),
+            Page::RandomMatch(id) => format!("#/playlists/{}/match", encode(id)),
+            Page::Track {
+                id,
+                playlist: Some(playlist),
+            } => format!("#/playlists/{}/tracks/{}", encode(playlist), encode(id)),
+            Page::Track { id, playlist: None } => format!("#/tracks/{}", encode(id)),
+        }
+    }
+
+    /// Find the page that a URL fragment links to. Unknown links go home.
+    fn from_hash(hash: &str) -> Page {
+        let path = hash.trim_start_matches('#').trim_start_matches('/');
+        let segments: Vec<_> = path.split('/').map(decode).collect();
+        match &segments[..] {
+            [playlists, id, page] if playlists == "playlists" && page == "match" => {
+                Page::RandomMatch(id.clone())
+            }
+            [playlists, playlist, tracks, id] if playlists == "playlists" && tracks == "tracks" => {
+                Page::Track {
+                    id: id.clone(),
+                    playlist: Some(playlist.clone()),
+                }
+            }
+            [tracks, id] if tracks == "tracks" => Page::Track {
+                id: id.clone(),
+                playlist: None,
+            },
+            _ => Page::Home,
+        }
+    }
+}
+
+fn encode(segment: &str) -> String {
+    js_sys::encode_uri_component(segment).into()
+}
+
+fn decode(segment: &str) -> String {
+    js_sys::decode_uri_component(segment)
+        .map(String::from)
+        .unwrap_or_else(|_| segment.to_owned())
 }
 
 // Called by our JS entry point to run the example
@@ -36,43 +101,51 @@ pub async fn run() -> Result<(), JsValue> {
     let document = window.document().expect("should have a document on window");
     let state = Rc::new(RefCell::new(State {
         current_page: Page::Login,
-        playlist: None,
-        auth: String::new(),
-        home: None,
-        random_match: None,
-        queued_scores: Vec::new(),
+        view: None,
     }));
+    // Links, and the back and forward buttons, change the URL fragment
     let state_ref = Rc::clone(&state);
     let a = Closure::wrap(Box::new(move || {
         let state = Rc::clone(&state_ref);
-        wasm_bindgen_futures::spawn_local(async {
-            switch_pages(state, Page::Home).await.unwrap();
-        });
-        true
-    }) as Box<dyn FnMut() -> bool>);
-    document
-        .get_element_by_id("brand")
-        .ok_or_else(|| JsValue::from("brand element missing"))?
-        .dyn_into::<HtmlAnchorElement>()?
-        .set_onclick(Some(a.as_ref().unchecked_ref()));
+        if state.borrow().current_page == Page::Login {
+            return;
+        }
+        let window = web_sys::window().expect("no global `window` exists");
+        let page = Page::from_hash(&window.location().hash().unwrap());
+        switch_pages(state, page).unwrap();
+    }) as Box<dyn FnMut()>);
+    window.set_onhashchange(Some(a.as_ref().unchecked_ref()));
     a.forget();
-    let q = window.location().search()?;
-    let params = UrlSearchParams::new_with_str(&q)?;
-    if let Some(code) = params.get("code") {
-        state.borrow_mut().auth = code.clone();
-        let request = query("/api/login", "POST", &state.borrow().auth)?;
-        let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-        let resp: Response = resp_value.dyn_into()?;
-        if resp.status() == 401 {
+    let location = window.location();
+    // Links to a page open it once the user is logged in
+    let linked_page = Page::from_hash(&location.hash()?);
+    let storage = window
+        .local_storage()?
+        .ok_or_else(|| JsValue::from("local storage missing"))?;
+    storage.remove_item(OLD_AUTH_KEY)?;
+    let params = UrlSearchParams::new_with_str(&location.search()?)?;
+    let logged_in = if let Some(code) = params.get("code") {
+        // Codes can only be exchanged once, so reloading shouldn't send it again
+        window.history()?.replace_state_with_url(
+            &JsValue::NULL,
+            "",
+            Some(&format!("{}{}", location.pathname()?, location.hash()?)),
+        )?;
+        let logged_in = log_in(&window, &Client::for_code(&code)).await?;
+        if !logged_in {
             window.alert_with_message("Please contact bngo92@gmail.com for support")?;
-        } else {
-            switch_pages(state, Page::Home).await?;
         }
+        logged_in
+    } else {
+        storage.get_item(LOGGED_IN_KEY)?.is_some()
+    };
+    if logged_in {
+        switch_pages(state, linked_page)?;
     } else {
         let a = Closure::wrap(Box::new(move || {
             let window = web_sys::window().expect("no global `window` exists");
             let location = window.location();
-            location.set_href(&format!("https://accounts.spotify.com/authorize?client_id=ee3d1b4f8d80477ea48743a511ef3018&redirect_uri={}&response_type=code", location.origin().unwrap().as_str())).unwrap();
+            location.set_href(&format!("https://accounts.spotify.com/authorize?client_id=ee3d1b4f8d80477ea48743a511ef3018&redirect_uri={}&response_type=code&scope=user-library-read%20user-top-read%20playlist-modify-public%20playlist-modify-private", location.origin().unwrap().as_str())).unwrap();
         }) as Box<dyn FnMut()>);
         document
             .get_element_by_id("login")
@@ -83,9 +156,20 @@ pub async fn run() -> Result<(), JsValue> {
         let state_ref = Rc::clone(&state);
         let a = Closure::wrap(Box::new(move || {
             let state = Rc::clone(&state_ref);
-            state.borrow_mut().auth = String::from("demo");
-            wasm_bindgen_futures::spawn_local(async {
-                switch_pages(state, Page::Home).await.unwrap();
+            let page = linked_page.clone();
+            // Each visit ranks in its own sandbox so the shared demo data stays intact
+            let visitor: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect();
+            // The session keeps the sandbox across reloads
+            let client = Client::for_code(&format!("demo:{}", visitor));
+            wasm_bindgen_futures::spawn_local(async move {
+                let window = web_sys::window().expect("no global `window` exists");
+                if log_in(&window, &client).await.unwrap() {
+                    switch_pages(state, page).unwrap();
+                }
             });
         }) as Box<dyn FnMut()>);
         document
@@ -98,10 +182,11 @@ pub async fn run() -> Result<(), JsValue> {
     Ok(())
 }
 
-async fn switch_pages(state: Rc<RefCell<State>>, next_page: Page) -> Result<(), JsValue> {
+fn switch_pages(state: Rc<RefCell<State>>, next_page: Page) -> Result<(), JsValue> {
     if state.borrow().current_page == next_page {
         return Ok(());
     }
+    let hash = next_page.hash();
     let window = web_sys::window().expect("no global `window` exists");
     let document = window.document().expect("should have a document on window");
     let main = document
@@ -111,52 +196,23 @@ async fn switch_pages(state: Rc<RefCell<State>>, next_page: Page) -> Result<(),
         .get_element_by_id("navbar")
         .ok_or_else(|| JsValue::from("navbar element missing"))?;
     let mut borrowed_state = state.borrow_mut();
-    // TODO: Clean up closures and one-off elements
+    borrowed_state.view = None;
     match borrowed_state.current_page {
-        Page::Home => {
+        // The login page is part of the index
+        Page::Login => {
             if let Some(child) = main.first_element_child() {
                 child.remove();
-                borrowed_state.home = Some(child);
             }
         }
         Page::RandomMatch(_) => {
-            if let Some(child) = main.first_element_child() {
-                child.remove();
-                borrowed_state.random_match = Some(child);
-            }
             navbar.children().item(1).unwrap().remove();
-            borrowed_state.queued_scores.clear();
-        }
-        Page::Login => {
-            if let Some(child) = main.first_element_child() {
-                child.remove();
-            }
         }
+        Page::Home | Page::Track { .. } => {}
     }
-    drop(borrowed_state);
-    match next_page {
-        Page::Home => {
-            let mut borrowed_state = state.borrow_mut();
-            let element = if let Some(element) = borrowed_state.home.take() {
-                element
-            } else {
-                web_sys::console::log_1(&JsValue::from("Generating home page"));
-                generate_home_page(&state).await?
-            };
-            main.append_child(&element)?;
-            borrowed_state.current_page = Page::Home;
-            drop(borrowed_state);
-            refresh_home_page(state).await?;
-        }
+    let client = Client::new();
+    let view = match &next_page {
+        Page::Home => view::mount(&main, HomePage::new(client))?,
         Page::RandomMatch(id) => {
-            let mut borrowed_state = state.borrow_mut();
-            let element = if let Some(element) = borrowed_state.random_match.take() {
-                element
-            } else {
-                web_sys::console::log_1(&JsValue::from("Generating random match"));
-                generate_random_page()?
-            };
-            main.append_child(&element)?;
             // TODO: Cache navbar element
             let ul = document.create_element("ul")?;
             ul.set_class_name("navbar-nav flex-grow-1");
@@ -166,7 +222,7 @@ async fn switch_pages(state: Rc<RefCell<State>>, next_page: Page) -> Result<(),
                 .create_element("a")?
                 .dyn_into::<HtmlAnchorElement>()?;
             item.set_class_name("nav-link");
-            item.set_href("#");
+            item.set_href(&hash);
             item.set_text_content(Some("Random match"));
             li.append_child(&item)?;
             ul.append_child(&li)?;
@@ -175,663 +231,266 @@ async fn switch_pages(state: Rc<RefCell<State>>, next_page: Page) -> Result<(),
                 .item(0)
                 .expect("brand element missing")
                 .insert_adjacent_element("afterend", &ul)?;
-            borrowed_state.current_page = Page::RandomMatch(id.clone());
-            borrowed_state.playlist = Some(id.clone());
-            drop(borrowed_state);
-            let scores = fetch_scores(&window, &state, &id).await?;
-            refresh_scores(state, scores)?;
+            view::mount(&main, RandomPage::new(client, id.clone()))?
+        }
+        Page::Track { id, playlist } => {
+            view::mount(&main, TrackPage::new(client, id.clone(), playlist.clone()))?
         }
         Page::Login => {
             unreachable!()
         }
+    };
+    borrowed_state.view = Some(view);
+    borrowed_state.current_page = next_page;
+    drop(borrowed_state);
+    // Pages opened by buttons aren't in the URL yet. The hashchange this causes is for the page
+    // that's already shown, so it's ignored.
+    let location = window.location();
+    if location.hash()? != hash {
+        location.set_hash(&hash)?;
     }
     Ok(())
 }
 
-async fn generate_home_page(state: &Rc<RefCell<State>>) -> Result<Element, JsValue> {
+/// Open a page by linking to it, which switches pages once the URL fragment changes.
+fn open(page: &Page) -> Result<(), JsValue> {
     let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    let home = document.create_element("div")?;
-    home.set_id("home");
-    let header = document.create_element("h1")?;
-    header.set_text_content(Some("Saved Playlists"));
-    home.append_child(&header)?;
-    let playlists = document.create_element("div")?;
-    playlists.set_id("playlists");
-    home.append_child(&playlists)?;
-    let row = document.create_element("div")?;
-    row.set_class_name("row");
-    let form = document.create_element("form")?;
-    let form_row = document.create_element("div")?;
-    form_row.set_class_name("row");
-    let input = document
-        .create_element("input")?
-        .dyn_into::<HtmlInputElement>()?;
-    input.set_type("text");
-    input.set_id("input");
-    input.set_value("https://open.spotify.com/playlist/37i9dQZF1DX49jUV2NfGku?si=379bbc586c78450a");
-    input.set_class_name("col-7");
-    form_row.append_child(&input)?;
-    let import = document
-        .create_element("button")?
-        .dyn_into::<HtmlButtonElement>()?;
-    import.set_type("button");
-    import.set_class_name("col-1 offset-2 btn btn-success");
-    import.set_text_content(Some("Save"));
-    form_row.append_child(&import)?;
-    form.append_child(&form_row)?;
-    row.append_child(&form)?;
-    home.append_child(&row)?;
-    let header = document.create_element("h1")?;
-    header.set_text_content(Some("My Spotify Playlists"));
-    home.append_child(&header)?;
-    let spotify = document.create_element("div")?;
-    spotify.set_id("spotify");
-    home.append_child(&spotify)?;
-    let header = document.create_element("h1")?;
-    header.set_text_content(Some("Stats"));
-    home.append_child(&header)?;
-    let row = document.create_element("div")?;
-    row.set_class_name("row");
-    let left = document.create_element("div")?;
-    left.set_class_name("col-6");
-    let header = document.create_element("h2")?;
-    header.set_text_content(Some("Artists"));
-    left.append_child(&header)?;
-    let table = document.create_element("table")?;
-    table.set_class_name("table table-striped");
-    let head = document.create_element("thead")?;
-    let tr = document.create_element("tr")?;
-    tr.append_child(create_th(&document, "col-1", "#")?.as_ref())?;
-    tr.append_child(create_th(&document, "col-8", "Artist")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Score")?.as_ref())?;
-    head.append_child(&tr)?;
-    table.append_child(&head)?;
-    let body = document.create_element("tbody")?;
-    body.set_id("left-stats");
-    table.append_child(&body)?;
-    left.append_child(&table)?;
-    row.append_child(&left)?;
-    home.append_child(&row)?;
-    let right = document.create_element("div")?;
-    right.set_class_name("col-6");
-    let header = document.create_element("h2")?;
-    header.set_text_content(Some("Albums"));
-    right.append_child(&header)?;
-    let table = document.create_element("table")?;
-    table.set_class_name("table table-striped");
-    let head = document.create_element("thead")?;
-    let tr = document.create_element("tr")?;
-    tr.append_child(create_th(&document, "col-1", "#")?.as_ref())?;
-    tr.append_child(create_th(&document, "col-8", "Album")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Score")?.as_ref())?;
-    head.append_child(&tr)?;
-    table.append_child(&head)?;
-    let body = document.create_element("tbody")?;
-    body.set_id("right-stats");
-    table.append_child(&body)?;
-    right.append_child(&table)?;
-    row.append_child(&right)?;
-    home.append_child(&row)?;
-    let state_ref = Rc::clone(state);
-    let a = Closure::wrap(Box::new(move || {
-        let state = Rc::clone(&state_ref);
-        wasm_bindgen_futures::spawn_local(async move {
-            let window = web_sys::window().expect("no global `window` exists");
-            let document = window.document().expect("should have a document on window");
-            let input = document
-                .get_element_by_id("input")
-                .unwrap()
-                .dyn_into::<HtmlInputElement>()
-                .unwrap();
-            let mut input = input.value();
-            let playlist_re =
-                Regex::new(r"https://open.spotify.com/playlist/([[:alnum:]]*)").unwrap();
-            let album_re = Regex::new(r"https://open.spotify.com/album/([[:alnum:]]*)").unwrap();
-            let url = if let Some(id) = playlist_re.captures_iter(&input).next() {
-                input = id[1].to_owned();
-                format!("/api/?action=import&playlist={}", input)
-            } else if let Some(id) = album_re.captures_iter(&input).next() {
-                input = id[1].to_owned();
-                format!("/api/?action=import&album={}", input)
-            } else {
-                input
-            };
-            let request = query(&url, "POST", &state.borrow().auth).unwrap();
-            let resp_value = JsFuture::from(window.fetch_with_request(&request))
-                .await
-                .unwrap();
-            let resp: Response = resp_value.dyn_into().unwrap();
-            match resp.status() {
-                405 => {
-                    demo_alert(&window).unwrap();
-                }
-                201 => {
-                    load_playlists(state).await.unwrap();
-                }
-                // TODO: error handling
-                _ => {}
-            }
-        })
-    }) as Box<dyn FnMut()>);
-    import.set_onclick(Some(a.as_ref().unchecked_ref()));
-    a.forget();
-    Ok(home)
+    window.location().set_hash(&page.hash())
 }
 
-async fn refresh_home_page(state: Rc<RefCell<State>>) -> Result<(), JsValue> {
-    let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    load_playlists(Rc::clone(&state)).await?;
-    let request = query("/api/scores", "GET", &state.borrow().auth).unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request))
-        .await
-        .unwrap();
-    let resp: Response = resp_value.dyn_into().unwrap();
-    let json = JsFuture::from(resp.json()?).await?;
-    let scores: Scores = json.into_serde().unwrap();
-    let mut artists = HashMap::new();
-    for s in &scores.scores {
-        artists
-            .entry(s.artists.join(", "))
-            .or_insert_with(Vec::new)
-            .push(s.score);
-    }
-    let mut artists: Vec<_> = artists
-        .iter()
-        .map(|(k, v)| (k.as_str(), v.iter().sum::<i32>() / v.len() as i32))
-        .collect();
-    artists.sort_by_key(|(_, v)| -*v);
-    let mut albums = HashMap::new();
-    for s in &scores.scores {
-        albums
-            .entry(s.album.clone())
-            .or_insert_with(Vec::new)
-            .push(s.score);
-    }
-    let mut albums: Vec<_> = albums
+fn button(class: &str, text: &str) -> VElement {
+    el("button").attr("type", "button").class(class).text(text)
+}
+
+/// A striped table with a header of `(class, title)` columns.
+fn table(columns: &[(&str, &str)], rows: Vec<Html>) -> Html {
+    let header = columns
         .iter()
-        .map(|(k, v)| (k.as_str(), v.iter().sum::<i32>() / v.len() as i32))
-        .collect();
-    albums.sort_by_key(|(_, v)| -*v);
-    let body = document
-        .get_element_by_id("left-stats")
-        .ok_or_else(|| JsValue::from("left-stats element missing"))?;
-    while let Some(child) = body.first_element_child() {
-        child.remove();
-    }
-    for (i, (artist, score)) in artists.iter().enumerate() {
-        let row = document.create_element("tr")?;
-        let num = document.create_element("th")?;
-        num.set_text_content(Some(&(i + 1).to_string()));
-        row.append_child(&num)?;
-        let track = document.create_element("td")?;
-        track.set_text_content(Some(artist));
-        row.append_child(&track)?;
-        let score_element = document.create_element("td")?;
-        score_element.set_text_content(Some(&score.to_string()));
-        row.append_child(&score_element)?;
-        body.append_child(&row)?;
-    }
-    let body = document
-        .get_element_by_id("right-stats")
-        .ok_or_else(|| JsValue::from("right-stats element missing"))?;
-    while let Some(child) = body.first_element_child() {
-        child.remove();
-    }
-    for (i, (album, score)) in albums.iter().enumerate() {
-        let row = document.create_element("tr")?;
-        let num = document.create_element("th")?;
-        num.set_text_content(Some(&(i + 1).to_string()));
-        row.append_child(&num)?;
-        let track = document.create_element("td")?;
-        track.set_text_content(Some(album));
-        row.append_child(&track)?;
-        let score_element = document.create_element("td")?;
-        score_element.set_text_content(Some(&score.to_string()));
-        row.append_child(&score_element)?;
-        body.append_child(&row)?;
-    }
-    Ok(())
+        .map(|&(class, title)| el("th").class(class).text(title).into());
+    el("table")
+        .class("table table-striped")
+        .child(el("thead").child(el("tr").children(header)))
+        .child(el("tbody").children(rows))
+        .into()
 }
 
-async fn load_playlists(state: Rc<RefCell<State>>) -> Result<(), JsValue> {
-    let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    let playlists_element = document
-        .get_element_by_id("playlists")
-        .ok_or_else(|| JsValue::from("playlists element missing"))?;
-    let request = query("/api/playlists", "GET", &state.borrow().auth)?;
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    let json = JsFuture::from(resp.json()?).await?;
-    let playlists: Playlists = json.into_serde().unwrap();
-    while let Some(child) = playlists_element.first_element_child() {
-        child.remove();
-    }
-    if playlists.items.is_empty() {
-        let p = document.create_element("p")?;
-        p.set_text_content(Some(
-            "Import a playlist and choose an option to start sorting songs!",
-        ));
-        playlists_element.append_child(&p)?;
+/// A table row that opens `page` when it's clicked.
+fn linked_row(page: &Page) -> VElement {
+    let page = page.clone();
+    el("tr")
+        .attr("style", "cursor: pointer")
+        .on("click", move |_| open(&page).unwrap())
+}
+
+/// A row of a scores table, linking to the track's page.
+fn score_row(i: usize, score: &Score, playlist: Option<&str>) -> Html {
+    let page = Page::Track {
+        id: score.track_id.clone(),
+        playlist: playlist.map(str::to_owned),
+    };
+    linked_row(&page)
+        .key(score.track_id.clone())
+        .child(el("th").text(i.to_string()))
+        .child(el("td").child(el("a").attr("href", page.hash()).text(&score.track)))
+        .child(el("td").text(format!("{}-{}", score.wins, score.losses)))
+        .child(el("td").text(score.score.to_string()))
+        .into()
+}
+
+/// Play a track. Spotify tracks are embedded, and other tracks are shown on a card instead with a
+/// player for tracks from the server's music library once their `clip` has loaded. Players are
+/// numbered so that they can be found from the keyboard.
+fn player(n: &str, track: &Score, clip: Option<&str>, height: &str) -> Html {
+    let id = ProviderId::parse(&track.track_id);
+    // Only Spotify tracks can be embedded
+    let spotify = id.is_spotify();
+    let src = if spotify {
+        format!(
+            "https://open.spotify.com/embed/track/{}?utm_source=generator",
+            track.track_id
+        )
     } else {
-        for p in playlists.items {
-            let row = document.create_element("div")?;
-            row.set_class_name("row");
-            let label = document.create_element("label")?;
-            label.set_class_name("col-7");
-            let link = document
-                .create_element("a")?
-                .dyn_into::<HtmlAnchorElement>()?;
-            link.set_text_content(Some(&p.name));
-            link.set_href(&format!(
-                "https://open.spotify.com/playlist/{}",
-                p.playlist_id
-            ));
-            label.append_child(&link)?;
-            row.append_child(&label)?;
-            let div = document.create_element("div")?;
-            div.set_class_name("col-2");
-            let select = document.create_element("select")?;
-            select.set_class_name("form-select");
-            let option = document.create_element("option")?;
-            option.set_text_content(Some("Random match"));
-            select.append_child(&option)?;
-            div.append_child(&select)?;
-            row.append_child(&div)?;
-            let button = document
-                .create_element("button")?
-                .dyn_into::<HtmlButtonElement>()?;
-            button.set_type("button");
-            button.set_class_name("btn btn-success col-1 me-2");
-            button.set_text_content(Some("Go"));
-            let state_ref = Rc::clone(&state);
-            let id = p.id.clone();
-            let a = Closure::wrap(Box::new(move || {
-                let state = Rc::clone(&state_ref);
-                let id = id.clone();
-                wasm_bindgen_futures::spawn_local(async move {
-                    let window = web_sys::window().expect("no global `window` exists");
-                    let scores = fetch_scores(&window, &state, &id).await.unwrap();
-                    if scores.scores.len() < 2 {
-                        window
-                            .alert_with_message("Playlist has less than 2 songs")
-                            .expect("alert");
-                    } else {
-                        switch_pages(state, Page::RandomMatch(id)).await.unwrap();
-                    }
-                });
-            }) as Box<dyn FnMut()>);
-            button.set_onclick(Some(a.as_ref().unchecked_ref()));
-            a.forget();
-            row.append_child(&button)?;
-            let button = document
-                .create_element("button")?
-                .dyn_into::<HtmlButtonElement>()?;
-            button.set_type("button");
-            button.set_class_name("btn btn-danger col-1");
-            button.set_text_content(Some("Unsave"));
-            let state_ref = Rc::clone(&state);
-            let a = Closure::wrap(Box::new(move || {
-                let state = Rc::clone(&state_ref);
-                let id = p.id.clone();
-                wasm_bindgen_futures::spawn_local(async move {
-                    let window = web_sys::window().expect("no global `window` exists");
-                    let url = format!("/api/playlists/{}", id);
-                    let request = query(&url, "DELETE", &state.borrow().auth).unwrap();
-                    let resp_value = JsFuture::from(window.fetch_with_request(&request))
-                        .await
-                        .unwrap();
-                    let resp: Response = resp_value.dyn_into().unwrap();
-                    match resp.status() {
-                        405 => {
-                            demo_alert(&window).unwrap();
-                        }
-                        204 => {
-                            load_playlists(state).await.unwrap();
-                        }
-                        // TODO: error handling
-                        _ => {}
-                    }
-                })
-            }) as Box<dyn FnMut()>);
-            button.set_onclick(Some(a.as_ref().unchecked_ref()));
-            a.forget();
-            row.append_child(&button)?;
-            playlists_element.append_child(&row)?;
-        }
+        String::from("about:blank")
+    };
+    let audio = if id.provider == songsort::LIBRARY {
+        // Each track gets its own element, which stops the last track playing
+        let audio = el("audio")
+            .key(track.track_id.clone())
+            .id(format!("audio{}", n))
+            .flag("controls", true)
+            .class("width");
+        Some(match clip {
+            Some(clip) => audio.attr("src", clip).into(),
+            None => audio.into(),
+        })
+    } else {
+        None
+    };
+    let card = el("div")
+        .class("card-body")
+        .child(el("h2").class("card-title truncate").text(&track.track))
+        .child(
+            el("p")
+                .class("card-text truncate")
+                .text(track.artists.join(", ")),
+        )
+        .child(
+            el("p")
+                .class("card-text text-muted truncate")
+                .text(&track.album),
+        )
+        .children(audio);
+    el("div")
+        .child(
+            el("iframe")
+                .id(format!("iframe{}", n))
+                .attr("width", "100%")
+                .attr("height", height)
+                .attr("frameborder", "0")
+                .attr("src", src)
+                .flag("hidden", !spotify),
+        )
+        .child(
+            el("div")
+                .id(format!("local{}", n))
+                .class("card mb-2")
+                .flag("hidden", spotify)
+                .child(card),
+        )
+        .into()
+}
+
+/// Get a clip from the middle of a track from the server's music library.
+async fn fetch_clip(client: &Client, track_id: &str) -> Result<Option<String>, JsValue> {
+    match client.create_clip(track_id).await {
+        Ok(clip) => Ok(Some(clip.url)),
+        Err(ApiError::Status(_)) => Ok(None),
+        Err(e) => Err(js_error(e)),
     }
-    let playlists_element = document
-        .get_element_by_id("spotify")
-        .ok_or_else(|| JsValue::from("spotify element missing"))?;
-    let request = query("/api/spotify/playlists", "GET", &state.borrow().auth).unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request))
+}
+
+/// Get a playlist's scores, highest first.
+async fn fetch_scores(client: &Client, id: &str) -> Result<Scores, JsValue> {
+    let query = ScoresQuery {
+        sort: Some(SortKey::Score),
+        ..ScoresQuery::default()
+    };
+    client
+        .get_playlist_scores(id, &query)
         .await
-        .unwrap();
-    let resp: Response = resp_value.dyn_into().unwrap();
-    if resp.status() == 405 {
-        web_sys::console::log_1(&JsValue::from("Not supported in demo"));
-        return Ok(());
-    }
-    let json = JsFuture::from(resp.json()?).await?;
-    let playlists: Playlists = json.into_serde().unwrap();
-    while let Some(child) = playlists_element.first_element_child() {
-        child.remove();
-    }
-    for p in playlists.items {
-        let row = document.create_element("div")?;
-        row.set_class_name("row");
-        let label = document.create_element("label")?;
-        label.set_class_name("col-9");
-        let link = document
-            .create_element("a")?
-            .dyn_into::<HtmlAnchorElement>()?;
-        link.set_text_content(Some(&p.name));
-        link.set_href(&format!(
-            "https://open.spotify.com/playlist/{}",
-            p.playlist_id
-        ));
-        label.append_child(&link)?;
-        row.append_child(&label)?;
-        let button = document
-            .create_element("button")?
-            .dyn_into::<HtmlButtonElement>()?;
-        button.set_type("button");
-        button.set_class_name("btn btn-success col-1");
-        button.set_text_content(Some("Save"));
-        let state_ref = Rc::clone(&state);
-        let a = Closure::wrap(Box::new(move || {
-            let state = Rc::clone(&state_ref);
-            let id = p.playlist_id.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                let window = web_sys::window().expect("no global `window` exists");
-                let url = format!("/api/playlists/{}", id);
-                let request = query(&url, "POST", &state.borrow().auth).unwrap();
-                let resp_value = JsFuture::from(window.fetch_with_request(&request))
-                    .await
-                    .unwrap();
-                let resp: Response = resp_value.dyn_into().unwrap();
-                match resp.status() {
-                    405 => {
-                        demo_alert(&window).unwrap();
-                    }
-                    201 => {
-                        load_playlists(state).await.unwrap();
-                    }
-                    // TODO: error handling
-                    _ => {}
-                }
-            })
-        }) as Box<dyn FnMut()>);
-        button.set_onclick(Some(a.as_ref().unchecked_ref()));
-        a.forget();
-        row.append_child(&button)?;
-        playlists_element.append_child(&row)?;
-    }
-    Ok(())
+        .map_err(js_error)
 }
 
-fn generate_random_page() -> Result<Element, JsValue> {
-    let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    let random = document.create_element("div")?;
-    random.set_id("random");
-    let header = document.create_element("h1")?;
-    header.set_text_content(Some("Random match"));
-    random.append_child(&header)?;
-    let row = document.create_element("div")?;
-    row.set_class_name("row");
-    let left = document.create_element("div")?;
-    left.set_class_name("col-6");
-    let iframe1 = document
-        .create_element("iframe")?
-        .dyn_into::<HtmlIFrameElement>()?;
-    iframe1.set_id("iframe1");
-    iframe1.set_width("100%");
-    iframe1.set_height("380");
-    iframe1.set_frame_border("0");
-    left.append_child(&iframe1)?;
-    let score1 = document
-        .create_element("button")?
-        .dyn_into::<HtmlButtonElement>()?;
-    score1.set_type("button");
-    score1.set_id("score1");
-    score1.set_class_name("btn btn-info width");
-    let track1 = document.create_element("div")?;
-    track1.set_id("track1");
-    track1.set_class_name("truncate");
-    score1.append_child(&track1)?;
-    left.append_child(&score1)?;
-    row.append_child(&left)?;
-    random.append_child(&row)?;
-    let right = document.create_element("div")?;
-    right.set_class_name("col-6");
-    let iframe2 = document
-        .create_element("iframe")?
-        .dyn_into::<HtmlIFrameElement>()?;
-    iframe2.set_id("iframe2");
-    iframe2.set_width("100%");
-    iframe2.set_height("380");
-    iframe2.set_frame_border("0");
-    right.append_child(&iframe2)?;
-    let score2 = document
-        .create_element("button")?
-        .dyn_into::<HtmlButtonElement>()?;
-    score2.set_type("button");
-    score2.set_id("score2");
-    score2.set_class_name("btn btn-warning width");
-    let track2 = document.create_element("div")?;
-    track2.set_id("track2");
-    track2.set_class_name("truncate");
-    score2.append_child(&track2)?;
-    right.append_child(&score2)?;
-    row.append_child(&right)?;
-    random.append_child(&row)?;
-    let row = document.create_element("div")?;
-    row.set_class_name("row");
-    let left = document.create_element("div")?;
-    left.set_class_name("col-6");
-    let table = document.create_element("table")?;
-    table.set_class_name("table table-striped");
-    let head = document.create_element("thead")?;
-    let tr = document.create_element("tr")?;
-    tr.append_child(create_th(&document, "col-1", "#")?.as_ref())?;
-    tr.append_child(create_th(&document, "col-8", "Track")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Record")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Score")?.as_ref())?;
-    head.append_child(&tr)?;
-    table.append_child(&head)?;
-    let body = document.create_element("tbody")?;
-    body.set_id("scores1");
-    table.append_child(&body)?;
-    left.append_child(&table)?;
-    row.append_child(&left)?;
-    let right = document.create_element("div")?;
-    right.set_class_name("col-6");
-    let table = document.create_element("table")?;
-    table.set_class_name("table table-striped");
-    let head = document.create_element("thead")?;
-    let tr = document.create_element("tr")?;
-    tr.append_child(create_th(&document, "col-1", "#")?.as_ref())?;
-    tr.append_child(create_th(&document, "col-8", "Track")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Record")?.as_ref())?;
-    tr.append_child(create_th(&document, "", "Score")?.as_ref())?;
-    head.append_child(&tr)?;
-    table.append_child(&head)?;
-    let body = document.create_element("tbody")?;
-    body.set_id("scores2");
-    table.append_child(&body)?;
-    right.append_child(&table)?;
-    row.append_child(&right)?;
-    random.append_child(&row)?;
-    Ok(random)
+/// Sends API requests with the user's credentials.
+#[derive(Clone)]
+struct Client {
+    /// The Authorization header for logging in. Without one, the browser sends the session
+    /// cookie, which logs the user out once it's expired.
+    auth: Option<String>,
 }
 
-fn refresh_scores(state: Rc<RefCell<State>>, mut scores: Scores) -> Result<(), JsValue> {
-    async fn elo(state: Rc<RefCell<State>>, url: String) -> Result<(), JsValue> {
-        let window = web_sys::window().expect("no global `window` exists");
-        let request = query(&url, "POST", &state.borrow().auth)?;
-        JsFuture::from(window.fetch_with_request(&request)).await?;
-        let scores =
-            fetch_scores(&window, &state, state.borrow().playlist.as_ref().unwrap()).await?;
-        refresh_scores(state, scores)?;
-        Ok(())
+impl Client {
+    fn new() -> Client {
+        Client { auth: None }
     }
 
-    let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    scores.scores.sort_by_key(|s| -s.score);
-    let scores1 = document
-        .get_element_by_id("scores1")
-        .ok_or_else(|| JsValue::from("scores element missing"))?;
-    while let Some(child) = scores1.first_element_child() {
-        child.remove();
-    }
-    let scores2 = document
-        .get_element_by_id("scores2")
-        .ok_or_else(|| JsValue::from("scores element missing"))?;
-    while let Some(child) = scores2.first_element_child() {
-        child.remove();
+    /// A client for logging in with the authorization code from Spotify or demo credentials.
+    fn for_code(code: &str) -> Client {
+        Client {
+            auth: Some(format!("Basic {}", code)),
+        }
     }
-    let mut iter = (1..).zip(scores.scores.iter());
-    while let Some((i, score)) = iter.next() {
-        let row = document.create_element("tr")?;
-        let num = document.create_element("th")?;
-        num.set_text_content(Some(&i.to_string()));
-        row.append_child(&num)?;
-        let track = document.create_element("td")?;
-        track.set_text_content(Some(&score.track));
-        row.append_child(&track)?;
-        let record = document.create_element("td")?;
-        record.set_text_content(Some(&format!("{}-{}", score.wins, score.losses)));
-        row.append_child(&record)?;
-        let score_element = document.create_element("td")?;
-        score_element.set_text_content(Some(&score.score.to_string()));
-        row.append_child(&score_element)?;
-        scores1.append_child(&row)?;
+}
 
-        if let Some((i, score)) = iter.next() {
-            let row = document.create_element("tr")?;
-            let num = document.create_element("th")?;
-            num.set_text_content(Some(&i.to_string()));
-            row.append_child(&num)?;
-            let track = document.create_element("td")?;
-            track.set_text_content(Some(&score.track));
-            row.append_child(&track)?;
-            let record = document.create_element("td")?;
-            record.set_text_content(Some(&format!("{}-{}", score.wins, score.losses)));
-            row.append_child(&record)?;
-            let score_element = document.create_element("td")?;
-            score_element.set_text_content(Some(&score.score.to_string()));
-            row.append_child(&score_element)?;
-            scores2.append_child(&row)?;
-        }
+#[async_trait(?Send)]
+impl Api for Client {
+    type Error = JsValue;
+
+    async fn send(&self, request: api::Request) -> Result<api::Response, JsValue> {
+        let window = web_sys::window().expect("no global `window` exists");
+        let body = request.body.map(JsValue::from);
+        let method = request.method.as_str();
+        let request = query_with_body(&request.url, method, self.auth.as_deref(), body.as_ref())?;
+        let resp = if self.auth.is_none() {
+            fetch(&window, &request).await?
+        } else {
+            JsFuture::from(window.fetch_with_request(&request))
+                .await?
+                .dyn_into()?
+        };
+        let body = JsFuture::from(resp.text()?).await?;
+        Ok(api::Response {
+            status: resp.status(),
+            body: body.as_string().unwrap_or_default(),
+        })
     }
-    let queued_scores = &mut state.borrow_mut().queued_scores;
-    match queued_scores.len() {
-        // Reload the queue if it's empty
-        0 => {
-            let mut scores = scores.scores;
-            scores.shuffle(&mut rand::thread_rng());
-            queued_scores.extend(scores);
-        }
-        // Always queue the last song next before reloading
-        1 => {
-            let last = queued_scores.pop().unwrap();
-            let mut scores = scores.scores;
-            scores.shuffle(&mut rand::thread_rng());
-            queued_scores.extend(scores);
-            queued_scores.push(last);
-        }
-        _ => {}
-    };
-    let track1 = queued_scores.pop().unwrap();
-    let track2 = queued_scores.pop().unwrap();
-    let state_ref = Rc::clone(&state);
-    let url = format!("/api/elo?{}&{}", track1.track_id, track2.track_id);
-    let a = Closure::wrap(Box::new(move || {
-        let state = Rc::clone(&state_ref);
-        let url = url.clone();
-        wasm_bindgen_futures::spawn_local(async { elo(state, url).await.unwrap() })
-    }) as Box<dyn FnMut()>);
-    document
-        .get_element_by_id("score1")
-        .ok_or_else(|| JsValue::from("score1 element missing"))?
-        .dyn_into::<HtmlButtonElement>()?
-        .set_onclick(Some(a.as_ref().unchecked_ref()));
-    a.forget();
-    let state_ref = Rc::clone(&state);
-    let url = format!("/api/elo?{}&{}", track2.track_id, track1.track_id);
-    let a = Closure::wrap(Box::new(move || {
-        let state = Rc::clone(&state_ref);
-        let url = url.clone();
-        wasm_bindgen_futures::spawn_local(async { elo(state, url).await.unwrap() })
-    }) as Box<dyn FnMut()>);
-    document
-        .get_element_by_id("score2")
-        .ok_or_else(|| JsValue::from("score2 element missing"))?
-        .dyn_into::<HtmlButtonElement>()?
-        .set_onclick(Some(a.as_ref().unchecked_ref()));
-    a.forget();
-    document
-        .get_element_by_id("iframe1")
-        .ok_or_else(|| JsValue::from("iframe1 element missing"))?
-        .dyn_into::<HtmlIFrameElement>()?
-        .set_src(&format!(
-            "https://open.spotify.com/embed/track/{}?utm_source=generator",
-            track1.track_id
-        ));
-    document
-        .get_element_by_id("iframe2")
-        .ok_or_else(|| JsValue::from("iframe2 element missing"))?
-        .dyn_into::<HtmlIFrameElement>()?
-        .set_src(&format!(
-            "https://open.spotify.com/embed/track/{}?utm_source=generator",
-            track2.track_id
-        ));
-    document
-        .get_element_by_id("track1")
-        .ok_or_else(|| JsValue::from("track1 element missing"))?
-        .set_text_content(Some(&track1.track));
-    document
-        .get_element_by_id("track2")
-        .ok_or_else(|| JsValue::from("track2 element missing"))?
-        .set_text_content(Some(&track2.track));
-    Ok(())
 }
 
-async fn fetch_scores(
-    window: &Window,
-    state: &Rc<RefCell<State>>,
-    id: &str,
-) -> Result<Scores, JsValue> {
-    let url = format!("/api/playlists/{}/scores", id);
-    let request = query(&url, "GET", &state.borrow().auth)?;
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-    let resp: Response = resp_value.dyn_into()?;
-    let json = JsFuture::from(resp.json()?).await?;
-    Ok(json.into_serde().unwrap())
+/// Errors from the API as the `JsValue`s that the rest of the frontend fails with.
+fn js_error(e: ApiError<JsValue>) -> JsValue {
+    match e {
+        ApiError::Transport(e) => e,
+        e => JsValue::from(e.to_string()),
+    }
 }
 
-fn query(url: &str, method: &str, auth: &str) -> Result<Request, JsValue> {
+fn query_with_body(
+    url: &str,
+    method: &str,
+    auth: Option<&str>,
+    body: Option<&JsValue>,
+) -> Result<Request, JsValue> {
     let mut opts = RequestInit::new();
     opts.method(method);
     opts.mode(RequestMode::Cors);
+    opts.body(body);
     let request = Request::new_with_str_and_init(url, &opts)?;
-    request
-        .headers()
-        .set("Authorization", &format!("Basic {}", auth))?;
+    if let Some(auth) = auth {
+        request.headers().set("Authorization", auth)?;
+    }
     Ok(request)
 }
 
+/// Start a session with the client's credentials, returning whether they were accepted.
+async fn log_in(window: &Window, client: &Client) -> Result<bool, JsValue> {
+    match client.login().await {
+        Ok(_) => {
+            if let Some(storage) = window.local_storage()? {
+                storage.set_item(LOGGED_IN_KEY, "true")?;
+            }
+            Ok(true)
+        }
+        Err(ApiError::Status(401)) => Ok(false),
+        Err(e) => Err(js_error(e)),
+    }
+}
+
+/// Send a request. If the session has expired, the request fails and the user is sent back to
+/// the login page.
+async fn fetch(window: &Window, request: &Request) -> Result<Response, JsValue> {
+    let resp: Response = JsFuture::from(window.fetch_with_request(request))
+        .await?
+        .dyn_into()?;
+    if resp.status() == 401 {
+        log_out(window)?;
+        return Err(JsValue::from("session expired"));
+    }
+    Ok(resp)
+}
+
+/// Forget the session and start over at the login page.
+fn log_out(window: &Window) -> Result<(), JsValue> {
+    if let Some(storage) = window.local_storage()? {
+        storage.remove_item(LOGGED_IN_KEY)?;
+    }
+    window.location().reload()
+}
+
 fn demo_alert(window: &Window) -> Result<(), JsValue> {
     window.alert_with_message("Not supported in demo")
 }
 
-fn create_th(document: &Document, class: &str, text: &str) -> Result<Element, JsValue> {
-    let th = document.create_element("th")?;
-    th.set_class_name(class);
-    th.set_text_content(Some(text));
-    Ok(th)
+/// Spotify refused the user's authorization, see `api::SPOTIFY_UNAUTHORIZED`.
+fn spotify_alert(window: &Window) -> Result<(), JsValue> {
+    window.alert_with_message("Please log in again to allow songsort to use your Spotify account")
 }
@@ -0,0 +1,457 @@
+//! The random match page, which plays two tracks from a playlist against each other.
+
+use crate::view::{el, Component, Html, Link, Listener, VElement};
+use crate::{button, fetch_clip, fetch_scores, js_error, player, score_row, table, Client};
+use rand::prelude::SliceRandom;
+use songsort::api::{Api, ApiError, EloQuery};
+use songsort::{ProviderId, Score, Scores};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Document, HtmlAudioElement, HtmlIFrameElement, KeyboardEvent};
+
+pub struct RandomPage {
+    client: Client,
+    playlist: String,
+    scores: Option<Scores>,
+    /// Tracks waiting to be played, next last
+    queue: Vec<Score>,
+    /// The tracks of the match that's shown, until it's decided
+    current: Option<(Score, Score)>,
+    /// Whether the match that's shown is being decided. Other actions are ignored until the next
+    /// match is shown.
+    busy: bool,
+    /// Matches played since the page was opened, latest last
+    played: Vec<Played>,
+    /// Clips of library tracks by track ID
+    clips: HashMap<String, String>,
+    /// Whether the keyboard shortcuts are listed
+    hints: bool,
+    /// Shortcuts work anywhere on the page, so they're listened for on the document until the
+    /// page is dropped
+    _keys: Option<Listener>,
+}
+
+/// A match that can be taken back, with its tracks so that it can be shown again.
+struct Played {
+    id: String,
+    track1: Score,
+    track2: Score,
+}
+
+/// What can be done with the match on the random match page.
+#[derive(Clone, Copy)]
+enum MatchAction {
+    /// The left track wins
+    Win1,
+    /// The right track wins
+    Win2,
+    Draw,
+    Skip,
+    /// Take back the last match and show it again
+    Undo,
+}
+
+impl RandomPage {
+    pub fn new(client: Client, playlist: String) -> RandomPage {
+        RandomPage {
+            client,
+            playlist,
+            scores: None,
+            queue: Vec::new(),
+            current: None,
+            busy: false,
+            played: Vec::new(),
+            clips: HashMap::new(),
+            hints: true,
+            _keys: None,
+        }
+    }
+
+    /// Show the latest scores and the next match from the queue.
+    fn next_match(&mut self, scores: Scores) {
+        self.current = None;
+        if scores.scores.len() >= 2 {
+            match self.queue.len() {
+                // Reload the queue if it's empty
+                0 => {
+                    let mut scores = scores.scores.clone();
+                    scores.shuffle(&mut rand::thread_rng());
+                    self.queue.extend(scores);
+                }
+                // Always queue the last song next before reloading
+                1 => {
+                    let last = self.queue.pop().unwrap();
+                    let mut scores = scores.scores.clone();
+                    scores.shuffle(&mut rand::thread_rng());
+                    self.queue.extend(scores);
+                    self.queue.push(last);
+                }
+                _ => {}
+            };
+            let track1 = self.queue.pop().unwrap();
+            let track2 = self.queue.pop().unwrap();
+            self.current = Some((track1, track2));
+        }
+        self.scores = Some(scores);
+    }
+
+    fn side(&self, link: &Link<RandomPage>, n: &str, track: &Score, class: &str) -> Html {
+        let action = if n == "1" {
+            MatchAction::Win1
+        } else {
+            MatchAction::Win2
+        };
+        let clip = self.clips.get(&track.track_id).map(String::as_str);
+        el("div")
+            .class("col-6")
+            .child(player(n, track, clip, "380"))
+            .child(
+                on_action(link, el("button").attr("type", "button"), action)
+                    .id(format!("score{}", n))
+                    .class(format!("btn {} width", class))
+                    .child(
+                        el("div")
+                            .id(format!("track{}", n))
+                            .class("truncate")
+                            .text(&track.track),
+                    ),
+            )
+            .into()
+    }
+
+    /// Split the scores between two tables, alternating rows.
+    fn score_tables(&self) -> Html {
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        if let Some(scores) = &self.scores {
+            for (i, score) in (1..).zip(scores.scores.iter()) {
+                let row = score_row(i, score, Some(self.playlist.as_str()));
+                if i % 2 == 1 {
+                    left.push(row);
+                } else {
+                    right.push(row);
+                }
+            }
+        }
+        let columns = [
+            ("col-1", "#"),
+            ("col-8", "Track"),
+            ("", "Record"),
+            ("", "Score"),
+        ];
+        el("div")
+            .class("row")
+            .child(el("div").class("col-6").child(table(&columns, left)))
+            .child(el("div").class("col-6").child(table(&columns, right)))
+            .into()
+    }
+
+    /// List the keyboard shortcuts in a corner of the screen.
+    fn hints(&self, link: &Link<RandomPage>) -> Html {
+        let link = link.clone();
+        let close = el("button")
+            .attr("type", "button")
+            .class("btn-close")
+            .attr("aria-label", "Close")
+            .on("click", move |_| {
+                link.update(|page| page.hints = false).unwrap()
+            });
+        let rows = [
+            ("\u{2190}", "Left track wins"),
+            ("\u{2192}", "Right track wins"),
+            ("\u{2193}", "Tie"),
+            ("Space", "Skip"),
+            ("U", "Undo the last match"),
+            ("1 / 2", "Play or pause a track"),
+            ("?", "Show or hide these shortcuts"),
+        ]
+        .iter()
+        .map(|&(key, description)| {
+            el("tr")
+                .child(el("td").child(el("kbd").text(key)))
+                .child(el("td").text(description))
+                .into()
+        })
+        .collect::<Vec<Html>>();
+        el("div")
+            .id("hints")
+            .class("card position-fixed bottom-0 end-0 m-3 shadow-sm")
+            .flag("hidden", !self.hints)
+            .child(
+                el("div")
+                    .class("card-body")
+                    .child(
+                        el("div")
+                            .class("d-flex justify-content-between align-items-center mb-2")
+                            .child(el("h5").class("card-title mb-0").text("Keyboard shortcuts"))
+                            .child(close),
+                    )
+                    .child(
+                        el("table")
+                            .class("table table-sm mb-0")
+                            .child(el("tbody").children(rows)),
+                    ),
+            )
+            .into()
+    }
+}
+
+impl Component for RandomPage {
+    fn init(&mut self, link: &Link<RandomPage>) -> Result<(), JsValue> {
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let keys_link = link.clone();
+        self._keys = Some(Listener::new(&document, "keydown", move |event| {
+            let event = match event.dyn_into::<KeyboardEvent>() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            on_key(&keys_link, &event).unwrap();
+        })?);
+        let (link, client, id) = (link.clone(), self.client.clone(), self.playlist.clone());
+        wasm_bindgen_futures::spawn_local(async move {
+            let scores = fetch_scores(&client, &id).await.unwrap();
+            link.update(|page| page.next_match(scores)).unwrap();
+            load_clips(&link, &client).await.unwrap();
+        });
+        Ok(())
+    }
+
+    fn view(&self, link: &Link<RandomPage>) -> Html {
+        let mut random = el("div").id("random").child(el("h1").text("Random match"));
+        match &self.current {
+            Some((track1, track2)) => {
+                let controls = [
+                    ("draw", "Tie", MatchAction::Draw),
+                    ("skip", "Skip", MatchAction::Skip),
+                    ("undo", "Undo", MatchAction::Undo),
+                ]
+                .iter()
+                .map(|&(id, text, action)| {
+                    on_action(link, button("btn btn-outline-secondary", text), action)
+                        .id(id)
+                        .into()
+                })
+                .collect::<Vec<Html>>();
+                random = random
+                    .child(
+                        el("div")
+                            .class("row")
+                            .child(self.side(link, "1", track1, "btn-info"))
+                            .child(self.side(link, "2", track2, "btn-warning")),
+                    )
+                    .child(
+                        el("div").class("row").child(
+                            el("div")
+                                .class("col-12 d-flex justify-content-center gap-2 my-2")
+                                .children(controls),
+                        ),
+                    );
+            }
+            None if self.scores.is_some() => {
+                random = random.child(el("p").text("Playlist has less than 2 songs"));
+            }
+            None => {}
+        }
+        random
+            .child(self.score_tables())
+            .child(self.hints(link))
+            .into()
+    }
+}
+
+/// Do `action` when `element` is clicked.
+fn on_action(link: &Link<RandomPage>, element: VElement, action: MatchAction) -> VElement {
+    let link = link.clone();
+    element.spawn_on("click", move || act(link.clone(), action))
+}
+
+fn on_key(link: &Link<RandomPage>, event: &KeyboardEvent) -> Result<(), JsValue> {
+    if event.repeat() || event.ctrl_key() || event.meta_key() || event.alt_key() {
+        return Ok(());
+    }
+    let window = web_sys::window().expect("no global `window` exists");
+    let document = window.document().expect("should have a document on window");
+    let action = match event.key().as_str() {
+        "ArrowLeft" => MatchAction::Win1,
+        "ArrowRight" => MatchAction::Win2,
+        "ArrowDown" => MatchAction::Draw,
+        " " => MatchAction::Skip,
+        "u" => MatchAction::Undo,
+        n @ ("1" | "2") => {
+            event.prevent_default();
+            return toggle_preview(&document, n);
+        }
+        "?" => return link.update(|page| page.hints = !page.hints),
+        _ => return Ok(()),
+    };
+    // Arrows and space would scroll the page or press the focused button
+    event.prevent_default();
+    let link = link.clone();
+    wasm_bindgen_futures::spawn_local(async move { act(link, action).await.unwrap() });
+    Ok(())
+}
+
+/// Decide the match that's shown, or take back the last one, and show the next match.
+async fn act(link: Link<RandomPage>, action: MatchAction) -> Result<(), JsValue> {
+    // The match can only be decided once
+    let mut current = None;
+    link.set(|page| {
+        if !page.busy {
+            current = page.current.clone();
+            page.busy = current.is_some();
+        }
+    });
+    let (track1, track2) = match current {
+        Some(tracks) => tracks,
+        None => return Ok(()),
+    };
+    // The page stays busy until the next match is shown, even if deciding this one failed
+    let decided = decide(&link, action, track1, track2).await;
+    let (scores, result) = match decided {
+        Ok(scores) => (scores, Ok(())),
+        Err(e) => (None, Err(e)),
+    };
+    link.update(|page| {
+        page.busy = false;
+        if let Some(scores) = scores {
+            page.next_match(scores);
+        }
+    })?;
+    result?;
+    match link.with(|page| page.client.clone()) {
+        Some(client) => load_clips(&link, &client).await,
+        None => Ok(()),
+    }
+}
+
+/// Send the outcome of a match, or take back the last one, and get the scores to pick the next
+/// match from.
+async fn decide(
+    link: &Link<RandomPage>,
+    action: MatchAction,
+    track1: Score,
+    track2: Score,
+) -> Result<Option<Scores>, JsValue> {
+    let window = web_sys::window().expect("no global `window` exists");
+    let (client, id) = match link.with(|page| (page.client.clone(), page.playlist.clone())) {
+        Some(page) => page,
+        None => return Ok(None),
+    };
+    let outcome = match action {
+        MatchAction::Win1 => Some((&track1, &track2, false)),
+        MatchAction::Win2 => Some((&track2, &track1, false)),
+        MatchAction::Draw => Some((&track1, &track2, true)),
+        MatchAction::Skip | MatchAction::Undo => None,
+    };
+    if let Some((win, lose, draw)) = outcome {
+        // Matches change the playlist's own scores if it rates its tracks separately
+        let query = EloQuery {
+            win: win.track_id.clone(),
+            lose: lose.track_id.clone(),
+            playlist: Some(id.clone()),
+            draw,
+        };
+        match client.elo(&query).await {
+            Ok(played) => link.set(|page| {
+                page.played.push(Played {
+                    id: played.id,
+                    track1,
+                    track2,
+                })
+            }),
+            // TODO: error handling
+            Err(ApiError::Status(_)) => {}
+            Err(e) => return Err(js_error(e)),
+        }
+    } else if let MatchAction::Undo = action {
+        let mut last = None;
+        link.set(|page| last = page.played.pop());
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        match client.undo_match(&last.id).await {
+            Ok(()) => {
+                // Show the match again, followed by the one that was shown
+                link.set(|page| {
+                    page.queue.push(track2);
+                    page.queue.push(track1);
+                    page.queue.push(last.track2);
+                    page.queue.push(last.track1);
+                });
+            }
+            Err(ApiError::Status(409)) => {
+                // The tracks played somewhere else since, so earlier matches can't be taken back
+                // either, but the match that's shown can still be decided
+                link.set(|page| {
+                    page.played.clear();
+                    page.queue.push(track2);
+                    page.queue.push(track1);
+                });
+                window.alert_with_message(
+                    "This match can't be undone because its tracks have played since",
+                )?;
+            }
+            // TODO: error handling
+            Err(ApiError::Status(_)) => link.set(|page| {
+                // Undoing can be tried again
+                page.played.push(last);
+                page.queue.push(track2);
+                page.queue.push(track1);
+            }),
+            Err(e) => return Err(js_error(e)),
+        }
+    }
+    fetch_scores(&client, &id).await.map(Some)
+}
+
+/// Load clips for the library tracks in the match that's shown.
+async fn load_clips(link: &Link<RandomPage>, client: &Client) -> Result<(), JsValue> {
+    let tracks = link.with(|page| {
+        page.current
+            .iter()
+            .flat_map(|(track1, track2)| vec![&track1.track_id, &track2.track_id])
+            .filter(|id| ProviderId::parse(id).provider == songsort::LIBRARY)
+            .filter(|&id| !page.clips.contains_key(id))
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+    for track_id in tracks.unwrap_or_default() {
+        if let Some(clip) = fetch_clip(client, &track_id).await? {
+            link.update(|page| {
+                page.clips.insert(track_id, clip);
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Play or pause a track, in whichever player shows it.
+fn toggle_preview(document: &Document, n: &str) -> Result<(), JsValue> {
+    // Only library tracks have an audio player
+    if let Some(audio) = document.get_element_by_id(&format!("audio{}", n)) {
+        let audio = audio.dyn_into::<HtmlAudioElement>()?;
+        if audio.paused() {
+            audio.play()?;
+        } else {
+            audio.pause()?;
+        }
+        return Ok(());
+    }
+    let iframe = document
+        .get_element_by_id(&format!("iframe{}", n))
+        .ok_or_else(|| JsValue::from("iframe element missing"))?
+        .dyn_into::<HtmlIFrameElement>()?;
+    if iframe.has_attribute("hidden") {
+        return Ok(());
+    }
+    // The Spotify embed plays and pauses when it's asked to
+    if let Some(embed) = iframe.content_window() {
+        let message = js_sys::Object::new();
+        let (key, value) = (JsValue::from("command"), JsValue::from("toggle"));
+        js_sys::Reflect::set(&message, &key, &value)?;
+        embed.post_message(&message, "https://open.spotify.com")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,280 @@
+//! A track's page: where it ranks, who it has played and how its rating changed.
+
+use crate::view::{el, svg, Component, Html, Link};
+use crate::{fetch_clip, js_error, linked_row, player, table, Client, Page};
+use songsort::api::{Api, ApiError, PlaylistQuery};
+use songsort::history::{RatingHistory, RatingPoint};
+use songsort::{ProviderId, TrackDetails};
+use wasm_bindgen::prelude::*;
+
+pub struct TrackPage {
+    client: Client,
+    id: String,
+    /// The playlist whose ratings are shown, if the track's shared score isn't
+    playlist: Option<String>,
+    details: Option<TrackDetails>,
+    history: Option<RatingHistory>,
+    clip: Option<String>,
+    /// Whether the track has no score, like after it was merged into another
+    missing: bool,
+}
+
+impl TrackPage {
+    pub fn new(client: Client, id: String, playlist: Option<String>) -> TrackPage {
+        TrackPage {
+            client,
+            id,
+            playlist,
+            details: None,
+            history: None,
+            clip: None,
+            missing: false,
+        }
+    }
+}
+
+impl Component for TrackPage {
+    fn init(&mut self, link: &Link<TrackPage>) -> Result<(), JsValue> {
+        let (link, client, id) = (link.clone(), self.client.clone(), self.id.clone());
+        let query = PlaylistQuery {
+            playlist: self.playlist.clone(),
+        };
+        wasm_bindgen_futures::spawn_local(async move {
+            load(&link, &client, &id, &query).await.unwrap()
+        });
+        Ok(())
+    }
+
+    fn view(&self, _link: &Link<TrackPage>) -> Html {
+        let mut page = el("div").id("track");
+        if self.missing {
+            return page
+                .child(el("h1").text("Track not found"))
+                .child(
+                    el("p").text("This track hasn't been rated. ").child(
+                        el("a")
+                            .attr("href", Page::Home.hash())
+                            .text("Back to your playlists"),
+                    ),
+                )
+                .into();
+        }
+        let (details, history) = match (&self.details, &self.history) {
+            (Some(details), Some(history)) => (details, history),
+            _ => return page.into(),
+        };
+        let score = &details.score;
+        let mut album = score.album.clone();
+        if let Some(year) = score.release_year {
+            album.push_str(&format!(" ({})", year));
+        }
+        if let Some(duration_ms) = score.duration_ms {
+            let seconds = duration_ms / 1000;
+            album.push_str(&format!(", {}:{:02}", seconds / 60, seconds % 60));
+        }
+        let artwork = score.artwork_url.as_ref().map(|url| {
+            el("img")
+                .attr("src", url)
+                .attr("alt", &score.album)
+                .class("col-2 img-fluid")
+                .into()
+        });
+        let info = el("div")
+            .class("col")
+            .child(el("h1").text(&score.track))
+            .child(el("p").class("lead").text(score.artists.join(", ")))
+            .child(el("p").class("text-muted").text(album))
+            .child(el("p").text(format!(
+                "Rated {} with a record of {}-{}",
+                score.score, score.wins, score.losses
+            )));
+        let playlists = details
+            .playlists
+            .iter()
+            .map(|rank| {
+                el("tr")
+                    .child(el("td").text(&rank.name))
+                    .child(el("td").text(format!("{} of {}", rank.rank, rank.tracks)))
+                    .child(el("td").text(rank.score.to_string()))
+                    .into()
+            })
+            .collect();
+        let opponents = details
+            .opponents
+            .iter()
+            .map(|opponent| {
+                let page = Page::Track {
+                    id: opponent.track_id.clone(),
+                    playlist: self.playlist.clone(),
+                };
+                let record = if opponent.draws > 0 {
+                    format!("{}-{}-{}", opponent.wins, opponent.losses, opponent.draws)
+                } else {
+                    format!("{}-{}", opponent.wins, opponent.losses)
+                };
+                linked_row(&page)
+                    .key(&opponent.track_id)
+                    .child(el("td").child(el("a").attr("href", page.hash()).text(&opponent.track)))
+                    .child(el("td").text(record))
+                    .into()
+            })
+            .collect();
+        page = page
+            .child(el("div").class("row mb-3").children(artwork).child(info))
+            // The match page's players are 1 and 2
+            .child(player("0", score, self.clip.as_deref(), "80"))
+            .child(
+                el("div")
+                    .class("row")
+                    .child(
+                        el("div")
+                            .class("col-6")
+                            .child(el("h2").text("Playlists"))
+                            .child(table(
+                                &[("col-8", "Playlist"), ("", "Rank"), ("", "Score")],
+                                playlists,
+                            )),
+                    )
+                    .child(
+                        el("div")
+                            .class("col-6")
+                            .child(el("h2").text("Opponents"))
+                            .child(table(&[("col-8", "Track"), ("", "Record")], opponents)),
+                    ),
+            )
+            .child(el("h2").text("Rating history"));
+        if history.points.is_empty() {
+            page = page.child(el("p").text("This track hasn't played any matches yet."));
+        } else {
+            page = page.child(rating_chart(&history.points));
+        }
+        page.into()
+    }
+}
+
+/// Load the track's details and rating history, then a clip if it's from the music library.
+async fn load(
+    link: &Link<TrackPage>,
+    client: &Client,
+    id: &str,
+    query: &PlaylistQuery,
+) -> Result<(), JsValue> {
+    let details = match client.get_track(id, query).await {
+        Ok(details) => details,
+        Err(ApiError::Status(404)) => return link.update(|page| page.missing = true),
+        Err(e) => return Err(js_error(e)),
+    };
+    let history = client
+        .get_track_history(id, query)
+        .await
+        .map_err(js_error)?;
+    link.update(|page| {
+        page.details = Some(details);
+        page.history = Some(history);
+    })?;
+    if ProviderId::parse(id).provider != songsort::LIBRARY {
+        return Ok(());
+    }
+    if let Some(clip) = fetch_clip(client, id).await? {
+        link.update(|page| page.clip = Some(clip))?;
+    }
+    Ok(())
+}
+
+/// Plot ratings as a line with a dot for each match, green for wins, red for losses and gray for
+/// ties. Matches are spaced evenly since they're usually played in bursts.
+fn rating_chart(points: &[RatingPoint]) -> Html {
+    const WIDTH: f64 = 800.;
+    const HEIGHT: f64 = 300.;
+    const MARGIN: f64 = 40.;
+    // The rating before the first match starts the line
+    let first = &points[0];
+    let last = &points[points.len() - 1];
+    let ratings: Vec<_> = std::iter::once(first.score - first.change)
+        .chain(points.iter().map(|p| p.score))
+        .collect();
+    let low = *ratings.iter().min().unwrap();
+    let high = *ratings.iter().max().unwrap();
+    // Leave room above and below the line
+    let (min, max) = (low - 10, high + 10);
+    let x = |i: usize| MARGIN + i as f64 * (WIDTH - 2. * MARGIN) / points.len() as f64;
+    let y = |r: i32| MARGIN + f64::from(max - r) * (HEIGHT - 2. * MARGIN) / f64::from(max - min);
+
+    let axes = svg("polyline")
+        .attr(
+            "points",
+            format!(
+                "{left},{top} {left},{bottom} {right},{bottom}",
+                left = MARGIN,
+                top = MARGIN,
+                bottom = HEIGHT - MARGIN,
+                right = WIDTH - MARGIN
+            ),
+        )
+        .attr("fill", "none")
+        .attr("stroke", "#adb5bd");
+    let line = svg("polyline")
+        .attr(
+            "points",
+            ratings
+                .iter()
+                .enumerate()
+                .map(|(i, &r)| format!("{},{}", x(i), y(r)))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+        .attr("fill", "none")
+        .attr("stroke", "#0d6efd")
+        .attr("stroke-width", "2");
+    let dots = points.iter().enumerate().map(|(i, point)| {
+        let (fill, outcome) = if point.draw {
+            ("#6c757d", "Tied with")
+        } else if point.won {
+            ("#198754", "Beat")
+        } else {
+            ("#dc3545", "Lost to")
+        };
+        svg("circle")
+            .attr("cx", x(i + 1).to_string())
+            .attr("cy", y(point.score).to_string())
+            .attr("r", "4")
+            .attr("fill", fill)
+            // Shown on hover
+            .child(svg("title").text(format!(
+                "{} {} ({:+})",
+                outcome, point.opponent.track, point.change
+            )))
+            .into()
+    });
+    let below = HEIGHT - MARGIN + 16.;
+    let labels = vec![
+        (MARGIN - 4., y(high), "end", high.to_string()),
+        (MARGIN - 4., y(low), "end", low.to_string()),
+        (MARGIN, below, "start", date(first.timestamp)),
+        (WIDTH - MARGIN, below, "end", date(last.timestamp)),
+    ];
+    let labels = labels.into_iter().map(|(label_x, label_y, anchor, text)| {
+        svg("text")
+            .attr("x", label_x.to_string())
+            .attr("y", label_y.to_string())
+            .attr("text-anchor", anchor)
+            .attr("font-size", "12")
+            .text(text)
+            .into()
+    });
+    svg("svg")
+        .attr("viewBox", format!("0 0 {} {}", WIDTH, HEIGHT))
+        .attr("width", "100%")
+        .child(axes)
+        .child(line)
+        .children(dots)
+        .children(labels)
+        .into()
+}
+
+/// Format milliseconds since the Unix epoch as a date in the browser's locale.
+fn date(timestamp: u64) -> String {
+    js_sys::Date::new(&JsValue::from_f64(timestamp as f64))
+        .to_locale_date_string("default", &JsValue::UNDEFINED)
+        .into()
+}
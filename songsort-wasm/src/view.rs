@@ -0,0 +1,564 @@
+//! A small view layer. Components describe the DOM they want as `Html`, and their mount keeps the
+//! real DOM in step with the latest description, only touching what changed. Event listeners
+//! belong to the elements they're registered on, so they're removed and freed when the element is
+//! updated or unmounted instead of being leaked with `Closure::forget`.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::rc::{Rc, Weak};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Document, Element, Event, EventTarget, HtmlInputElement, HtmlSelectElement, Node};
+
+const SVG: &str = "http://www.w3.org/2000/svg";
+
+type Handler = Box<dyn Fn(Event)>;
+
+/// A description of a DOM node.
+pub enum Html {
+    Element(VElement),
+    Text(String),
+}
+
+impl Html {
+    fn key(&self) -> Option<&str> {
+        match self {
+            Html::Element(element) => element.key.as_deref(),
+            Html::Text(_) => None,
+        }
+    }
+}
+
+impl From<VElement> for Html {
+    fn from(element: VElement) -> Html {
+        Html::Element(element)
+    }
+}
+
+/// A description of an element, started with `el` or `svg`.
+pub struct VElement {
+    tag: &'static str,
+    namespace: Option<&'static str>,
+    key: Option<String>,
+    attributes: Vec<(&'static str, String)>,
+    value: Option<String>,
+    checked: Option<bool>,
+    listeners: Vec<(&'static str, Handler)>,
+    children: Vec<Html>,
+}
+
+/// Describe an HTML element.
+pub fn el(tag: &'static str) -> VElement {
+    VElement {
+        tag,
+        namespace: None,
+        key: None,
+        attributes: Vec::new(),
+        value: None,
+        checked: None,
+        listeners: Vec::new(),
+        children: Vec::new(),
+    }
+}
+
+/// Describe an SVG element.
+pub fn svg(tag: &'static str) -> VElement {
+    VElement {
+        namespace: Some(SVG),
+        ..el(tag)
+    }
+}
+
+impl VElement {
+    pub fn attr(mut self, name: &'static str, value: impl Into<String>) -> VElement {
+        self.attributes.push((name, value.into()));
+        self
+    }
+
+    /// Set a boolean attribute like `hidden` if `on` is true.
+    pub fn flag(self, name: &'static str, on: bool) -> VElement {
+        if on {
+            self.attr(name, "")
+        } else {
+            self
+        }
+    }
+
+    pub fn id(self, id: impl Into<String>) -> VElement {
+        self.attr("id", id)
+    }
+
+    pub fn class(self, class: impl Into<String>) -> VElement {
+        self.attr("class", class)
+    }
+
+    /// An element is only reused for a description with the same key. Rows keyed by their data
+    /// follow it when a list is reordered, and changing the key replaces the element.
+    pub fn key(mut self, key: impl Into<String>) -> VElement {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Keep an input's or a select's value, which the user can change, set to `value`.
+    pub fn value(mut self, value: impl Into<String>) -> VElement {
+        self.value = Some(value.into());
+        self
+    }
+
+    /// Keep a checkbox, which the user can change, checked or unchecked.
+    pub fn checked(mut self, checked: bool) -> VElement {
+        self.checked = Some(checked);
+        self
+    }
+
+    pub fn on(mut self, event: &'static str, handler: impl Fn(Event) + 'static) -> VElement {
+        self.listeners.push((event, Box::new(handler)));
+        self
+    }
+
+    /// Start `task` whenever `event` happens, for handlers that wait on requests.
+    pub fn spawn_on<F>(self, event: &'static str, task: impl Fn() -> F + 'static) -> VElement
+    where
+        F: Future<Output = Result<(), JsValue>> + 'static,
+    {
+        self.on(event, move |_| {
+            let future = task();
+            wasm_bindgen_futures::spawn_local(async move { future.await.unwrap() });
+        })
+    }
+
+    pub fn child(mut self, child: impl Into<Html>) -> VElement {
+        self.children.push(child.into());
+        self
+    }
+
+    pub fn children(mut self, children: impl IntoIterator<Item = Html>) -> VElement {
+        self.children.extend(children);
+        self
+    }
+
+    pub fn text(self, text: impl Into<String>) -> VElement {
+        self.child(Html::Text(text.into()))
+    }
+}
+
+/// The value of the input or select that an event happened on.
+pub fn target_value(event: &Event) -> String {
+    let target = event.target();
+    if let Some(input) = target
+        .as_ref()
+        .and_then(|t| t.dyn_ref::<HtmlInputElement>())
+    {
+        input.value()
+    } else if let Some(select) = target
+        .as_ref()
+        .and_then(|t| t.dyn_ref::<HtmlSelectElement>())
+    {
+        select.value()
+    } else {
+        String::new()
+    }
+}
+
+/// The input that an event happened on.
+pub fn target_input(event: &Event) -> Option<HtmlInputElement> {
+    event.target().and_then(|t| t.dyn_into().ok())
+}
+
+/// An event listener that's removed when it's dropped.
+pub struct Listener {
+    target: EventTarget,
+    event: &'static str,
+    closure: Closure<dyn FnMut(Event)>,
+}
+
+impl Listener {
+    pub fn new(
+        target: &EventTarget,
+        event: &'static str,
+        handler: impl Fn(Event) + 'static,
+    ) -> Result<Listener, JsValue> {
+        let closure = Closure::wrap(Box::new(move |e: Event| handler(e)) as Box<dyn FnMut(Event)>);
+        target.add_event_listener_with_callback(event, closure.as_ref().unchecked_ref())?;
+        Ok(Listener {
+            target: target.clone(),
+            event,
+            closure,
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.target
+            .remove_event_listener_with_callback(self.event, self.closure.as_ref().unchecked_ref())
+            .unwrap();
+    }
+}
+
+/// A piece of the page with state of its own. It's rendered again whenever its state is updated
+/// through its `Link`.
+pub trait Component: Sized + 'static {
+    /// Called once before the first render, to start loading data or to listen for events outside
+    /// the component's elements.
+    fn init(&mut self, _link: &Link<Self>) -> Result<(), JsValue> {
+        Ok(())
+    }
+
+    fn view(&self, link: &Link<Self>) -> Html;
+}
+
+/// Updates a component from its event handlers and tasks. Links don't keep the component alive,
+/// so updates after it's unmounted are ignored.
+pub struct Link<C> {
+    root: Weak<RefCell<Root<C>>>,
+}
+
+impl<C> Clone for Link<C> {
+    fn clone(&self) -> Link<C> {
+        Link {
+            root: Weak::clone(&self.root),
+        }
+    }
+}
+
+impl<C: Component> Link<C> {
+    /// Change the component's state and render it again.
+    pub fn update(&self, f: impl FnOnce(&mut C)) -> Result<(), JsValue> {
+        let root = match self.root.upgrade() {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        let mut borrowed_root = root.borrow_mut();
+        f(&mut borrowed_root.component);
+        borrowed_root.render(self)
+    }
+
+    /// Change state that the page already shows, like what's typed into an input, without
+    /// rendering again.
+    pub fn set(&self, f: impl FnOnce(&mut C)) {
+        if let Some(root) = self.root.upgrade() {
+            f(&mut root.borrow_mut().component);
+        }
+    }
+
+    /// Read the component's state, unless it has been unmounted.
+    pub fn with<R>(&self, f: impl FnOnce(&C) -> R) -> Option<R> {
+        self.root.upgrade().map(|root| f(&root.borrow().component))
+    }
+}
+
+struct Root<C> {
+    component: C,
+    parent: Element,
+    mounted: Option<Mounted>,
+}
+
+impl<C: Component> Root<C> {
+    fn render(&mut self, link: &Link<C>) -> Result<(), JsValue> {
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let html = self.component.view(link);
+        if let Some(mounted) = &mut self.mounted {
+            return patch(&document, mounted, html);
+        }
+        let mounted = create(&document, html)?;
+        self.parent.append_child(mounted.node())?;
+        self.mounted = Some(mounted);
+        Ok(())
+    }
+}
+
+trait Unmount {
+    fn unmount(&self) -> Result<(), JsValue>;
+}
+
+impl<C> Unmount for RefCell<Root<C>> {
+    fn unmount(&self) -> Result<(), JsValue> {
+        match self.borrow_mut().mounted.take() {
+            Some(mounted) => mounted.remove(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A mounted component. Dropping it removes the component's elements and their listeners.
+pub struct View {
+    root: Rc<dyn Unmount>,
+}
+
+impl Drop for View {
+    fn drop(&mut self) {
+        self.root.unmount().unwrap();
+    }
+}
+
+/// Render a component at the end of `parent`.
+pub fn mount<C: Component>(parent: &Element, component: C) -> Result<View, JsValue> {
+    let root = Rc::new(RefCell::new(Root {
+        component,
+        parent: parent.clone(),
+        mounted: None,
+    }));
+    let link = Link {
+        root: Rc::downgrade(&root),
+    };
+    root.borrow_mut().component.init(&link)?;
+    root.borrow_mut().render(&link)?;
+    Ok(View { root })
+}
+
+/// The DOM that was created for a description, with what's needed to compare it to the next one.
+enum Mounted {
+    Element {
+        element: Element,
+        tag: &'static str,
+        namespace: Option<&'static str>,
+        key: Option<String>,
+        attributes: Vec<(&'static str, String)>,
+        listeners: Vec<Listener>,
+        children: Vec<Mounted>,
+    },
+    Text {
+        node: Node,
+        text: String,
+    },
+}
+
+impl Mounted {
+    fn node(&self) -> &Node {
+        match self {
+            Mounted::Element { element, .. } => element.as_ref(),
+            Mounted::Text { node, .. } => node,
+        }
+    }
+
+    fn key(&self) -> Option<&str> {
+        match self {
+            Mounted::Element { key, .. } => key.as_deref(),
+            Mounted::Text { .. } => None,
+        }
+    }
+
+    fn remove(&self) -> Result<(), JsValue> {
+        if let Some(parent) = self.node().parent_node() {
+            parent.remove_child(self.node())?;
+        }
+        Ok(())
+    }
+}
+
+fn create(document: &Document, html: Html) -> Result<Mounted, JsValue> {
+    let description = match html {
+        Html::Element(description) => description,
+        Html::Text(text) => {
+            return Ok(Mounted::Text {
+                node: document.create_text_node(&text).into(),
+                text,
+            })
+        }
+    };
+    let element = match description.namespace {
+        Some(namespace) => document.create_element_ns(Some(namespace), description.tag)?,
+        None => document.create_element(description.tag)?,
+    };
+    for (name, value) in &description.attributes {
+        element.set_attribute(name, value)?;
+    }
+    let listeners = listen(&element, description.listeners)?;
+    let mut children = Vec::new();
+    for child in description.children {
+        let child = create(document, child)?;
+        element.append_child(child.node())?;
+        children.push(child);
+    }
+    // A select's value can only be set once it has its options
+    set_value(&element, description.value.as_deref(), description.checked);
+    Ok(Mounted::Element {
+        element,
+        tag: description.tag,
+        namespace: description.namespace,
+        key: description.key,
+        attributes: description.attributes,
+        listeners,
+        children,
+    })
+}
+
+/// Change `mounted` to match `html`. Elements are replaced if they're a different kind of element
+/// or have a different key.
+fn patch(document: &Document, mounted: &mut Mounted, html: Html) -> Result<(), JsValue> {
+    let html = match (&mut *mounted, html) {
+        (Mounted::Text { node, text }, Html::Text(new_text)) => {
+            if *text != new_text {
+                node.set_text_content(Some(&new_text));
+                *text = new_text;
+            }
+            return Ok(());
+        }
+        (
+            Mounted::Element {
+                element,
+                tag,
+                namespace,
+                key,
+                attributes,
+                listeners,
+                children,
+            },
+            Html::Element(description),
+        ) if *tag == description.tag
+            && *namespace == description.namespace
+            && *key == description.key =>
+        {
+            for (name, value) in &description.attributes {
+                if !attributes.iter().any(|(n, v)| n == name && v == value) {
+                    element.set_attribute(name, value)?;
+                }
+            }
+            for (name, _) in attributes.iter() {
+                if !description.attributes.iter().any(|(n, _)| n == name) {
+                    element.remove_attribute(name)?;
+                }
+            }
+            *attributes = description.attributes;
+            // Handlers can't be compared, so they're all replaced
+            listeners.clear();
+            *listeners = listen(element, description.listeners)?;
+            patch_children(document, element, children, description.children)?;
+            set_value(element, description.value.as_deref(), description.checked);
+            return Ok(());
+        }
+        (_, html) => html,
+    };
+    let replacement = create(document, html)?;
+    if let Some(parent) = mounted.node().parent_node() {
+        parent.replace_child(replacement.node(), mounted.node())?;
+    }
+    *mounted = replacement;
+    Ok(())
+}
+
+/// Change an element's children to match `descriptions`. Keyed children are matched by key and
+/// others by position, and whatever isn't matched is created or removed.
+fn patch_children(
+    document: &Document,
+    parent: &Element,
+    children: &mut Vec<Mounted>,
+    descriptions: Vec<Html>,
+) -> Result<(), JsValue> {
+    let matches = {
+        let old: Vec<_> = children.iter().map(Mounted::key).collect();
+        let new: Vec<_> = descriptions.iter().map(Html::key).collect();
+        match_children(&old, &new)
+    };
+    let mut old: Vec<_> = children.drain(..).map(Some).collect();
+    for ((i, html), found) in descriptions.into_iter().enumerate().zip(matches) {
+        let child = match found.and_then(|j| old[j].take()) {
+            Some(mut child) => {
+                patch(document, &mut child, html)?;
+                child
+            }
+            None => create(document, html)?,
+        };
+        // The children before this one are already in place
+        let next = parent.child_nodes().item(i as u32);
+        if next.as_ref() != Some(child.node()) {
+            parent.insert_before(child.node(), next.as_ref())?;
+        }
+        children.push(child);
+    }
+    for child in old.into_iter().flatten() {
+        child.remove()?;
+    }
+    Ok(())
+}
+
+/// Find the old child that each new child updates, by their keys. Keyed children match the old
+/// child with the same key and others the unkeyed old child in the same position. Each old child
+/// is matched at most once.
+fn match_children(old: &[Option<&str>], new: &[Option<&str>]) -> Vec<Option<usize>> {
+    let mut taken = vec![false; old.len()];
+    new.iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let found = match key {
+                Some(_) => (0..old.len()).find(|&j| !taken[j] && old[j] == *key),
+                None => Some(i).filter(|&i| i < old.len() && !taken[i] && old[i].is_none()),
+            };
+            if let Some(j) = found {
+                taken[j] = true;
+            }
+            found
+        })
+        .collect()
+}
+
+fn listen(
+    element: &Element,
+    handlers: Vec<(&'static str, Handler)>,
+) -> Result<Vec<Listener>, JsValue> {
+    handlers
+        .into_iter()
+        .map(|(event, handler)| Listener::new(element, event, handler))
+        .collect()
+}
+
+/// Values are compared with the element's own, since the user changes them without a render.
+fn set_value(element: &Element, value: Option<&str>, checked: Option<bool>) {
+    if let Some(input) = element.dyn_ref::<HtmlInputElement>() {
+        if let Some(value) = value.filter(|&v| v != input.value()) {
+            input.set_value(value);
+        }
+        if let Some(checked) = checked.filter(|&c| c != input.checked()) {
+            input.set_checked(checked);
+        }
+    } else if let Some(select) = element.dyn_ref::<HtmlSelectElement>() {
+        if let Some(value) = value.filter(|&v| v != select.value()) {
+            select.set_value(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::match_children;
+
+    #[test]
+    fn unkeyed_children_match_by_position() {
+        assert_eq!(
+            match_children(&[None, None], &[None, None, None]),
+            [Some(0), Some(1), None]
+        );
+        assert_eq!(match_children(&[None, None, None], &[None]), [Some(0)]);
+    }
+
+    #[test]
+    fn keyed_children_match_by_key() {
+        assert_eq!(
+            match_children(&[Some("a"), Some("b"), Some("c")], &[Some("c"), Some("a")]),
+            [Some(2), Some(0)]
+        );
+        assert_eq!(match_children(&[Some("a")], &[Some("b")]), [None]);
+    }
+
+    #[test]
+    fn keyed_and_unkeyed_children_dont_match() {
+        assert_eq!(
+            match_children(&[Some("a"), None], &[None, Some("b")]),
+            [None, None]
+        );
+        assert_eq!(
+            match_children(&[None, Some("a")], &[Some("a"), None]),
+            [Some(1), None]
+        );
+    }
+
+    #[test]
+    fn old_children_match_once() {
+        assert_eq!(
+            match_children(&[Some("a"), Some("a")], &[Some("a"), Some("a"), Some("a")]),
+            [Some(0), Some(1), None]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use crate::config::AssetsConfig;
+use crate::{get_response_builder, method_not_allowed, not_found, Error};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+const INDEX: &str = "index.html";
+
+/// Files built into the binary with the `embed-assets` feature, used when no directory is
+/// configured or a file is missing from it.
+#[cfg(feature = "embed-assets")]
+const EMBEDDED: &[(&str, &[u8])] = &[
+    (
+        INDEX,
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../songsort-wasm/www/index.html"
+        )),
+    ),
+    (
+        "songsort_wasm.js",
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../songsort-wasm/pkg/songsort_wasm.js"
+        )),
+    ),
+    (
+        "songsort_wasm_bg.wasm",
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../songsort-wasm/pkg/songsort_wasm_bg.wasm"
+        )),
+    ),
+];
+#[cfg(not(feature = "embed-assets"))]
+const EMBEDDED: &[(&str, &[u8])] = &[];
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+/// A file name and the encoding it's stored in.
+type AssetKey = (String, Option<Encoding>);
+
+struct Asset {
+    contents: Vec<u8>,
+    etag: String,
+}
+
+impl Asset {
+    fn new(contents: Vec<u8>) -> Asset {
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        Asset {
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            contents,
+        }
+    }
+}
+
+/// Serves the wasm frontend from the configured directory or the embedded copy.
+pub struct Assets {
+    dir: Option<PathBuf>,
+    index: Option<PathBuf>,
+    /// Only files that exist are cached, so requests for made up paths can't grow it.
+    cache: RwLock<HashMap<AssetKey, Arc<Asset>>>,
+}
+
+impl Assets {
+    pub fn new(config: &AssetsConfig) -> Assets {
+        Assets {
+            dir: config.dir.clone(),
+            index: config.index.clone(),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn serve(&self, req: &Request<Body>) -> Result<Response<Body>, Error> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return method_not_allowed();
+        }
+        let Some(name) = asset_name(req.uri().path()) else {
+            return not_found();
+        };
+        let accepted = accepted_encodings(req.headers());
+        for encoding in [Some(Encoding::Brotli), Some(Encoding::Gzip), None] {
+            if let Some(encoding) = encoding {
+                if !accepted.contains(&encoding) {
+                    continue;
+                }
+            }
+            let Some(asset) = self.load(&name, encoding).await? else {
+                continue;
+            };
+            let mut builder = get_response_builder()
+                .header("Content-Type", HeaderValue::from_static(mime_type(&name)))
+                .header(
+                    "Cache-Control",
+                    HeaderValue::from_static(cache_control(&name)),
+                )
+                .header("ETag", &asset.etag)
+                .header("Vary", HeaderValue::from_static("Accept-Encoding"));
+            if let Some(encoding) = encoding {
+                builder = builder.header("Content-Encoding", encoding.name());
+            }
+            let not_modified = req
+                .headers()
+                .get("If-None-Match")
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |tags| {
+                    tags.split(',')
+                        .any(|tag| tag.trim() == asset.etag || tag.trim() == "*")
+                });
+            let resp = if not_modified {
+                builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+            } else {
+                let builder = builder
+                    .status(StatusCode::OK)
+                    .header("Content-Length", asset.contents.len());
+                if req.method() == Method::HEAD {
+                    builder.body(Body::empty())
+                } else {
+                    builder.body(Body::from(asset.contents.clone()))
+                }
+            };
+            return resp.map_err(Error::from);
+        }
+        not_found()
+    }
+
+    async fn load(
+        &self,
+        name: &str,
+        encoding: Option<Encoding>,
+    ) -> Result<Option<Arc<Asset>>, Error> {
+        let key = (name.to_owned(), encoding);
+        if let Some(asset) = self.cache.read().unwrap().get(&key) {
+            return Ok(Some(Arc::clone(asset)));
+        }
+        let Some(asset) = self.read(name, encoding).await? else {
+            return Ok(None);
+        };
+        let asset = Arc::new(asset);
+        // Always pick up freshly built files during development
+        if cfg!(not(feature = "dev")) {
+            self.cache.write().unwrap().insert(key, Arc::clone(&asset));
+        }
+        Ok(Some(asset))
+    }
+
+    async fn read(&self, name: &str, encoding: Option<Encoding>) -> Result<Option<Asset>, Error> {
+        let path = if name == INDEX {
+            self.index
+                .clone()
+                .or_else(|| self.dir.as_ref().map(|dir| dir.join(INDEX)))
+        } else {
+            self.dir.as_ref().map(|dir| dir.join(name))
+        };
+        if let Some(mut path) = path {
+            if let Some(encoding) = encoding {
+                let mut file_name = path.file_name().unwrap_or_default().to_owned();
+                file_name.push(".");
+                file_name.push(encoding.extension());
+                path.set_file_name(file_name);
+            }
+            match tokio::fs::read(&path).await {
+                Ok(contents) => return Ok(Some(Asset::new(contents))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+        if encoding.is_some() {
+            return Ok(None);
+        }
+        Ok(EMBEDDED
+            .iter()
+            .find(|(embedded, _)| *embedded == name)
+            .map(|(_, contents)| Asset::new(contents.to_vec())))
+    }
+}
+
+/// Map a request path to a file name relative to the asset directory, refusing anything that
+/// could escape it.
+fn asset_name(path: &str) -> Option<String> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return Some(String::from(INDEX));
+    }
+    if path
+        .split('/')
+        .all(|s| !s.is_empty() && s != "." && s != ".." && !s.contains('\\'))
+    {
+        Some(path.to_owned())
+    } else {
+        None
+    }
+}
+
+fn accepted_encodings(headers: &HeaderMap) -> Vec<Encoding> {
+    let Some(accept) = headers.get("Accept-Encoding").and_then(|v| v.to_str().ok()) else {
+        return Vec::new();
+    };
+    accept
+        .split(',')
+        .filter_map(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next()?;
+            if parts.any(|p| p.replace(' ', "") == "q=0") {
+                return None;
+            }
+            match name {
+                "br" => Some(Encoding::Brotli),
+                "gzip" => Some(Encoding::Gzip),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn mime_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "application/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json" | "map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Files with a content hash in their name never change, so they can be cached forever. Anything
+/// else has to be revalidated with its ETag.
+fn cache_control(name: &str) -> &'static str {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    let is_hashed = stem
+        .rsplit(|c: char| c == '.' || c == '-')
+        .next()
+        .map_or(false, |hash| {
+            hash.len() >= 8 && hash.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    if is_hashed {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh asset directory containing `files`.
+    fn asset_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("songsort-assets-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn assets(dir: PathBuf) -> Assets {
+        Assets::new(&AssetsConfig {
+            dir: Some(dir),
+            index: None,
+        })
+    }
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header("Accept-Encoding", "gzip, br;q=0")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        assert_eq!(asset_name("/").as_deref(), Some(INDEX));
+        assert_eq!(asset_name("/pkg/app.js").as_deref(), Some("pkg/app.js"));
+        for path in ["/../secret", "/a/./b", "/a//b", "/a\\..\\b", "/a/"] {
+            assert_eq!(asset_name(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn encodings() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Accept-Encoding",
+            HeaderValue::from_static("gzip;q=0.5, br; q=0, deflate"),
+        );
+        assert_eq!(accepted_encodings(&headers), [Encoding::Gzip]);
+        assert!(accepted_encodings(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn hashed_names_are_immutable() {
+        assert_eq!(
+            cache_control("app-0123abcd.js"),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(cache_control("songsort_wasm.js"), "no-cache");
+        assert_eq!(mime_type("songsort_wasm_bg.wasm"), "application/wasm");
+    }
+
+    #[tokio::test]
+    async fn only_existing_files_are_cached() {
+        let assets = assets(asset_dir("cache", &[("app.js", "app")]));
+        for path in ["/missing.js", "/missing.css"] {
+            let resp = assets.serve(&request(Method::GET, path)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        assert!(assets.cache.read().unwrap().is_empty());
+        let resp = assets
+            .serve(&request(Method::GET, "/app.js"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        if cfg!(not(feature = "dev")) {
+            assert_eq!(assets.cache.read().unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_files_are_preferred() {
+        let assets = assets(asset_dir(
+            "encoded",
+            &[("app.js", "app"), ("app.js.gz", "gzipped")],
+        ));
+        let resp = assets
+            .serve(&request(Method::GET, "/app.js"))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["Content-Encoding"], "gzip");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"gzipped");
+    }
+
+    #[tokio::test]
+    async fn head_has_length_without_body() {
+        let assets = assets(asset_dir("head", &[("index.html", "<html></html>")]));
+        let resp = assets.serve(&request(Method::HEAD, "/")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Length"], "13");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+}
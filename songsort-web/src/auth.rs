@@ -0,0 +1,93 @@
+/// The user whose data the demo shows.
+pub const DEMO_USER: &str = "demo";
+
+/// What a route needs from its caller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    /// No Authorization header is needed.
+    Public,
+    /// Read the caller's playlists and scores.
+    Read,
+    /// Import, delete or rate the caller's playlists.
+    Write,
+    /// Use the caller's own Spotify account.
+    Spotify,
+    /// Inspect and operate the server.
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// The shared demo data, which visitors can look at but not change.
+    Demo,
+    /// A visitor's own temporary copy of the demo data.
+    Sandbox,
+    /// A logged in Spotify user.
+    User,
+    /// A logged in Spotify user listed in the `admins` setting.
+    Admin,
+}
+
+impl Role {
+    pub fn grants(self, permission: Permission) -> bool {
+        match (self, permission) {
+            (Role::Admin, _) => true,
+            (_, Permission::Public) | (_, Permission::Read) => true,
+            (Role::Sandbox | Role::User, Permission::Write) => true,
+            (Role::User, Permission::Spotify) => true,
+            (Role::Demo, Permission::Write)
+            | (Role::Demo | Role::Sandbox, Permission::Spotify)
+            | (_, Permission::Admin) => false,
+        }
+    }
+}
+
+/// The caller of an authenticated route.
+pub struct Principal {
+    pub user_id: String,
+    pub role: Role,
+    pub access_token: Option<String>,
+}
+
+impl Principal {
+    /// Recognize the demo credentials `demo` and `demo:<visitor>`.
+    ///
+    /// Visitors only get a sandbox if sandboxes are enabled, otherwise they share the read-only
+    /// demo user. Returns `None` for anything that should be treated as a Spotify login.
+    pub fn demo(auth: &str, sandbox: bool) -> Option<Principal> {
+        let visitor = match auth.split_once(':') {
+            None if auth == DEMO_USER => None,
+            Some((DEMO_USER, visitor)) => Some(visitor),
+            _ => return None,
+        };
+        Some(match visitor {
+            Some(visitor) if sandbox && is_visitor_id(visitor) => Principal {
+                user_id: format!("{}:{}", DEMO_USER, visitor),
+                role: Role::Sandbox,
+                access_token: None,
+            },
+            _ => Principal {
+                user_id: String::from(DEMO_USER),
+                role: Role::Demo,
+                access_token: None,
+            },
+        })
+    }
+}
+
+/// The role of a logged in Spotify user.
+pub fn user_role(user_id: &str, admins: &[String]) -> Role {
+    if admins.iter().any(|admin| admin == user_id) {
+        Role::Admin
+    } else {
+        Role::User
+    }
+}
+
+fn is_visitor_id(visitor: &str) -> bool {
+    !visitor.is_empty()
+        && visitor.len() <= 64
+        && visitor
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
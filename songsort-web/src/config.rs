@@ -0,0 +1,498 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_CONFIG: &str = "songsort.toml";
+const DEFAULT_DEMO_PLAYLIST: &str = "37i9dQZF1DX49jUV2NfGku";
+
+/// Server settings, merged from defaults, a TOML file, environment variables and CLI flags, in
+/// increasing order of precedence.
+#[derive(Debug)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub storage: StorageConfig,
+    pub spotify: SpotifyConfig,
+    pub demo: DemoConfig,
+    pub assets: AssetsConfig,
+    pub library: LibraryConfig,
+    /// Spotify user ids that are allowed to use the admin endpoints.
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum StorageConfig {
+    Cosmos {
+        account: String,
+        master_key: String,
+        database: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct SpotifyConfig {
+    /// Base64 encoded `client_id:client_secret`.
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub struct DemoConfig {
+    pub playlist_id: String,
+    pub sandbox: bool,
+    pub reset_interval: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct AssetsConfig {
+    /// Directory containing the wasm-pack output.
+    pub dir: Option<PathBuf>,
+    /// The page served at `/`.
+    pub index: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LibraryConfig {
+    /// Directory of audio files to import from, the library provider is disabled without it.
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Missing {
+        setting: &'static str,
+        env: &'static str,
+        flag: &'static str,
+    },
+    Invalid {
+        setting: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Missing { setting, env, flag } => write!(
+                f,
+                "{} is not configured; set it in the config file, {} or --{}",
+                setting, env, flag
+            ),
+            ConfigError::Invalid { setting, reason } => {
+                write!(f, "invalid {}: {}", setting, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Parser)]
+#[clap(about = "Songsort API server")]
+struct Args {
+    /// TOML config file, defaults to ./songsort.toml if it exists
+    #[clap(long, env = "SONGSORT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on
+    #[clap(long, env = "SONGSORT_BIND")]
+    bind: Option<SocketAddr>,
+    /// Storage backend, only "cosmos" is supported
+    #[clap(long, env = "SONGSORT_STORAGE")]
+    storage: Option<String>,
+    #[clap(long, env = "COSMOS_ACCOUNT")]
+    cosmos_account: Option<String>,
+    #[clap(long, env = "COSMOS_MASTER_KEY", hide_env_values = true)]
+    cosmos_master_key: Option<String>,
+    #[clap(long, env = "SONGSORT_DATABASE")]
+    database: Option<String>,
+    /// Base64 encoded Spotify client_id:client_secret
+    #[clap(long, env = "SPOTIFY_TOKEN", hide_env_values = true)]
+    spotify_token: Option<String>,
+    /// Spotify playlist that the demo data is restored from
+    #[clap(long, env = "DEMO_PLAYLIST")]
+    demo_playlist: Option<String>,
+    /// Give demo visitors a writable copy of the demo data
+    #[clap(long, env = "DEMO_SANDBOX", parse(try_from_str = parse_bool))]
+    demo_sandbox: Option<bool>,
+    /// Seconds between demo resets, 0 to disable
+    #[clap(long, env = "DEMO_RESET_INTERVAL")]
+    demo_reset_interval: Option<u64>,
+    /// Directory containing the wasm-pack output
+    #[clap(long, env = "SONGSORT_ASSETS_DIR")]
+    assets_dir: Option<PathBuf>,
+    /// Page served at /
+    #[clap(long, env = "SONGSORT_ASSETS_INDEX")]
+    assets_index: Option<PathBuf>,
+    /// Directory of FLAC, MP3, Ogg and M4A files to import from
+    #[clap(long, env = "SONGSORT_LIBRARY_DIR")]
+    library_dir: Option<PathBuf>,
+    /// Comma separated Spotify user ids with admin access
+    #[clap(long, env = "ADMIN_USERS", use_value_delimiter = true)]
+    admins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<SocketAddr>,
+    storage: FileStorage,
+    spotify: FileSpotify,
+    demo: FileDemo,
+    assets: FileAssets,
+    library: FileLibrary,
+    admins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileStorage {
+    backend: Option<String>,
+    account: Option<String>,
+    master_key: Option<String>,
+    database: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileSpotify {
+    token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileDemo {
+    playlist: Option<String>,
+    sandbox: Option<bool>,
+    reset_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileAssets {
+    dir: Option<PathBuf>,
+    index: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileLibrary {
+    dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Load the configuration for this process from its arguments and environment.
+    pub fn load() -> Result<Config, ConfigError> {
+        let args = Args::parse();
+        let file = match &args.config {
+            Some(path) => read_file(path.clone())?,
+            None if std::path::Path::new(DEFAULT_CONFIG).exists() => {
+                read_file(PathBuf::from(DEFAULT_CONFIG))?
+            }
+            None => FileConfig::default(),
+        };
+        Config::merge(args, file)
+    }
+
+    fn merge(args: Args, file: FileConfig) -> Result<Config, ConfigError> {
+        let storage = match args
+            .storage
+            .or(file.storage.backend)
+            .unwrap_or_else(|| String::from("cosmos"))
+            .as_str()
+        {
+            "cosmos" => StorageConfig::Cosmos {
+                account: required(
+                    args.cosmos_account.or(file.storage.account),
+                    "storage.account",
+                    "COSMOS_ACCOUNT",
+                    "cosmos-account",
+                )?,
+                master_key: required(
+                    args.cosmos_master_key.or(file.storage.master_key),
+                    "storage.master_key",
+                    "COSMOS_MASTER_KEY",
+                    "cosmos-master-key",
+                )?,
+                database: args
+                    .database
+                    .or(file.storage.database)
+                    .unwrap_or_else(|| String::from("songsort")),
+            },
+            backend => {
+                return Err(ConfigError::Invalid {
+                    setting: "storage.backend",
+                    reason: format!("unsupported backend {:?}", backend),
+                })
+            }
+        };
+        let spotify = SpotifyConfig {
+            token: required(
+                args.spotify_token.or(file.spotify.token),
+                "spotify.token",
+                "SPOTIFY_TOKEN",
+                "spotify-token",
+            )?,
+        };
+        let playlist_id = args
+            .demo_playlist
+            .or(file.demo.playlist)
+            .unwrap_or_else(|| String::from(DEFAULT_DEMO_PLAYLIST));
+        if playlist_id.is_empty() || !playlist_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(ConfigError::Invalid {
+                setting: "demo.playlist",
+                reason: format!("{:?} is not a Spotify playlist id", playlist_id),
+            });
+        }
+        // Reset demo user data daily in production
+        let reset_interval = match args.demo_reset_interval.or(file.demo.reset_interval) {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None if cfg!(feature = "dev") => None,
+            None => Some(Duration::from_secs(60 * 60 * 24)),
+        };
+        let demo = DemoConfig {
+            playlist_id,
+            sandbox: args.demo_sandbox.or(file.demo.sandbox).unwrap_or(false),
+            reset_interval,
+        };
+        let dir = args.assets_dir.or(file.assets.dir);
+        if let Some(dir) = &dir {
+            if !dir.is_dir() {
+                return Err(ConfigError::Invalid {
+                    setting: "assets.dir",
+                    reason: format!("{} is not a directory", dir.display()),
+                });
+            }
+        }
+        let index = args.assets_index.or(file.assets.index);
+        if let Some(index) = &index {
+            if !index.is_file() {
+                return Err(ConfigError::Invalid {
+                    setting: "assets.index",
+                    reason: format!("{} is not a file", index.display()),
+                });
+            }
+        }
+        // Serve the frontend straight out of the source tree during development
+        let assets = if cfg!(feature = "dev") {
+            AssetsConfig {
+                dir: dir.or_else(|| Some(PathBuf::from("../songsort-wasm/pkg"))),
+                index: index.or_else(|| Some(PathBuf::from("../songsort-wasm/www/index.html"))),
+            }
+        } else {
+            AssetsConfig { dir, index }
+        };
+        let library_dir = args.library_dir.or(file.library.dir);
+        if let Some(dir) = &library_dir {
+            if !dir.is_dir() {
+                return Err(ConfigError::Invalid {
+                    setting: "library.dir",
+                    reason: format!("{} is not a directory", dir.display()),
+                });
+            }
+        }
+        Ok(Config {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+            storage,
+            spotify,
+            demo,
+            assets,
+            library: LibraryConfig { dir: library_dir },
+            admins: args.admins.or(file.admins).unwrap_or_default(),
+        })
+    }
+}
+
+fn read_file(path: PathBuf) -> Result<FileConfig, ConfigError> {
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(ConfigError::Read(path, e)),
+    };
+    toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))
+}
+
+/// Accept the ways that switches are usually written in the environment, like `DEMO_SANDBOX=1`.
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("{:?} is not true or false", value)),
+    }
+}
+
+fn required(
+    value: Option<String>,
+    setting: &'static str,
+    env: &'static str,
+    flag: &'static str,
+) -> Result<String, ConfigError> {
+    match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(ConfigError::Missing { setting, env, flag }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required_args() -> Args {
+        Args {
+            cosmos_account: Some(String::from("account")),
+            cosmos_master_key: Some(String::from("key")),
+            spotify_token: Some(String::from("token")),
+            ..Args::default()
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::merge(required_args(), FileConfig::default()).unwrap();
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 3000)));
+        let StorageConfig::Cosmos { database, .. } = config.storage;
+        assert_eq!(database, "songsort");
+        assert_eq!(config.demo.playlist_id, DEFAULT_DEMO_PLAYLIST);
+        assert!(!config.demo.sandbox);
+        assert!(config.admins.is_empty());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            bind = "0.0.0.0:80"
+            admins = ["file"]
+
+            [storage]
+            account = "file"
+            database = "file"
+
+            [demo]
+            sandbox = true
+            reset_interval = 60
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            bind: Some(SocketAddr::from(([127, 0, 0, 1], 8080))),
+            admins: Some(vec![String::from("flag")]),
+            ..required_args()
+        };
+        let config = Config::merge(args, file).unwrap();
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(config.admins, ["flag"]);
+        let StorageConfig::Cosmos {
+            account, database, ..
+        } = config.storage;
+        assert_eq!(account, "account");
+        // Settings without a flag come from the file
+        assert_eq!(database, "file");
+        assert!(config.demo.sandbox);
+        assert_eq!(config.demo.reset_interval, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn missing_settings() {
+        let args = Args {
+            spotify_token: None,
+            ..required_args()
+        };
+        let e = Config::merge(args, FileConfig::default()).unwrap_err();
+        assert!(matches!(
+            e,
+            ConfigError::Missing {
+                setting: "spotify.token",
+                ..
+            }
+        ));
+        // Empty settings count as missing
+        let args = Args {
+            cosmos_master_key: Some(String::new()),
+            ..required_args()
+        };
+        let e = Config::merge(args, FileConfig::default()).unwrap_err();
+        assert!(matches!(
+            e,
+            ConfigError::Missing {
+                setting: "storage.master_key",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let args = Args {
+            storage: Some(String::from("sqlite")),
+            ..required_args()
+        };
+        let e = Config::merge(args, FileConfig::default()).unwrap_err();
+        assert!(matches!(
+            e,
+            ConfigError::Invalid {
+                setting: "storage.backend",
+                ..
+            }
+        ));
+        let args = Args {
+            demo_playlist: Some(String::from("not a playlist")),
+            ..required_args()
+        };
+        let e = Config::merge(args, FileConfig::default()).unwrap_err();
+        assert!(matches!(
+            e,
+            ConfigError::Invalid {
+                setting: "demo.playlist",
+                ..
+            }
+        ));
+        let args = Args {
+            library_dir: Some(PathBuf::from("/nonexistent/songsort/library")),
+            ..required_args()
+        };
+        let e = Config::merge(args, FileConfig::default()).unwrap_err();
+        assert!(matches!(
+            e,
+            ConfigError::Invalid {
+                setting: "library.dir",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn zero_disables_demo_resets() {
+        let args = Args {
+            demo_reset_interval: Some(0),
+            ..required_args()
+        };
+        let config = Config::merge(args, FileConfig::default()).unwrap();
+        assert_eq!(config.demo.reset_interval, None);
+    }
+
+    #[test]
+    fn switches() {
+        for value in ["1", "true", "TRUE", "yes", "on"] {
+            assert_eq!(parse_bool(value), Ok(true));
+        }
+        for value in ["0", "false", "no", "off"] {
+            assert_eq!(parse_bool(value), Ok(false));
+        }
+        assert!(parse_bool("2").is_err());
+        let args = Args::try_parse_from(["songsort-web", "--demo-sandbox", "1"]).unwrap();
+        assert_eq!(args.demo_sandbox, Some(true));
+    }
+
+    #[test]
+    fn example_file() {
+        let file: FileConfig = toml::from_str(include_str!("../songsort.example.toml")).unwrap();
+        assert_eq!(file.storage.backend.as_deref(), Some("cosmos"));
+        assert!(toml::from_str::<FileConfig>("[demo]\nsandbox = true\nunknown = 1").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{
+    bad_request, delete_document, get_response_builder, not_found, query_documents, save_playlist,
+    scope_condition, search, Error,
+};
+use azure_core::Context;
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient, ReplaceDocumentOptions};
+use futures::{StreamExt, TryStreamExt};
+use hyper::{Body, Response};
+use songsort::dedupe::{self, Duplicates, MergeRequest};
+use songsort::history::Match;
+use songsort::{Playlist, RatingScope, Score};
+use std::sync::{Arc, RwLock};
+
+/// Suggest scores to merge.
+pub async fn get_duplicates(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+) -> Result<Response<Body>, Error> {
+    let scores: Vec<Score> = query_documents(
+        db.into_collection_client("scores"),
+        &session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {}",
+            search::literal(&user_id)
+        ),
+    )
+    .await?;
+    let duplicates = Duplicates {
+        groups: dedupe::find_duplicates(&scores),
+    };
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&duplicates)?))
+        .map_err(Error::from)
+}
+
+/// Merge duplicate scores into one. Playlists and matches that use the merged tracks switch to the
+/// kept track, the kept score takes their records and then the merged scores are deleted. Every
+/// step can be repeated, so a merge that failed part way is finished by sending it again.
+pub async fn merge_scores(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    request: MergeRequest,
+) -> Result<Response<Body>, Error> {
+    if request.merge.is_empty() || request.merge.contains(&request.keep) {
+        return bad_request();
+    }
+    let client = db.clone().into_collection_client("scores");
+    let mut scores: Vec<Score> = query_documents(
+        client.clone(),
+        &session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {} AND c.id IN ({})",
+            search::literal(&user_id),
+            list(std::iter::once(&request.keep).chain(&request.merge))
+        ),
+    )
+    .await?;
+    let Some(keep) = scores.iter().position(|s| s.id == request.keep) else {
+        return not_found();
+    };
+    let keep = scores.swap_remove(keep);
+    // Scores that are gone were deleted by an earlier attempt at this merge
+    if request
+        .merge
+        .iter()
+        .any(|id| !keep.merged.contains(id) && !scores.iter().any(|s| s.id == *id))
+    {
+        return not_found();
+    }
+    // Shared scores can't absorb a playlist's own scores and the other way around
+    if scores.iter().any(|s| s.playlist_id != keep.playlist_id) {
+        return bad_request();
+    }
+    let merged_tracks: Vec<_> = scores.iter().map(|s| s.track_id.clone()).collect();
+    let merged = dedupe::merge_scores(keep, &scores);
+
+    if !merged_tracks.is_empty() {
+        replace_playlist_tracks(db.clone(), &session, &user_id, &merged_tracks, &merged).await?;
+        replace_match_tracks(db.clone(), &session, &user_id, &merged_tracks, &merged).await?;
+    }
+
+    let session_copy = session
+        .read()
+        .unwrap()
+        .clone()
+        .expect("session should be set by query_documents");
+    client
+        .clone()
+        .into_document_client(merged.id.clone(), &merged.user_id)?
+        .replace_document(
+            Context::new(),
+            &merged,
+            ReplaceDocumentOptions::new().consistency_level(session_copy),
+        )
+        .await?;
+
+    let client = &client;
+    let session = &session;
+    let user_id = &user_id;
+    futures::stream::iter(scores.into_iter().map(async move |score| {
+        delete_document(client.clone(), session, &score.id, user_id).await
+    }))
+    .buffered(5)
+    .try_collect::<()>()
+    .await?;
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&merged)?))
+        .map_err(Error::from)
+}
+
+/// Switch the playlists in the kept score's rating scope to the kept track.
+async fn replace_playlist_tracks(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+    merged_tracks: &[String],
+    keep: &Score,
+) -> Result<(), Error> {
+    let playlists: Vec<Playlist> = query_documents(
+        db.clone().into_collection_client("playlists"),
+        session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {}",
+            search::literal(user_id)
+        ),
+    )
+    .await?;
+    for mut playlist in playlists {
+        let in_scope = match &keep.playlist_id {
+            Some(id) => playlist.id == *id,
+            None => playlist.rating_scope == RatingScope::Global,
+        };
+        if in_scope && playlist.replace_tracks(merged_tracks, &keep.track_id) {
+            save_playlist(db.clone(), Arc::clone(session), playlist, Vec::new(), false).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Switch the matches in the kept score's rating scope to the kept track, so its rating history
+/// includes the merged tracks' matches.
+async fn replace_match_tracks(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+    merged_tracks: &[String],
+    keep: &Score,
+) -> Result<(), Error> {
+    let client = db.into_collection_client("matches");
+    let tracks = list(merged_tracks);
+    let matches: Vec<Match> = query_documents(
+        client.clone(),
+        session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {} AND {} AND \
+             (c.winner.track_id IN ({}) OR c.loser.track_id IN ({}))",
+            search::literal(user_id),
+            scope_condition(keep.playlist_id.as_deref()),
+            tracks,
+            tracks
+        ),
+    )
+    .await?;
+    let session_copy = session
+        .read()
+        .unwrap()
+        .clone()
+        .expect("session should be set by query_documents");
+    let client = &client;
+    let session_copy = &session_copy;
+    futures::stream::iter(matches.into_iter().filter_map(|mut game| {
+        game.replace_track(merged_tracks, keep).then(|| async move {
+            client
+                .clone()
+                .into_document_client(game.id.clone(), &game.user_id)?
+                .replace_document(
+                    Context::new(),
+                    &game,
+                    ReplaceDocumentOptions::new().consistency_level(session_copy.clone()),
+                )
+                .await?;
+            Ok::<_, Error>(())
+        })
+    }))
+    .buffered(5)
+    .try_collect::<()>()
+    .await
+}
+
+fn list<'a>(ids: impl IntoIterator<Item = &'a String>) -> String {
+    ids.into_iter()
+        .map(|id| search::literal(id))
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,340 @@
+use crate::provider::Providers;
+use crate::{
+    create_playlist, delete_document, delete_playlist, get_playlist, import_playlist,
+    query_documents, Error, DEMO_USER,
+};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use serde::Serialize;
+use songsort::history::Match;
+use songsort::{Playlist, Score};
+use songsort_web::auth::Role;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Sandboxes expire a day after they are created.
+///
+/// Per-document TTLs are only honoured if TTL is enabled on the playlists, scores and matches
+/// containers.
+const SANDBOX_TTL: i32 = 60 * 60 * 24;
+
+/// How long a sandbox is trusted to still have its data before storage is checked again, so that
+/// it's copied again once its documents expire.
+const SANDBOX_RECHECK: u64 = 60 * 10;
+
+/// Copying the demo data is the most expensive thing an anonymous visitor can cause, so only this
+/// many sandboxes are created per hour.
+const SANDBOX_COPIES_PER_HOUR: usize = 100;
+
+pub struct Demo {
+    /// Whether demo visitors get a writable copy of the demo data.
+    pub sandbox: bool,
+    /// The Spotify playlist that the demo data is restored from.
+    pub playlist_id: String,
+    /// How often the demo data is restored, if at all.
+    pub reset_interval: Option<Duration>,
+    sandboxes: Mutex<Sandboxes>,
+    status: RwLock<ResetStatus>,
+}
+
+/// The outcome of the most recent demo reset, reported by the admin endpoint.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ResetStatus {
+    pub playlist_id: String,
+    pub interval_secs: Option<u64>,
+    pub runs: u64,
+    /// Unix timestamps in seconds
+    pub last_started: Option<u64>,
+    pub last_finished: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Demo {
+    pub fn new(sandbox: bool, playlist_id: String, reset_interval: Option<Duration>) -> Demo {
+        Demo {
+            sandbox,
+            sandboxes: Mutex::new(Sandboxes::default()),
+            status: RwLock::new(ResetStatus {
+                playlist_id: playlist_id.clone(),
+                interval_secs: reset_interval.map(|d| d.as_secs()),
+                ..ResetStatus::default()
+            }),
+            playlist_id,
+            reset_interval,
+        }
+    }
+
+    pub fn status(&self) -> ResetStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Restore the demo data every `reset_interval`, starting immediately.
+    pub async fn run_resets(
+        self: Arc<Self>,
+        db: DatabaseClient,
+        session: Arc<RwLock<Option<ConsistencyLevel>>>,
+        providers: Arc<Providers>,
+    ) {
+        let Some(period) = self.reset_interval else {
+            return;
+        };
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.reset(db.clone(), &session, &providers).await {
+                eprintln!("demo reset error: {:?}", e);
+            }
+        }
+    }
+
+    /// Re-import the demo playlist with fresh scores and remove everything else the demo user
+    /// has accumulated.
+    pub async fn reset(
+        &self,
+        db: DatabaseClient,
+        session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+        providers: &Providers,
+    ) -> Result<(), Error> {
+        {
+            let mut status = self.status.write().unwrap();
+            status.runs += 1;
+            status.last_started = Some(now());
+        }
+        let result = self.restore(db, session, providers).await;
+        let mut status = self.status.write().unwrap();
+        status.last_finished = Some(now());
+        status.last_error = result.as_ref().err().map(|e| format!("{:?}", e));
+        result
+    }
+
+    async fn restore(
+        &self,
+        db: DatabaseClient,
+        session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+        providers: &Providers,
+    ) -> Result<(), Error> {
+        // Scores are upserted for the demo user, which resets their ratings and records
+        import_playlist(
+            db.clone(),
+            Arc::clone(session),
+            providers,
+            String::from(DEMO_USER),
+            None,
+            &self.playlist_id,
+        )
+        .await?;
+        let playlists: Vec<Playlist> = query_documents(
+            db.clone().into_collection_client("playlists"),
+            session,
+            &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", DEMO_USER),
+        )
+        .await?;
+        let mut tracks = HashSet::new();
+        for playlist in playlists {
+            if playlist.id == self.playlist_id {
+                tracks.extend(playlist.tracks);
+            } else {
+                delete_playlist(
+                    db.clone(),
+                    Arc::clone(session),
+                    String::from(DEMO_USER),
+                    &playlist.id,
+                )
+                .await?;
+            }
+        }
+        let scores: Vec<Score> = query_documents(
+            db.clone().into_collection_client("scores"),
+            session,
+            &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", DEMO_USER),
+        )
+        .await?;
+        for score in scores {
+            if !tracks.contains(&score.track_id) {
+                delete_document(
+                    db.clone().into_collection_client("scores"),
+                    session,
+                    &score.id,
+                    DEMO_USER,
+                )
+                .await?;
+            }
+        }
+        // The ratings start over, so their history does too
+        let matches: Vec<Match> = query_documents(
+            db.clone().into_collection_client("matches"),
+            session,
+            &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", DEMO_USER),
+        )
+        .await?;
+        for played in matches {
+            delete_document(
+                db.clone().into_collection_client("matches"),
+                session,
+                &played.id,
+                DEMO_USER,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Copy the demo playlists and scores to a sandbox the first time it is used, and again
+    /// after its copy expires.
+    pub async fn ensure_sandbox(
+        &self,
+        db: DatabaseClient,
+        session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+        user_id: &str,
+    ) -> Result<(), Error> {
+        if self.sandboxes.lock().unwrap().is_checked(user_id, now()) {
+            return Ok(());
+        }
+        // The sandbox's own playlists can outlive the copy, so only the copied one counts
+        if get_playlist(db.clone(), user_id, &self.playlist_id)
+            .await?
+            .is_none()
+        {
+            if !self.sandboxes.lock().unwrap().start_copy(now()) {
+                return Err(Error::TooManySandboxes);
+            }
+            copy_demo(db, session, user_id).await?;
+        }
+        self.sandboxes.lock().unwrap().check(user_id, now());
+        Ok(())
+    }
+}
+
+/// How long the documents that a role writes last. Everything a sandbox adds expires like its
+/// copy of the demo data.
+pub fn ttl(role: Role) -> Option<i32> {
+    match role {
+        Role::Sandbox => Some(SANDBOX_TTL),
+        Role::Demo | Role::User | Role::Admin => None,
+    }
+}
+
+/// The sandboxes that were recently seen with their data, and when recent copies were made.
+#[derive(Default)]
+struct Sandboxes {
+    /// When each sandbox should be looked for in storage again
+    checked: HashMap<String, u64>,
+    /// Unix timestamps in seconds, oldest first
+    copies: VecDeque<u64>,
+}
+
+impl Sandboxes {
+    fn is_checked(&self, user_id: &str, now: u64) -> bool {
+        self.checked
+            .get(user_id)
+            .map_or(false, |&recheck| recheck > now)
+    }
+
+    /// Remember that a sandbox has its data. Sandboxes that are due to be checked again are
+    /// forgotten, so only recently active ones are kept.
+    fn check(&mut self, user_id: &str, now: u64) {
+        self.checked.retain(|_, &mut recheck| recheck > now);
+        self.checked
+            .insert(user_id.to_owned(), now + SANDBOX_RECHECK);
+    }
+
+    /// Count a copy of the demo data, unless too many were made in the last hour.
+    fn start_copy(&mut self, now: u64) -> bool {
+        while self.copies.front().map_or(false, |&t| t + 60 * 60 <= now) {
+            self.copies.pop_front();
+        }
+        if self.copies.len() >= SANDBOX_COPIES_PER_HOUR {
+            return false;
+        }
+        self.copies.push_back(now);
+        true
+    }
+}
+
+async fn copy_demo(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+) -> Result<(), Error> {
+    let playlists: Vec<Playlist> = query_documents(
+        db.clone().into_collection_client("playlists"),
+        session,
+        &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", DEMO_USER),
+    )
+    .await?;
+    let scores: Vec<Score> = query_documents(
+        db.clone().into_collection_client("scores"),
+        session,
+        &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", DEMO_USER),
+    )
+    .await?;
+    for playlist in playlists {
+        let score_ids = playlist.score_ids();
+        let scores = scores
+            .iter()
+            .filter(|s| score_ids.contains(&s.id))
+            .map(|s| Score {
+                user_id: user_id.to_owned(),
+                ttl: Some(SANDBOX_TTL),
+                ..s.clone()
+            })
+            .collect();
+        let playlist = Playlist {
+            user_id: user_id.to_owned(),
+            ttl: Some(SANDBOX_TTL),
+            ..playlist
+        };
+        create_playlist(db.clone(), Arc::clone(session), playlist, scores, true).await?;
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sandboxes_are_checked_again() {
+        let mut sandboxes = Sandboxes::default();
+        assert!(!sandboxes.is_checked("demo:a", 0));
+        sandboxes.check("demo:a", 0);
+        assert!(sandboxes.is_checked("demo:a", SANDBOX_RECHECK - 1));
+        assert!(!sandboxes.is_checked("demo:a", SANDBOX_RECHECK));
+        assert!(!sandboxes.is_checked("demo:b", 0));
+    }
+
+    #[test]
+    fn stale_sandboxes_are_forgotten() {
+        let mut sandboxes = Sandboxes::default();
+        sandboxes.check("demo:a", 0);
+        sandboxes.check("demo:b", SANDBOX_RECHECK);
+        assert_eq!(sandboxes.checked.len(), 1);
+        assert!(sandboxes.is_checked("demo:b", SANDBOX_RECHECK));
+    }
+
+    #[test]
+    fn only_sandbox_writes_expire() {
+        assert_eq!(ttl(Role::Sandbox), Some(SANDBOX_TTL));
+        assert_eq!(ttl(Role::User), None);
+        assert_eq!(ttl(Role::Admin), None);
+    }
+
+    #[test]
+    fn copies_are_limited_per_hour() {
+        let mut sandboxes = Sandboxes::default();
+        for i in 0..SANDBOX_COPIES_PER_HOUR {
+            assert!(sandboxes.start_copy(i as u64));
+        }
+        assert!(!sandboxes.start_copy(60 * 60 - 1));
+        // The first copy is an hour old
+        assert!(sandboxes.start_copy(60 * 60));
+        assert!(!sandboxes.start_copy(60 * 60));
+    }
+}
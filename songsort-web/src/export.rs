@@ -0,0 +1,569 @@
+use crate::upload::local_track_id;
+use crate::{
+    bad_request, get_playlist, get_response_builder, get_score_docs, not_found, save_playlist,
+    Error,
+};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use songsort::{Playlist, RatingScope, Score};
+use songsort_web::router;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+const CSV_HEADERS: [&str; 8] = [
+    "rank", "track_id", "track", "album", "artists", "score", "wins", "losses",
+];
+
+/// Artists are joined into a single CSV column with this separator.
+const ARTIST_SEPARATOR: &str = "; ";
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Format,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    pub format: Format,
+    /// Renames the playlist. New playlists are named after their id by default.
+    pub name: Option<String>,
+    /// Whether a new playlist shares its ratings with other playlists.
+    #[serde(default)]
+    pub scope: RatingScope,
+}
+
+/// A score as it appears in an export. Only `track` is required when importing, and the
+/// existing score fills in whatever else is missing.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ExportRow {
+    pub rank: usize,
+    pub track_id: String,
+    pub track: String,
+    pub album: String,
+    pub artists: Vec<String>,
+    pub score: Option<i32>,
+    pub wins: Option<i32>,
+    pub losses: Option<i32>,
+}
+
+/// Why a row of an import was rejected. Rows are numbered from 1, and CSV rows by their line.
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportErrors {
+    errors: Vec<RowError>,
+}
+
+/// Dump the scores of a playlist, highest first.
+pub async fn export_playlist(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    id: &str,
+    query: ExportQuery,
+) -> Result<Response<Body>, Error> {
+    let Some(playlist) = get_playlist(db.clone(), &user_id, id).await? else {
+        return not_found();
+    };
+    let mut scores = if playlist.tracks.is_empty() {
+        Vec::new()
+    } else {
+        let track_ids: Vec<_> = playlist.tracks.iter().map(String::as_str).collect();
+        get_score_docs(
+            db.into_collection_client("scores"),
+            &session,
+            user_id,
+            Some(&playlist),
+            &track_ids,
+        )
+        .await?
+    };
+    scores.sort_by_key(|s| -s.score);
+    let rows = (1..).zip(scores).map(|(rank, score)| ExportRow {
+        rank,
+        track_id: score.track_id,
+        track: score.track,
+        album: score.album,
+        artists: score.artists,
+        score: Some(score.score),
+        wins: Some(score.wins),
+        losses: Some(score.losses),
+    });
+    let (content_type, extension, body) = match query.format {
+        Format::Json => (
+            "application/json",
+            "json",
+            serde_json::to_vec(&rows.collect::<Vec<_>>())?,
+        ),
+        Format::Csv => ("text/csv; charset=utf-8", "csv", write_csv(rows)?),
+    };
+    get_response_builder()
+        .header("Content-Type", content_type)
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.{}\"", file_name(id), extension),
+        )
+        .body(Body::from(body))
+        .map_err(Error::from)
+}
+
+/// Create or update a playlist from an export. Nothing is saved unless every row is valid.
+///
+/// The file replaces the playlist's tracks, and tracks that are missing from it are archived.
+pub async fn import_playlist_file(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    id: &str,
+    ttl: Option<i32>,
+    query: ImportQuery,
+    body: Body,
+) -> Result<Response<Body>, Error> {
+    let body = router::bytes(body).await?;
+    let rows = match query.format {
+        Format::Csv => read_csv(&body),
+        Format::Json => read_json(&body),
+    };
+    let rows = match rows.and_then(validate) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            return get_response_builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&ImportErrors { errors })?))
+                .map_err(Error::from)
+        }
+    };
+    let existing = get_playlist(db.clone(), &user_id, id).await?;
+    if existing.is_none() && !is_track_id(id) {
+        return bad_request();
+    }
+    let track_ids: Vec<_> = rows.iter().map(|row| row.track_id.as_str()).collect();
+    // Keep what the file doesn't carry, like artwork, from the existing scores
+    let mut existing_scores: HashMap<_, _> = get_score_docs(
+        db.clone().into_collection_client("scores"),
+        &session,
+        user_id.clone(),
+        existing.as_ref(),
+        &track_ids,
+    )
+    .await?
+    .into_iter()
+    .map(|score| (score.track_id.clone(), score))
+    .collect();
+    let tracks: Vec<_> = rows.iter().map(|row| row.track_id.clone()).collect();
+    let scores = rows
+        .into_iter()
+        .map(|row| {
+            let existing = existing_scores.remove(&row.track_id);
+            imported_score(row, existing, &user_id, ttl)
+        })
+        .collect();
+    let playlist = match existing {
+        Some(playlist) => {
+            let current: HashSet<_> = tracks.iter().collect();
+            let mut archived: Vec<_> = playlist
+                .archived
+                .iter()
+                .filter(|t| !current.contains(t))
+                .cloned()
+                .collect();
+            archived.extend(
+                playlist
+                    .tracks
+                    .iter()
+                    .filter(|t| !current.contains(t) && !archived.contains(t))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            );
+            Playlist {
+                name: query.name.unwrap_or(playlist.name),
+                tracks,
+                archived,
+                ..playlist
+            }
+        }
+        None => Playlist {
+            id: id.to_owned(),
+            playlist_id: id.to_owned(),
+            name: query.name.unwrap_or_else(|| id.to_owned()),
+            user_id,
+            tracks,
+            archived: Vec::new(),
+            published_id: None,
+            rating_scope: query.scope,
+            ttl,
+        },
+    };
+    save_playlist(db, session, playlist, scores, true).await?;
+    get_response_builder()
+        .status(StatusCode::CREATED)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+/// The score that a row of an import becomes. Columns that the file leaves out keep the existing
+/// score's values, so a file with only ratings doesn't erase the win and loss records.
+fn imported_score(
+    row: ExportRow,
+    existing: Option<Score>,
+    user_id: &str,
+    ttl: Option<i32>,
+) -> Score {
+    let wins = row
+        .wins
+        .or_else(|| existing.as_ref().map(|s| s.wins))
+        .unwrap_or_default();
+    let losses = row
+        .losses
+        .or_else(|| existing.as_ref().map(|s| s.losses))
+        .unwrap_or_default();
+    Score {
+        id: row.track_id.clone(),
+        track_id: row.track_id,
+        track: row.track,
+        album: row.album,
+        artists: row.artists,
+        user_id: user_id.to_owned(),
+        score: row
+            .score
+            .or_else(|| existing.as_ref().map(|s| s.score))
+            .unwrap_or(songsort::INITIAL_SCORE),
+        wins,
+        losses,
+        games: wins + losses,
+        release_year: existing.as_ref().and_then(|s| s.release_year),
+        duration_ms: existing.as_ref().and_then(|s| s.duration_ms),
+        isrc: existing.as_ref().and_then(|s| s.isrc.clone()),
+        merged: existing
+            .as_ref()
+            .map(|s| s.merged.clone())
+            .unwrap_or_default(),
+        // The file's ratings replace the ones that the latest match left
+        last_match: None,
+        artwork_url: existing.and_then(|s| s.artwork_url),
+        playlist_id: None,
+        ttl,
+    }
+}
+
+fn write_csv(rows: impl Iterator<Item = ExportRow>) -> Result<Vec<u8>, Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(CSV_HEADERS)?;
+    for row in rows {
+        writer.write_record([
+            row.rank.to_string(),
+            row.track_id,
+            row.track,
+            row.album,
+            row.artists.join(ARTIST_SEPARATOR),
+            row.score.unwrap_or_default().to_string(),
+            row.wins.map(|w| w.to_string()).unwrap_or_default(),
+            row.losses.map(|l| l.to_string()).unwrap_or_default(),
+        ])?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| Error::from(csv::Error::from(e.into_error())))
+}
+
+/// Columns are matched by header name, so they can be in any order and unknown ones are ignored.
+fn read_csv(body: &[u8]) -> Result<Vec<(usize, ExportRow)>, Vec<RowError>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(body);
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            return Err(vec![RowError {
+                row: 1,
+                message: e.to_string(),
+            }])
+        }
+    };
+    let column = |name: &str| headers.iter().position(|h| h.trim() == name);
+    let Some(track) = column("track") else {
+        return Err(vec![RowError {
+            row: 1,
+            message: String::from("missing track column"),
+        }]);
+    };
+    let (track_id, album, artists) = (column("track_id"), column("album"), column("artists"));
+    let (score, wins, losses) = (column("score"), column("wins"), column("losses"));
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        // The header is the first row
+        let mut row = i + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                errors.push(RowError {
+                    row,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        if let Some(position) = record.position() {
+            row = position.line() as usize;
+        }
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|i| record.get(i))
+                .map(str::trim)
+                .unwrap_or_default()
+        };
+        let number = |name: &str, column: Option<usize>| -> Result<Option<i32>, RowError> {
+            match field(column) {
+                "" => Ok(None),
+                value => value.parse().map(Some).map_err(|_| RowError {
+                    row,
+                    message: format!("{} {:?} is not a number", name, value),
+                }),
+            }
+        };
+        let parsed = number("score", score).and_then(|score| {
+            Ok(ExportRow {
+                rank: 0,
+                track_id: field(track_id).to_owned(),
+                track: field(Some(track)).to_owned(),
+                album: field(album).to_owned(),
+                artists: field(artists)
+                    .split(ARTIST_SEPARATOR.trim())
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                score,
+                wins: number("wins", wins)?,
+                losses: number("losses", losses)?,
+            })
+        });
+        match parsed {
+            Ok(parsed) => rows.push((row, parsed)),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+fn read_json(body: &[u8]) -> Result<Vec<(usize, ExportRow)>, Vec<RowError>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(body).map_err(|e| {
+        vec![RowError {
+            row: 0,
+            message: format!("expected an array of scores: {}", e),
+        }]
+    })?;
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (row, value) in (1..).zip(values) {
+        match serde_json::from_value(value) {
+            Ok(parsed) => rows.push((row, parsed)),
+            Err(e) => errors.push(RowError {
+                row,
+                message: e.to_string(),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check the rows that parsed, filling in ids for tracks that don't have one.
+fn validate(rows: Vec<(usize, ExportRow)>) -> Result<Vec<ExportRow>, Vec<RowError>> {
+    let mut seen = HashSet::new();
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for (row, mut parsed) in rows {
+        let error = |message: &str| RowError {
+            row,
+            message: message.to_owned(),
+        };
+        if parsed.track.trim().is_empty() {
+            errors.push(error("track is empty"));
+            continue;
+        }
+        if parsed.wins.unwrap_or_default() < 0 || parsed.losses.unwrap_or_default() < 0 {
+            errors.push(error("wins and losses can't be negative"));
+            continue;
+        }
+        if parsed.track_id.is_empty() {
+            let album = Some(parsed.album.as_str()).filter(|a| !a.is_empty());
+            parsed.track_id = local_track_id(&parsed.track, &parsed.artists, album).to_string();
+        } else if !is_track_id(&parsed.track_id) {
+            errors.push(error("track_id contains invalid characters"));
+            continue;
+        }
+        if !seen.insert(parsed.track_id.clone()) {
+            errors.push(error("track is listed more than once"));
+            continue;
+        }
+        valid.push(parsed);
+    }
+    if valid.is_empty() && errors.is_empty() {
+        errors.push(RowError {
+            row: 0,
+            message: String::from("no scores to import"),
+        });
+    }
+    if errors.is_empty() {
+        Ok(valid)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Track and playlist ids become document ids and end up in queries, so only allow what Spotify
+/// and local ids use.
+fn is_track_id(id: &str) -> bool {
+    id.len() <= 255
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ':' || c == '-' || c == '_')
+}
+
+fn file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<ExportRow> {
+        vec![
+            ExportRow {
+                rank: 1,
+                track_id: String::from("4uLU6hMCjMI75M1A2tKUQC"),
+                track: String::from("Never Gonna Give You Up"),
+                album: String::from("Whenever You Need Somebody"),
+                artists: vec![String::from("Rick Astley")],
+                score: Some(1612),
+                wins: Some(7),
+                losses: Some(1),
+            },
+            ExportRow {
+                rank: 2,
+                track_id: String::from("local:0123456789abcdef"),
+                track: String::from("Quotes \"and\", commas"),
+                album: String::new(),
+                artists: vec![String::from("A"), String::from("B")],
+                score: Some(1388),
+                wins: Some(0),
+                losses: Some(3),
+            },
+        ]
+    }
+
+    /// The rank of a CSV row isn't read, because it comes from the scores.
+    fn unranked(rows: Vec<ExportRow>) -> Vec<ExportRow> {
+        rows.into_iter()
+            .map(|row| ExportRow { rank: 0, ..row })
+            .collect()
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let csv = write_csv(rows().into_iter()).unwrap();
+        let read = read_csv(&csv).and_then(validate).unwrap();
+        assert_eq!(read, unranked(rows()));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = serde_json::to_vec(&rows()).unwrap();
+        let read = read_json(&json).and_then(validate).unwrap();
+        assert_eq!(read, rows());
+    }
+
+    #[test]
+    fn csv_columns_in_any_order() {
+        let csv = b"score,extra,track,artists\n1500,x,Song,A; B\n,,Other,\n";
+        let read = read_csv(csv).and_then(validate).unwrap();
+        assert_eq!(read[0].score, Some(1500));
+        assert_eq!(read[0].artists, ["A", "B"]);
+        assert_eq!(read[1].score, None);
+        // Tracks without an id are identified by their metadata
+        assert_eq!(
+            read[1].track_id,
+            local_track_id("Other", &[], None).to_string()
+        );
+    }
+
+    #[test]
+    fn missing_records_keep_the_existing_ones() {
+        let csv = b"track,score\nSong,1600\n";
+        let row = read_csv(csv).and_then(validate).unwrap().remove(0);
+        assert_eq!((row.wins, row.losses), (None, None));
+        let existing = imported_score(
+            ExportRow {
+                score: Some(1500),
+                wins: Some(4),
+                losses: Some(2),
+                ..row
+            },
+            None,
+            "user",
+            None,
+        );
+        let row = read_csv(csv).and_then(validate).unwrap().remove(0);
+        let imported = imported_score(row, Some(existing), "user", None);
+        assert_eq!(imported.score, 1600);
+        assert_eq!((imported.wins, imported.losses, imported.games), (4, 2, 6));
+        let row = read_csv(b"track,wins\nNew,3\n")
+            .and_then(validate)
+            .unwrap()
+            .remove(0);
+        let imported = imported_score(row, None, "user", None);
+        assert_eq!(
+            (imported.score, imported.wins, imported.losses),
+            (songsort::INITIAL_SCORE, 3, 0)
+        );
+    }
+
+    #[test]
+    fn csv_errors_name_their_line() {
+        let csv = b"track,score,wins\nA,high,0\nB,1500,-1\nA,1500,0\n";
+        let errors = read_csv(csv).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].row, 2);
+        let csv = b"track,score,wins\nA,1500,-1\nB,1500,0\nB,1500,0\n";
+        let errors = read_csv(csv).and_then(validate).unwrap_err();
+        let rows: Vec<_> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, [2, 4]);
+        assert!(read_csv(b"name,score\nA,1\n").is_err());
+    }
+
+    #[test]
+    fn json_errors() {
+        let errors = read_json(br#"[{"track": "A"}, {"score": "high"}]"#).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].row, 2);
+        assert!(read_json(b"{}").is_err());
+        let errors = read_json(b"[]").and_then(validate).unwrap_err();
+        assert_eq!(errors[0].message, "no scores to import");
+        let json = br#"[{"track": "A", "track_id": "a\" OR 1=1"}]"#;
+        assert!(read_json(json).and_then(validate).is_err());
+    }
+}
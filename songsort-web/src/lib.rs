@@ -1,5 +1,13 @@
+//! Request routing and access control for the server, and the response shapes of the Spotify Web
+//! API used by the Spotify provider.
+#![feature(let_else)]
+
 use serde::{Deserialize, Serialize};
 
+pub mod auth;
+pub mod matches;
+pub mod router;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Playlists {
     pub items: Vec<Playlist>,
@@ -25,6 +33,26 @@ pub struct Item {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AlbumItems {
     pub items: Vec<AlbumTrack>,
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TopTracks {
+    pub items: Vec<Track>,
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArtistAlbums {
+    pub items: Vec<ArtistAlbum>,
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArtistAlbum {
+    pub id: String,
+    #[serde(flatten)]
+    pub album: Album,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,20 +61,52 @@ pub struct Track {
     pub name: String,
     pub album: Album,
     pub artists: Vec<Artist>,
+    pub duration_ms: Option<u32>,
+    #[serde(default)]
+    pub external_ids: ExternalIds,
     pub preview_url: Option<String>,
 }
 
+/// Only full track objects have external ids, so album tracks don't have an ISRC.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExternalIds {
+    pub isrc: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AlbumTrack {
     pub id: String,
     pub name: String,
     pub artists: Vec<Artist>,
+    pub duration_ms: Option<u32>,
     pub preview_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Album {
     pub name: String,
+    pub release_date: Option<String>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+}
+
+impl Album {
+    /// Release dates are "YYYY", "YYYY-MM" or "YYYY-MM-DD" depending on their precision
+    pub fn release_year(&self) -> Option<i32> {
+        self.release_date.as_ref()?.get(..4)?.parse().ok()
+    }
+
+    /// Spotify lists the widest image first
+    pub fn artwork_url(&self) -> Option<String> {
+        self.images.first().map(|i| i.url.clone())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Image {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -0,0 +1,525 @@
+use crate::config::LibraryConfig;
+use crate::provider::{Collection, MusicProvider, Track};
+use crate::upload::hash;
+use crate::{get_response_builder, not_found, Error};
+use async_trait::async_trait;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, StatusCode};
+use lofty::{Accessor, AudioFile, ItemKey};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use songsort::{Clip, LibraryEntry, ProviderId, LIBRARY};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use walkdir::WalkDir;
+
+/// Supported audio files and the content type they are served with.
+const FORMATS: &[(&str, &str)] = &[
+    ("flac", "audio/flac"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("m4a", "audio/mp4"),
+];
+
+/// How long a clip link works for. Audio elements can't send the Authorization header, so clips
+/// are served to anyone holding the link.
+const CLIP_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const CLIP_LENGTH_MS: u32 = 30_000;
+/// Bitrates vary, so a little more than the clip is served to be sure that all of it plays.
+const CLIP_MARGIN_MS: u32 = 5_000;
+
+/// Range requests are answered a piece at a time instead of with the whole file.
+const MAX_RANGE: u64 = 1024 * 1024;
+/// Files are streamed in pieces of this size.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// The server's own music collection. Folders are imported as playlists and tagged albums as
+/// albums.
+pub struct Library {
+    dir: PathBuf,
+    index: RwLock<Arc<Index>>,
+    clips: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[derive(Default)]
+struct Index {
+    tracks: HashMap<String, LibraryTrack>,
+    albums: BTreeMap<String, Group>,
+    folders: BTreeMap<String, Group>,
+}
+
+struct LibraryTrack {
+    path: PathBuf,
+    track: Track,
+}
+
+struct Group {
+    name: String,
+    artist: Option<String>,
+    /// Sorted by disc and track number, then by path. Folders only sort by path.
+    tracks: Vec<(Option<u32>, Option<u32>, PathBuf, String)>,
+}
+
+impl Group {
+    fn entry(&self, id: &str) -> LibraryEntry {
+        LibraryEntry {
+            id: ProviderId::new(LIBRARY, id).to_string(),
+            name: self.name.clone(),
+            artist: self.artist.clone(),
+            tracks: self.tracks.len(),
+        }
+    }
+}
+
+impl Library {
+    pub fn new(config: &LibraryConfig) -> Option<Library> {
+        Some(Library {
+            dir: config.dir.clone()?,
+            index: RwLock::new(Arc::new(Index::default())),
+            clips: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Read the tags of every file in the library again. The previous index is used until the
+    /// scan finishes.
+    pub async fn scan(&self) -> Result<songsort::Library, Error> {
+        let dir = self.dir.clone();
+        let index = tokio::task::spawn_blocking(move || scan(&dir))
+            .await
+            .map_err(|e| Error::IOError(e.into()))?;
+        let index = Arc::new(index);
+        *self.index.write().unwrap() = Arc::clone(&index);
+        Ok(listing(&index))
+    }
+
+    pub fn listing(&self) -> songsort::Library {
+        listing(&self.index())
+    }
+
+    fn index(&self) -> Arc<Index> {
+        Arc::clone(&self.index.read().unwrap())
+    }
+
+    /// Create a link that plays about 30 seconds from the middle of a track.
+    pub fn clip(&self, id: &str) -> Result<Response<Body>, Error> {
+        let Some(track) = self.index().tracks.get(id).map(|t| t.track.clone()) else {
+            return not_found();
+        };
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        {
+            let mut clips = self.clips.lock().unwrap();
+            clips.retain(|_, (_, created)| created.elapsed() < CLIP_LIFETIME);
+            clips.insert(token.clone(), (id.to_owned(), Instant::now()));
+        }
+        let (start, end) = clip_window(track.duration_ms.unwrap_or(CLIP_LENGTH_MS));
+        let clip = Clip {
+            url: format!(
+                "/api/library/clips/{}#t={},{}",
+                token,
+                start / 1000,
+                end / 1000
+            ),
+        };
+        get_response_builder()
+            .body(Body::from(serde_json::to_string(&clip)?))
+            .map_err(Error::from)
+    }
+
+    /// Serve the audio behind a clip link. Browsers seek with range requests, so a single range is
+    /// supported.
+    ///
+    /// Only the start of the file up to the end of the clip is served, as if that were the whole
+    /// file. Decoders need the headers at the start, and the clip link seeks to the rest.
+    pub async fn serve_clip(
+        &self,
+        token: &str,
+        req: &Request<Body>,
+    ) -> Result<Response<Body>, Error> {
+        let id = match self.clips.lock().unwrap().get(token) {
+            Some((id, created)) if created.elapsed() < CLIP_LIFETIME => id.clone(),
+            _ => return not_found(),
+        };
+        let Some((path, duration_ms)) = self
+            .index()
+            .tracks
+            .get(&id)
+            .map(|t| (t.path.clone(), t.track.duration_ms))
+        else {
+            return not_found();
+        };
+        let Some(content_type) = content_type(&path) else {
+            return not_found();
+        };
+        let mut file = tokio::fs::File::open(&path).await?;
+        let len = clip_len(file.metadata().await?.len(), duration_ms);
+        let range = req
+            .headers()
+            .get("Range")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| parse_range(v, len));
+        let builder = get_response_builder()
+            .header("Content-Type", HeaderValue::from_static(content_type))
+            .header("Accept-Ranges", HeaderValue::from_static("bytes"))
+            .header(
+                "Cache-Control",
+                HeaderValue::from_static("private, max-age=3600"),
+            );
+        let (builder, start, end) = match range {
+            None if len == 0 => {
+                return builder
+                    .status(StatusCode::OK)
+                    .body(Body::empty())
+                    .map_err(Error::from)
+            }
+            None => (builder.status(StatusCode::OK), 0, len - 1),
+            Some(Some((start, end))) => {
+                let builder = builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+                (builder, start, end)
+            }
+            Some(None) => {
+                return get_response_builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header("Content-Range", format!("bytes */{}", len))
+                    .body(Body::empty())
+                    .map_err(Error::from)
+            }
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        builder
+            .header("Content-Length", end - start + 1)
+            .body(Body::wrap_stream(read_chunks(file, end - start + 1)))
+            .map_err(Error::from)
+    }
+}
+
+/// The part of a track that its clip plays, in milliseconds: 30 seconds from a third of the way
+/// in, or the whole track if it's shorter.
+fn clip_window(duration_ms: u32) -> (u32, u32) {
+    let start = (duration_ms / 3).min(duration_ms.saturating_sub(CLIP_LENGTH_MS));
+    let end = start.saturating_add(CLIP_LENGTH_MS).min(duration_ms);
+    (start, end)
+}
+
+/// How many bytes of a file are served for its clip, estimated from the track's duration. Files
+/// without a duration are served whole.
+fn clip_len(file_len: u64, duration_ms: Option<u32>) -> u64 {
+    let Some(duration_ms) = duration_ms.filter(|&d| d > 0) else {
+        return file_len;
+    };
+    let (_, end) = clip_window(duration_ms);
+    let end = u128::from(end.saturating_add(CLIP_MARGIN_MS).min(duration_ms));
+    let len = u128::from(file_len) * end / u128::from(duration_ms);
+    // Never more than the file, which also makes it fit in a u64 again
+    len.min(u128::from(file_len)) as u64
+}
+
+/// Stream `len` bytes from the file's current position.
+fn read_chunks(
+    file: tokio::fs::File,
+    len: u64,
+) -> impl futures::Stream<Item = std::io::Result<Vec<u8>>> {
+    futures::stream::try_unfold((file, len), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut chunk = vec![0; remaining.min(CHUNK_SIZE) as usize];
+        file.read_exact(&mut chunk).await?;
+        let remaining = remaining - chunk.len() as u64;
+        Ok(Some((chunk, (file, remaining))))
+    })
+}
+
+#[async_trait]
+impl MusicProvider for Library {
+    fn name(&self) -> &'static str {
+        LIBRARY
+    }
+
+    /// The files directly inside a folder. Subfolders are playlists of their own.
+    async fn playlist(&self, id: &str) -> Result<Collection, Error> {
+        let index = self.index();
+        collection(&index, &index.folders, id)
+    }
+
+    async fn album(&self, id: &str) -> Result<Collection, Error> {
+        let index = self.index();
+        collection(&index, &index.albums, id)
+    }
+}
+
+fn collection(
+    index: &Index,
+    groups: &BTreeMap<String, Group>,
+    id: &str,
+) -> Result<Collection, Error> {
+    let group = groups.get(id).ok_or(Error::SourceNotFound)?;
+    Ok(Collection {
+        id: ProviderId::new(LIBRARY, id),
+        name: group.name.clone(),
+        tracks: group
+            .tracks
+            .iter()
+            .filter_map(|(_, _, _, id)| index.tracks.get(id))
+            .map(|t| t.track.clone())
+            .collect(),
+    })
+}
+
+fn listing(index: &Index) -> songsort::Library {
+    songsort::Library {
+        albums: index.albums.iter().map(|(id, g)| g.entry(id)).collect(),
+        folders: index.folders.iter().map(|(id, g)| g.entry(id)).collect(),
+    }
+}
+
+fn content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    FORMATS
+        .iter()
+        .find(|(e, _)| *e == extension)
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Parse `bytes=<start>-<end>`, `bytes=<start>-` or `bytes=-<suffix>`. Returns `None` if the
+/// range can't be satisfied. Ranges are cut short at `MAX_RANGE` bytes, which clients handle by
+/// asking for the rest.
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let last = len.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (len.saturating_sub(suffix), last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    if start > end || start >= len {
+        return None;
+    }
+    // Near the end of the largest files, the rest of the file is less than MAX_RANGE anyway
+    let end = start
+        .checked_add(MAX_RANGE - 1)
+        .map_or(end, |limit| end.min(limit));
+    Some((start, end))
+}
+
+fn scan(dir: &Path) -> Index {
+    let mut index = Index::default();
+    for entry in WalkDir::new(dir).follow_links(true) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("library: {}", e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        if !entry.file_type().is_file() || content_type(path).is_none() {
+            continue;
+        }
+        let tagged = match lofty::read_from_path(path, true) {
+            Ok(tagged) => tagged,
+            Err(e) => {
+                eprintln!("library: could not read {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let relative = path.strip_prefix(dir).unwrap_or(path).to_owned();
+        let tag = tagged.primary_tag().or_else(|| tagged.first_tag());
+        let text = |key: &ItemKey| {
+            tag.and_then(|t| t.get_string(key))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_owned)
+        };
+        // Numbers are sometimes written as "3/12"
+        let number = |key: &ItemKey| {
+            text(key).and_then(|v| v.split('/').next().and_then(|n| n.trim().parse().ok()))
+        };
+        let title = tag
+            .and_then(|t| t.title())
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| {
+                relative
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+        let artists: Vec<_> = tag
+            .and_then(|t| t.artist())
+            .map(|a| a.trim().to_owned())
+            .filter(|a| !a.is_empty())
+            .into_iter()
+            .collect();
+        let album = tag
+            .and_then(|t| t.album())
+            .map(|a| a.trim().to_owned())
+            .filter(|a| !a.is_empty());
+        let album_artist = text(&ItemKey::AlbumArtist).or_else(|| artists.first().cloned());
+        let id = hash(&relative.to_string_lossy());
+        let order = (
+            number(&ItemKey::DiscNumber),
+            number(&ItemKey::TrackNumber),
+            relative.clone(),
+            id.clone(),
+        );
+
+        let folder = relative.parent().unwrap_or_else(|| Path::new(""));
+        index
+            .folders
+            .entry(format!("folder-{}", hash(&folder.to_string_lossy())))
+            .or_insert_with(|| Group {
+                name: match folder.to_string_lossy() {
+                    name if name.is_empty() => dir
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| String::from("Library")),
+                    name => name.into_owned(),
+                },
+                artist: None,
+                tracks: Vec::new(),
+            })
+            .tracks
+            .push((None, None, relative.clone(), id.clone()));
+        if let Some(album) = &album {
+            let key = format!(
+                "{}\u{1f}{}",
+                album_artist.as_deref().unwrap_or_default().to_lowercase(),
+                album.to_lowercase()
+            );
+            index
+                .albums
+                .entry(format!("album-{}", hash(&key)))
+                .or_insert_with(|| Group {
+                    name: album.clone(),
+                    artist: album_artist.clone(),
+                    tracks: Vec::new(),
+                })
+                .tracks
+                .push(order);
+        }
+        index.tracks.insert(
+            id.clone(),
+            LibraryTrack {
+                path: path.to_owned(),
+                track: Track {
+                    id: ProviderId::new(LIBRARY, &id),
+                    name: title,
+                    album: album.unwrap_or_default(),
+                    artists,
+                    release_year: number(&ItemKey::Year)
+                        .or_else(|| text(&ItemKey::RecordingDate)?.get(..4)?.parse().ok()),
+                    artwork_url: None,
+                    duration_ms: u32::try_from(tagged.properties().duration().as_millis()).ok(),
+                    isrc: text(&ItemKey::Isrc),
+                },
+            },
+        );
+    }
+    for group in index.albums.values_mut().chain(index.folders.values_mut()) {
+        group.tracks.sort();
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        // Suffixes longer than the file are the whole file
+        assert_eq!(parse_range("bytes=-2000", 1000), Some((0, 999)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        for header in [
+            "bytes=1000-",
+            "bytes=50-10",
+            "bytes=-0",
+            "bytes=a-b",
+            "bytes=5",
+            "items=0-10",
+            "bytes=0-10,20-30",
+        ] {
+            assert_eq!(parse_range(header, 1000), None, "{}", header);
+        }
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range(&format!("bytes={}-", u64::MAX), u64::MAX), None);
+    }
+
+    #[test]
+    fn ranges_are_capped() {
+        let len = 10 * MAX_RANGE;
+        assert_eq!(parse_range("bytes=0-", len), Some((0, MAX_RANGE - 1)));
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", len - 1), len),
+            Some((0, MAX_RANGE - 1))
+        );
+        assert_eq!(
+            parse_range(&format!("bytes=-{}", 2 * MAX_RANGE), len),
+            Some((8 * MAX_RANGE, 9 * MAX_RANGE - 1))
+        );
+        let start = u64::MAX - 10;
+        assert_eq!(
+            parse_range(&format!("bytes={}-", start), u64::MAX),
+            Some((start, u64::MAX - 1))
+        );
+    }
+
+    #[test]
+    fn clips() {
+        assert_eq!(clip_window(180_000), (60_000, 90_000));
+        assert_eq!(clip_window(40_000), (10_000, 40_000));
+        assert_eq!(clip_window(10_000), (0, 10_000));
+        // Up to the end of the clip and its margin
+        assert_eq!(clip_len(1_800_000, Some(180_000)), 950_000);
+        assert_eq!(clip_len(1_000, Some(40_000)), 1_000);
+        assert_eq!(clip_len(1_000, None), 1_000);
+        assert_eq!(clip_len(1_000, Some(0)), 1_000);
+        // Large files and long tracks don't overflow
+        assert_eq!(
+            clip_len(u64::MAX, Some(u32::MAX)),
+            6_149_065_015_091_912_205
+        );
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let path = std::env::temp_dir().join("songsort-library-chunks");
+        let contents: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        file.seek(SeekFrom::Start(10)).await.unwrap();
+        let chunks: Vec<Vec<u8>> = read_chunks(file, 2 * CHUNK_SIZE + 1)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks.concat(),
+            &contents[10..10 + 2 * CHUNK_SIZE as usize + 1]
+        );
+    }
+}
@@ -1,80 +1,104 @@
 #![feature(async_closure, let_else)]
+use assets::Assets;
 use azure_core::Context;
 use azure_data_cosmos::prelude::{
-    AuthorizationToken, CollectionClient, ConsistencyLevel, CosmosClient, CosmosEntity,
-    CosmosOptions, CreateDocumentOptions, DatabaseClient, DeleteDocumentOptions,
-    GetDocumentOptions, GetDocumentResponse, Query, ReplaceDocumentOptions,
+    AuthorizationToken, CollectionClient, ConsistencyLevel, CosmosClient, CosmosOptions,
+    CreateDocumentOptions, DatabaseClient, DeleteDocumentOptions, GetDocumentOptions,
+    GetDocumentResponse, Query,
 };
+use config::{Config, StorageConfig};
+use demo::Demo;
 use futures::{StreamExt, TryStreamExt};
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderValue, SET_COOKIE};
 use hyper::http::response::Builder;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
+use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
 use hyper_tls::HttpsConnector;
+use provider::{Collection, Providers};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use songsort::{Playlist, Playlists, Score, Scores};
+use songsort::api::{
+    Action, ActionQuery, EloQuery, ImportSource, SavedItems, ScoresQuery, TimeRange,
+};
+use songsort::{Playlist, PlaylistSync, Playlists, ProviderId, RatingScope, Score, Scores};
+use songsort_web::auth::{self, Permission, Principal, Role, DEMO_USER};
+use songsort_web::matches::{self, MatchError};
+use songsort_web::router::{self, BodyError, Credentials, CredentialsError, Route, RouteError};
+use std::collections::HashSet;
 use std::convert::Infallible;
-use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
-#[cfg(feature = "dev")]
-use tokio::fs::File;
-#[cfg(feature = "dev")]
-use tokio::io::AsyncReadExt;
+use store::CosmosStore;
 use uuid::Uuid;
 
+mod assets;
+mod config;
+mod dedupe;
+mod demo;
+mod export;
+mod library;
+mod provider;
+mod publish;
+mod search;
+mod sessions;
+mod spotify;
+mod stats;
+mod store;
+mod tracks;
+mod upload;
+mod users;
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Token {
     access_token: String,
     refresh_token: Option<String>,
+    /// Seconds until the access token expires
+    expires_in: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct User {
-    id: String,
-    user_id: String,
-    auth: String,
-    access_token: String,
-    refresh_token: String,
-}
-
-impl<'a> CosmosEntity<'a> for User {
-    type Entity = &'a str;
-
-    fn partition_key(&'a self) -> Self::Entity {
-        self.user_id.as_ref()
-    }
-}
-
-const DEMO_USER: &str = "demo";
-
 async fn handle(
-    db: CosmosClient,
+    db: DatabaseClient,
     req: Request<Body>,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    config: Arc<Config>,
+    demo: Arc<Demo>,
+    assets: Arc<Assets>,
+    providers: Arc<Providers>,
 ) -> Result<Response<Body>, Infallible> {
-    Ok(match route(db, req, session).await {
-        Err(e) => {
-            eprintln!("server error: {:?}", e);
-            Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::empty())
-                .expect("empty response builder should work")
-        }
-        Ok(resp) => resp,
-    })
+    Ok(
+        match route(db, req, session, config, demo, assets, providers).await {
+            Err(e) => {
+                eprintln!("server error: {:?}", e);
+                get_response_builder()
+                    .status(e.status())
+                    .body(Body::empty())
+                    .expect("empty response builder should work")
+            }
+            Ok(resp) => resp,
+        },
+    )
 }
 
 async fn route(
-    db: CosmosClient,
+    db: DatabaseClient,
     req: Request<Body>,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    config: Arc<Config>,
+    demo: Arc<Demo>,
+    assets: Arc<Assets>,
+    providers: Arc<Providers>,
 ) -> Result<Response<Body>, Error> {
-    let db = db.into_database_client("songsort");
     eprintln!("{}", req.uri().path());
-    if let Some(path) = req.uri().path().strip_prefix("/api/") {
-        let path: Vec<_> = path.split('/').collect();
-        if req.method() == Method::OPTIONS {
-            return get_response_builder()
+    let Some(path) = req.uri().path().strip_prefix("/api/") else {
+        return assets.serve(&req).await;
+    };
+    let route = match Route::recognize(req.method(), path) {
+        Ok(route) => route,
+        Err(RouteError::NotFound) => return not_found(),
+        Err(RouteError::MethodNotAllowed) => return method_not_allowed(),
+    };
+    if route.permission() == Permission::Public {
+        return match route {
+            Route::Preflight => get_response_builder()
                 .header(
                     "Access-Control-Allow-Headers",
                     HeaderValue::from_static("Authorization"),
@@ -85,215 +109,179 @@ async fn route(
                 )
                 .status(StatusCode::OK)
                 .body(Body::empty())
-                .map_err(Error::from);
-        }
-        let Some(auth) = req.headers().get("Authorization") else {
-            return unauthorized()};
-        let Some((_, auth)) = auth.to_str().expect("auth to be ASCII").split_once(' ') else {
-            return get_response_builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::empty())
-                .map_err(Error::from);
+                .map_err(Error::from),
+            Route::GetClip { token } => match providers.library() {
+                Some(library) => library.serve_clip(&token, &req).await,
+                None => not_found(),
+            },
+            _ => unreachable!("only preflight requests and clips are public"),
         };
-        if auth == "demo" {
-            let user_id = String::from(DEMO_USER);
-            match (&path[..], req.method()) {
-                (["playlists"], &Method::GET) => get_playlists(db, session, user_id).await,
-                (["playlists", id, "scores"], &Method::GET) => {
-                    get_playlist_scores(db, session, user_id, id).await
-                }
-                // TODO: deprecate
-                (["elo"], &Method::POST) => elo(db, session, user_id, req.uri().query()).await,
-                (["scores"], &Method::GET) => get_scores(db, session, user_id).await,
-                /*([""], &Method::POST) => {
-                    handle_action(db, session, user_id, req.uri().query()).await
-                }*/
-                (_, _) => get_response_builder()
-                    .status(StatusCode::METHOD_NOT_ALLOWED)
-                    .body(Body::empty())
-                    .map_err(Error::from),
-            }
-        } else if let Ok((user_id, access_token)) = login(db.clone(), &session, auth, {
-            let uri: Uri = req.headers()["Referer"]
-                .to_str()
-                .expect("Referer to be ASCII")
-                .parse()
-                .expect("referer URI");
-            &format!(
-                "{}://{}",
-                uri.scheme().expect("scheme"),
-                uri.authority().expect("authority")
-            )
-        })
-        .await
-        {
-            match (&path[..], req.method()) {
-                (["login"], &Method::POST) => get_response_builder()
-                    .header(
-                        "Access-Control-Allow-Headers",
-                        HeaderValue::from_static("Authorization"),
-                    )
-                    .status(StatusCode::OK)
-                    .body(Body::empty())
-                    .map_err(Error::from),
-                (["playlists"], &Method::GET) => get_playlists(db, session, user_id).await,
-                // TODO: deprecate
-                (["playlists", playlist_id], &Method::POST) => {
-                    import_playlist(db, session, user_id, playlist_id).await
-                }
-                (["playlists", id], &Method::DELETE) => {
-                    delete_playlist(db, session, user_id, id).await
-                }
-                (["playlists", id, "scores"], &Method::GET) => {
-                    get_playlist_scores(db, session, user_id, id).await
-                }
-                // TODO: deprecate
-                (["elo"], &Method::POST) => elo(db, session, user_id, req.uri().query()).await,
-                (["scores"], &Method::GET) => get_scores(db, session, user_id).await,
-                (["spotify", "playlists"], &Method::GET) => {
-                    get_spotify_playlists(user_id, &access_token).await
-                }
-                ([""], &Method::POST) => {
-                    handle_action(db, session, user_id, req.uri().query()).await
+    }
+    let mut principal = match router::credentials(req.headers()) {
+        Ok(Credentials::Session(token)) => match sessions::find(db.clone(), &token).await? {
+            Some(stored) if stored.demo => match Principal::demo(&stored.user_id, demo.sandbox) {
+                Some(principal) => principal,
+                None => return unauthorized(),
+            },
+            Some(stored) => Principal {
+                role: auth::user_role(&stored.user_id, &config.admins),
+                user_id: stored.user_id,
+                access_token: None,
+            },
+            None => return unauthorized(),
+        },
+        Ok(Credentials::Code(code)) => match Principal::demo(&code, demo.sandbox) {
+            Some(principal) => principal,
+            // Authorization codes can only be exchanged once, which is what logging in does
+            None if route == Route::Login => {
+                let Some(origin) = router::origin(req.headers()) else {
+                    return bad_request();
+                };
+                let spotify = providers.spotify();
+                let Ok(user) = users::login(db.clone(), &session, spotify, &code, &origin).await
+                else {
+                    return unauthorized();
+                };
+                Principal {
+                    role: auth::user_role(&user.user_id, &config.admins),
+                    user_id: user.user_id,
+                    access_token: Some(user.access_token),
                 }
-                (_, _) => get_response_builder()
-                    .status(StatusCode::METHOD_NOT_ALLOWED)
-                    .body(Body::empty())
-                    .map_err(Error::from),
             }
-        } else {
-            unauthorized()
-        }
-    } else {
-        #[cfg(feature = "dev")]
-        if let Some((file, mime)) = match req.uri().path() {
-            "/" => Some((File::open("../songsort-wasm/www/index.html"), "text/html")),
-            "/songsort_wasm.js" => Some((
-                File::open("../songsort-wasm/pkg/songsort_wasm.js"),
-                "application/javascript",
-            )),
-            "/songsort_wasm_bg.wasm" => Some((
-                File::open("../songsort-wasm/pkg/songsort_wasm_bg.wasm"),
-                "application/wasm",
-            )),
-            _ => None,
-        } {
-            let mut contents = Vec::new();
-            file.await?.read_to_end(&mut contents).await?;
-            return get_response_builder()
-                .header("Content-Type", HeaderValue::from_static(mime))
-                .status(StatusCode::OK)
-                .body(Body::from(contents))
-                .map_err(Error::from);
-        }
-        get_response_builder()
+            None => return unauthorized(),
+        },
+        Err(CredentialsError::Missing) => return unauthorized(),
+        Err(CredentialsError::Malformed) => return bad_request(),
+    };
+    if !principal.role.grants(route.permission()) {
+        return forbidden();
+    }
+    if principal.role == Role::Sandbox {
+        demo.ensure_sandbox(db.clone(), &session, &principal.user_id)
+            .await?;
+    }
+    if route.uses_access_token()
+        && principal.access_token.is_none()
+        && principal.role.grants(Permission::Spotify)
+    {
+        let spotify = providers.spotify();
+        let access_token =
+            users::access_token(db.clone(), &session, spotify, &principal.user_id).await?;
+        principal.access_token = Some(access_token);
+    }
+    if route == Route::Login {
+        let stored = sessions::create(db, &principal).await?;
+        let login = songsort::Login {
+            user_id: principal.user_id,
+        };
+        return get_response_builder()
             .header(
                 "Access-Control-Allow-Headers",
                 HeaderValue::from_static("Authorization"),
             )
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty())
-            .map_err(Error::from)
+            .header(SET_COOKIE, sessions::cookie(&stored))
+            .status(StatusCode::OK)
+            .body(Body::from(serde_json::to_string(&login)?))
+            .map_err(Error::from);
     }
-}
-
-async fn login(
-    db: DatabaseClient,
-    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
-    auth: &str,
-    origin: &str,
-) -> Result<(String, String), Error> {
-    let db = db.into_collection_client("users");
-    let query = format!("SELECT * FROM c WHERE c.auth = \"{}\"", auth);
-    let query = Query::new(&query);
-    let session_copy = session.read().unwrap().clone();
-    // TODO: debug why session token isn't working here
-    let (resp, session) = /*if let Some(session) = session_copy {
-        println!("{:?}", session);
-        (
-            db.query_documents()
-                .query_cross_partition(true)
-                .parallelize_cross_partition_query(true)
-                .consistency_level(session.clone())
-                .execute(&query)
-                .await?,
-            session,
-        )
-    } else */{
-        let resp = db
-            .query_documents()
-            .query_cross_partition(true)
-            .parallelize_cross_partition_query(true)
-            .execute(&query)
-            .await?;
-        let token = ConsistencyLevel::Session(resp.session_token.clone());
-        *session.write().unwrap() = Some(token.clone());
-        (resp, token)
-    };
-    if let Some(user) = resp
-        .into_documents()?
-        .results
-        .into_iter()
-        .map(|r| -> User { r.result })
-        .next()
-    {
-        return Ok((user.user_id, user.access_token));
+    let user_id = principal.user_id;
+    let ttl = demo::ttl(principal.role);
+    match route {
+        Route::GetPlaylists => get_playlists(db, session, user_id).await,
+        Route::ImportPlaylist { id } => {
+            import_playlist(db, session, &providers, user_id, ttl, &id).await
+        }
+        Route::DeletePlaylist { id } => delete_playlist(db, session, user_id, &id).await,
+        Route::UploadPlaylists => upload::upload_playlists(db, session, user_id, ttl, req).await,
+        Route::ExportPlaylist { id } => {
+            let query = router::query(req.uri().query())?;
+            export::export_playlist(db, session, user_id, &id, query).await
+        }
+        Route::ImportPlaylistFile { id } => {
+            let query = router::query(req.uri().query())?;
+            let body = req.into_body();
+            export::import_playlist_file(db, session, user_id, &id, ttl, query, body).await
+        }
+        Route::PublishPlaylist { id } => {
+            let Some(access_token) = principal.access_token else {
+                return unauthorized();
+            };
+            let query = router::query(req.uri().query())?;
+            publish::publish_playlist(db, session, user_id, &access_token, &id, query).await
+        }
+        Route::SyncPlaylist { id } => {
+            let access_token = principal.access_token.as_deref();
+            sync_playlist(db, session, &providers, user_id, access_token, &id).await
+        }
+        Route::GetPlaylistScores { id } => {
+            let query = router::query(req.uri().query())?;
+            get_playlist_scores(db, session, user_id, &id, query).await
+        }
+        Route::Elo => elo(db, session, user_id, router::query(req.uri().query())?).await,
+        Route::GetScores => {
+            get_scores(db, session, user_id, router::query(req.uri().query())?).await
+        }
+        Route::GetArtistStats => {
+            let query = router::query(req.uri().query())?;
+            stats::get_leaderboard(db, session, user_id, songsort::stats::artists, query).await
+        }
+        Route::GetAlbumStats => {
+            let query = router::query(req.uri().query())?;
+            stats::get_leaderboard(db, session, user_id, songsort::stats::albums, query).await
+        }
+        Route::GetTrackHistory { id } => {
+            let query = router::query(req.uri().query())?;
+            tracks::get_history(db, session, user_id, &id, query).await
+        }
+        Route::UndoMatch { id } => undo_match(db, session, user_id, &id).await,
+        Route::GetTrack { id } => {
+            let query = router::query(req.uri().query())?;
+            tracks::get_track(db, session, user_id, &id, query).await
+        }
+        Route::GetDuplicates => dedupe::get_duplicates(db, session, user_id).await,
+        Route::MergeScores => {
+            let request = router::body(req.into_body()).await?;
+            dedupe::merge_scores(db, session, user_id, request).await
+        }
+        Route::GetSpotifyPlaylists => {
+            let Some(access_token) = principal.access_token else {
+                return unauthorized();
+            };
+            get_spotify_playlists(user_id, &access_token).await
+        }
+        Route::Action => {
+            let query = router::query(req.uri().query())?;
+            let access_token = principal.access_token.as_deref();
+            handle_action(db, session, &providers, user_id, ttl, access_token, query).await
+        }
+        Route::GetLibrary => match providers.library() {
+            Some(library) => get_response_builder()
+                .body(Body::from(serde_json::to_string(&library.listing())?))
+                .map_err(Error::from),
+            None => not_found(),
+        },
+        Route::CreateClip { id } => match providers.library() {
+            Some(library) => library.clip(&id),
+            None => not_found(),
+        },
+        Route::ScanLibrary => match providers.library() {
+            Some(library) => get_response_builder()
+                .body(Body::from(serde_json::to_string(&library.scan().await?)?))
+                .map_err(Error::from),
+            None => not_found(),
+        },
+        Route::GetDemoStatus => get_response_builder()
+            .body(Body::from(serde_json::to_string(&demo.status())?))
+            .map_err(Error::from),
+        Route::ResetDemo => {
+            demo.reset(db, &session, &providers).await?;
+            get_response_builder()
+                .body(Body::from(serde_json::to_string(&demo.status())?))
+                .map_err(Error::from)
+        }
+        Route::Login => unreachable!("logging in is handled above"),
+        Route::Preflight | Route::GetClip { .. } => unreachable!("public routes are handled above"),
     }
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = "https://accounts.spotify.com/api/token".parse().unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .method(Method::POST)
-                .uri(uri)
-                .header(
-                    "Authorization",
-                    &format!(
-                        "Basic {}",
-                        std::env::var("SPOTIFY_TOKEN").expect("SPOTIFY_TOKEN is missing")
-                    ),
-                )
-                .header("Content-Type", "application/x-www-form-urlencoded")
-                .body(Body::from(format!(
-                    "grant_type=authorization_code&code={}&redirect_uri={}",
-                    auth, origin
-                )))?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let token: Token = serde_json::from_slice(&got)?;
-
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = "https://api.spotify.com/v1/me".parse().unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .uri(uri)
-                .header("Authorization", format!("Bearer {}", token.access_token))
-                .body(Body::empty())?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let user: songsort_web::User = serde_json::from_slice(&got)?;
-
-    let user = User {
-        id: Uuid::new_v4().to_hyphenated().to_string(),
-        user_id: user.id,
-        auth: auth.to_owned(),
-        access_token: token.access_token.clone(),
-        refresh_token: token
-            .refresh_token
-            .expect("Spotify should return refresh token"),
-    };
-    db.create_document(
-        Context::new(),
-        &user,
-        CreateDocumentOptions::new().consistency_level(session),
-    )
-    .await?;
-    Ok((user.user_id, user.access_token))
 }
 
 async fn get_playlists(
@@ -306,7 +294,6 @@ async fn get_playlists(
     let query = Query::new(&query);
     let session_copy = session.read().unwrap().clone();
     let resp = if let Some(session) = session_copy {
-        println!("{:?}", session);
         db.query_documents()
             .consistency_level(session)
             .execute(&query)
@@ -329,15 +316,72 @@ async fn get_playlists(
         .map_err(Error::from)
 }
 
+/// Delete a playlist along with the scores that no other playlist uses.
 async fn delete_playlist(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
     user_id: String,
     id: &str,
 ) -> Result<Response<Body>, Error> {
+    let Some(playlist) = get_playlist(db.clone(), &user_id, id).await? else {
+        return not_found();
+    };
+    delete_document(
+        db.clone().into_collection_client("playlists"),
+        &session,
+        id,
+        &user_id,
+    )
+    .await?;
+    let remaining: Vec<Playlist> = query_documents(
+        db.clone().into_collection_client("playlists"),
+        &session,
+        &format!("SELECT * FROM c WHERE c.user_id = \"{}\"", user_id),
+    )
+    .await?;
+    let orphaned = songsort::orphaned_scores(&playlist, &remaining);
+    if !orphaned.is_empty() {
+        let client = db.into_collection_client("scores");
+        // Only delete scores that exist, since a track's score may never have been created
+        let scores: Vec<Score> = query_documents(
+            client.clone(),
+            &session,
+            &format!(
+                "SELECT * FROM c WHERE c.user_id = \"{}\" AND c.id IN ({})",
+                user_id,
+                orphaned
+                    .iter()
+                    .map(|id| format!("\"{}\"", id))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
+        .await?;
+        let client = &client;
+        let session = &session;
+        let user_id = &user_id;
+        futures::stream::iter(scores.into_iter().map(async move |score| {
+            delete_document(client.clone(), session, &score.id, user_id).await
+        }))
+        .buffered(5)
+        .try_collect::<()>()
+        .await?;
+    }
+    get_response_builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+async fn delete_document(
+    client: CollectionClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    id: &str,
+    user_id: &str,
+) -> Result<(), Error> {
     let session_copy = session.read().unwrap().clone();
     if let Some(session) = session_copy {
-        db.into_collection_client("playlists")
+        client
             .into_document_client(id, &user_id)?
             .delete_document(
                 Context::new(),
@@ -345,111 +389,70 @@ async fn delete_playlist(
             )
             .await?;
     } else {
-        let resp = db
-            .into_collection_client("playlists")
+        let resp = client
             .into_document_client(id, &user_id)?
             .delete_document(Context::new(), DeleteDocumentOptions::new())
             .await?;
         *session.write().unwrap() = Some(ConsistencyLevel::Session(resp.session_token));
     }
-    get_response_builder()
-        .status(StatusCode::NO_CONTENT)
-        .body(Body::empty())
-        .map_err(Error::from)
+    Ok(())
 }
 
 async fn elo(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
     user_id: String,
-    query: Option<&str>,
+    query: EloQuery,
 ) -> Result<Response<Body>, Error> {
-    if let Some((win, lose)) = query.and_then(|s| s.split_once('&')) {
-        let client = db.clone().into_collection_client("scores");
-        let scores =
-            get_score_docs(client.clone(), &session, user_id.clone(), &[win, lose]).await?;
-        let mut iter = scores.into_iter();
-        if let (Some(win_score), Some(lose_score)) = (iter.next(), iter.next()) {
-            let (mut win_score, mut lose_score) = if win_score.track_id == win {
-                (win_score, lose_score)
-            } else {
-                (lose_score, win_score)
-            };
-            let expected_win =
-                1. / (1. + 10f64.powf((lose_score.score - win_score.score) as f64 / 400.));
-            let expected_lose =
-                1. / (1. + 10f64.powf((win_score.score - lose_score.score) as f64 / 400.));
-            let win_diff = (32. * (1. - expected_win)) as i32;
-            let lose_diff = (32. * expected_lose) as i32;
-            win_score.score += win_diff;
-            lose_score.score -= lose_diff;
-            win_score.wins += 1;
-            lose_score.losses += 1;
-            let client1 = client
-                .clone()
-                .into_document_client(win_score.id.clone(), &win_score.user_id)?;
-            let client2 =
-                client.into_document_client(lose_score.id.clone(), &lose_score.user_id)?;
-            let session = session
-                .read()
-                .unwrap()
-                .clone()
-                .expect("session should be set by get_score_docs");
-            futures::future::try_join(
-                client1.replace_document(
-                    Context::new(),
-                    &win_score,
-                    ReplaceDocumentOptions::new().consistency_level(session.clone()),
-                ),
-                client2.replace_document(
-                    Context::new(),
-                    &lose_score,
-                    ReplaceDocumentOptions::new().consistency_level(session),
-                ),
-            )
-            .await?;
-            get_response_builder()
-                .status(StatusCode::OK)
-                .body(Body::empty())
-                .map_err(Error::from)
-        } else {
-            get_response_builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::empty())
-                .map_err(Error::from)
-        }
-    } else {
-        get_response_builder()
-            .status(StatusCode::BAD_REQUEST)
+    match matches::play(&CosmosStore { db, session }, &user_id, &query).await {
+        Ok(played) => get_response_builder()
+            .status(StatusCode::OK)
+            .body(Body::from(serde_json::to_string(&played)?))
+            .map_err(Error::from),
+        Err(e) => match_error(e),
+    }
+}
+
+async fn undo_match(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    id: &str,
+) -> Result<Response<Body>, Error> {
+    match matches::undo(&CosmosStore { db, session }, &user_id, id).await {
+        Ok(()) => get_response_builder()
+            .status(StatusCode::NO_CONTENT)
             .body(Body::empty())
-            .map_err(Error::from)
+            .map_err(Error::from),
+        Err(e) => match_error(e),
     }
 }
 
-async fn get_score_docs(
-    db: CollectionClient,
+fn match_error(e: MatchError<Error>) -> Result<Response<Body>, Error> {
+    match e {
+        MatchError::Store(e) => Err(e),
+        e => get_response_builder()
+            .status(e.status())
+            .body(Body::empty())
+            .map_err(Error::from),
+    }
+}
+
+async fn query_documents<T: DeserializeOwned>(
+    client: CollectionClient,
     session: &Arc<RwLock<Option<ConsistencyLevel>>>,
-    user_id: String,
-    track_ids: &[&str],
-) -> Result<Vec<Score>, Error> {
-    let query = format!(
-        "SELECT * FROM c WHERE c.user_id = \"{}\" AND c.track_id IN ({})",
-        user_id,
-        track_ids
-            .iter()
-            .map(|t| format!("\"{}\"", t))
-            .collect::<Vec<_>>()
-            .join(",")
-    );
-    let query = Query::new(&query);
+    query: &str,
+) -> Result<Vec<T>, Error> {
+    let query = Query::new(query);
     let session_copy = session.read().unwrap().clone();
     let resp = if let Some(session) = session_copy {
-        db.query_documents()
+        client
+            .query_documents()
             .consistency_level(session)
             .execute(&query)
             .await?
     } else {
-        let resp = db.query_documents().execute(&query).await?;
+        let resp = client.query_documents().execute(&query).await?;
         *session.write().unwrap() = Some(ConsistencyLevel::Session(resp.session_token.clone()));
         resp
     };
@@ -461,55 +464,105 @@ async fn get_score_docs(
         .collect())
 }
 
-async fn get_playlist_scores(
-    db: DatabaseClient,
-    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+/// Get the scores that rate tracks in a playlist. Without a playlist, the user's shared scores are
+/// returned.
+async fn get_score_docs(
+    db: CollectionClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
     user_id: String,
+    playlist: Option<&Playlist>,
+    track_ids: &[&str],
+) -> Result<Vec<Score>, Error> {
+    let query = format!(
+        "SELECT * FROM c WHERE c.user_id = {} AND {} AND c.track_id IN ({})",
+        search::literal(&user_id),
+        score_scope(playlist),
+        track_ids
+            .iter()
+            .map(|t| search::literal(t))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    query_documents(db, session, &query).await
+}
+
+/// The condition that selects the scores of a playlist's rating scope, or shared scores without a
+/// playlist.
+fn score_scope(playlist: Option<&Playlist>) -> String {
+    match playlist {
+        Some(playlist) if playlist.rating_scope == RatingScope::Playlist => {
+            scope_condition(Some(&playlist.id))
+        }
+        _ => scope_condition(None),
+    }
+}
+
+/// The condition that selects the scores that belong to a playlist, or shared scores without one.
+fn scope_condition(playlist_id: Option<&str>) -> String {
+    match playlist_id {
+        Some(id) => format!("c.playlist_id = {}", search::literal(id)),
+        None => String::from("NOT IS_DEFINED(c.playlist_id)"),
+    }
+}
+
+/// The conditions that select the scores of a playlist's tracks.
+fn playlist_score_conditions(user_id: &str, playlist: &Playlist) -> Vec<String> {
+    vec![
+        format!("c.user_id = {}", search::literal(user_id)),
+        score_scope(Some(playlist)),
+        format!(
+            "c.track_id IN ({})",
+            playlist
+                .tracks
+                .iter()
+                .map(|t| search::literal(t))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+    ]
+}
+
+async fn get_playlist(
+    db: DatabaseClient,
+    user_id: &str,
     id: &str,
-) -> Result<Response<Body>, Error> {
+) -> Result<Option<Playlist>, Error> {
     let client = db
-        .clone()
         .into_collection_client("playlists")
         .into_document_client(id, &user_id)?;
-    let playlist = if let GetDocumentResponse::Found(playlist) = client
+    if let GetDocumentResponse::Found(playlist) = client
         .get_document::<Playlist>(Context::new(), GetDocumentOptions::new())
         .await?
     {
-        playlist.document.document
+        Ok(Some(playlist.document.document))
     } else {
-        todo!()
-    };
+        Ok(None)
+    }
+}
 
-    let db = db.into_collection_client("scores");
-    let query = format!(
-        "SELECT * FROM c WHERE c.user_id = \"{}\" AND c.track_id IN ({})",
-        user_id,
-        playlist
-            .tracks
-            .iter()
-            .map(|t| format!("\"{}\"", t))
-            .collect::<Vec<_>>()
-            .join(",")
-    );
-    let query = Query::new(&query);
-    let session_copy = session.read().unwrap().clone();
-    let resp = if let Some(session) = session_copy {
-        db.query_documents()
-            .consistency_level(session)
-            .execute(&query)
-            .await?
-    } else {
-        let resp = db.query_documents().execute(&query).await?;
-        *session.write().unwrap() = Some(ConsistencyLevel::Session(resp.session_token.clone()));
-        resp
+async fn get_playlist_scores(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    id: &str,
+    query: ScoresQuery,
+) -> Result<Response<Body>, Error> {
+    let Some(playlist) = get_playlist(db.clone(), &user_id, id).await? else {
+        return not_found();
     };
-    let scores = Scores {
-        scores: resp
-            .into_documents()?
-            .results
-            .into_iter()
-            .map(|r| r.result)
-            .collect(),
+    let scores = if playlist.tracks.is_empty() {
+        Scores {
+            scores: Vec::new(),
+            next: None,
+        }
+    } else {
+        search::search_scores(
+            db.into_collection_client("scores"),
+            &session,
+            playlist_score_conditions(&user_id, &playlist),
+            &query,
+        )
+        .await?
     };
     get_response_builder()
         .body(Body::from(serde_json::to_string(&scores)?))
@@ -520,28 +573,20 @@ async fn get_scores(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
     user_id: String,
+    query: ScoresQuery,
 ) -> Result<Response<Body>, Error> {
-    let db = db.into_collection_client("scores");
-    let query = format!("SELECT * FROM c WHERE c.user_id = \"{}\"", user_id);
-    let session_copy = session.read().unwrap().clone();
-    let resp = if let Some(session) = session_copy {
-        db.query_documents()
-            .consistency_level(session)
-            .execute(&query)
-            .await?
-    } else {
-        let resp = db.query_documents().execute(&query).await?;
-        *session.write().unwrap() = Some(ConsistencyLevel::Session(resp.session_token.clone()));
-        resp
-    };
-    let scores = Scores {
-        scores: resp
-            .into_documents()?
-            .results
-            .into_iter()
-            .map(|r| r.result)
-            .collect(),
-    };
+    // Scores rated separately belong to their playlist's page
+    let conditions = vec![
+        format!("c.user_id = {}", search::literal(&user_id)),
+        score_scope(None),
+    ];
+    let scores = search::search_scores(
+        db.into_collection_client("scores"),
+        &session,
+        conditions,
+        &query,
+    )
+    .await?;
     get_response_builder()
         .body(Body::from(serde_json::to_string(&scores)?))
         .map_err(Error::from)
@@ -550,191 +595,152 @@ async fn get_scores(
 async fn handle_action(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    providers: &Providers,
     user_id: String,
-    query: Option<&str>,
+    ttl: Option<i32>,
+    access_token: Option<&str>,
+    query: ActionQuery,
 ) -> Result<Response<Body>, Error> {
-    if let Some(query) = query.and_then(|q| {
-        q.split('&')
-            .map(|s| s.split_once('='))
-            .collect::<Option<Vec<(&str, &str)>>>()
-    }) {
-        match query[..] {
-            [("action", "import"), ("playlist", id)] => {
-                return import_playlist(db, session, user_id, id).await;
-            }
-            [("action", "import"), ("album", id)] => {
-                return import_album(db, session, user_id, id).await;
-            }
-            _ => {}
+    let Action::Import = query.action;
+    let Some(collection) = fetch_source(providers, access_token, query.source).await? else {
+        return forbidden();
+    };
+    import_collection(db, session, user_id, ttl, collection, query.scope).await
+}
+
+/// Get the tracks of a source, or nothing if it belongs to a Spotify account and there's no access
+/// token for one.
+async fn fetch_source(
+    providers: &Providers,
+    access_token: Option<&str>,
+    source: ImportSource,
+) -> Result<Option<Collection>, Error> {
+    let collection = match source {
+        ImportSource::Playlist(id) => {
+            let (provider, id) = providers.resolve(&id)?;
+            provider.playlist(&id).await?
         }
-    }
-    get_response_builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(Body::empty())
-        .map_err(Error::from)
+        ImportSource::Album(id) => {
+            let (provider, id) = providers.resolve(&id)?;
+            provider.album(&id).await?
+        }
+        ImportSource::Artist(id) => {
+            let (provider, id) = providers.resolve(&id)?;
+            provider.artist(&id).await?
+        }
+        // Saved and top tracks are private, so they need the user's own token
+        ImportSource::Saved(SavedItems::Tracks) => {
+            let Some(access_token) = access_token else {
+                return Ok(None);
+            };
+            providers.spotify().saved_tracks(access_token).await?
+        }
+        ImportSource::Top(time_range) => {
+            let Some(access_token) = access_token else {
+                return Ok(None);
+            };
+            providers
+                .spotify()
+                .top_tracks(access_token, time_range)
+                .await?
+        }
+    };
+    Ok(Some(collection))
 }
 
 async fn import_playlist(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    providers: &Providers,
     user_id: String,
+    ttl: Option<i32>,
     playlist_id: &str,
 ) -> Result<Response<Body>, Error> {
-    let token = get_token().await?;
-
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = format!("https://api.spotify.com/v1/playlists/{}", playlist_id)
-        .parse()
-        .unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .uri(uri)
-                .header("Authorization", format!("Bearer {}", token.access_token))
-                .body(Body::empty())?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let playlist: songsort_web::Playlist = serde_json::from_slice(&got)?;
+    let (provider, playlist_id) = providers.resolve(playlist_id)?;
+    let collection = provider.playlist(&playlist_id).await?;
+    import_collection(db, session, user_id, ttl, collection, RatingScope::Global).await
+}
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = format!(
-        "https://api.spotify.com/v1/playlists/{}/tracks",
-        playlist_id
-    )
-    .parse()
-    .unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .uri(uri)
-                .header("Authorization", format!("Bearer {}", token.access_token))
-                .body(Body::empty())?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let mut playlist_items: songsort_web::PlaylistItems = serde_json::from_slice(&got)?;
-    let mut playlist = Playlist {
-        id: playlist_id.to_owned(),
-        user_id: user_id.clone(),
-        playlist_id: playlist_id.to_owned(),
-        name: playlist.name,
-        tracks: playlist_items
-            .items
-            .iter()
-            .map(|i| i.track.id.clone())
-            .collect(),
+/// Save an imported collection as a playlist. New playlists are rated in `rating_scope`, and
+/// playlists that were imported before keep theirs.
+async fn import_collection(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    ttl: Option<i32>,
+    collection: Collection,
+    rating_scope: RatingScope,
+) -> Result<Response<Body>, Error> {
+    let (playlist, scores) = collection.into_playlist(&user_id, ttl);
+    // Importing a playlist again can't move it to another scope, because its scores stay behind
+    let rating_scope = get_playlist(db.clone(), &user_id, &playlist.id)
+        .await?
+        .map_or(rating_scope, |existing| existing.rating_scope);
+    let playlist = Playlist {
+        rating_scope,
+        ..playlist
     };
-    let mut scores: Vec<_> = playlist_items
-        .items
-        .iter()
-        .map(|i| Score {
-            id: i.track.id.clone(),
-            track_id: i.track.id.clone(),
-            track: i.track.name.clone(),
-            album: i.track.album.name.clone(),
-            artists: i.track.artists.iter().map(|a| a.name.clone()).collect(),
-            user_id: user_id.clone(),
-            score: 1500,
-            wins: 0,
-            losses: 0,
-        })
-        .collect();
-    while let Some(uri) = playlist_items.next {
-        let uri: Uri = uri.parse().unwrap();
-        let resp = client
-            .request(
-                Request::builder()
-                    .uri(uri)
-                    .header("Authorization", format!("Bearer {}", token.access_token))
-                    .body(Body::empty())?,
-            )
-            .await?;
-        let got = hyper::body::to_bytes(resp.into_body()).await?;
-        playlist_items = serde_json::from_slice(&got)?;
-        for i in &playlist_items.items {
-            playlist.tracks.push(i.track.id.clone());
-            scores.push(Score {
-                id: i.track.id.clone(),
-                track_id: i.track.id.clone(),
-                track: i.track.name.clone(),
-                album: i.track.album.name.clone(),
-                artists: i.track.artists.iter().map(|a| a.name.clone()).collect(),
-                user_id: user_id.clone(),
-                score: 1500,
-                wins: 0,
-                losses: 0,
-            });
-        }
-    }
     // Reset demo user data
     create_playlist(db, session, playlist, scores, user_id == DEMO_USER).await
 }
 
-async fn import_album(
+/// Bring an imported playlist up to date with its source. New tracks get fresh scores and removed
+/// tracks are archived, so their scores are kept.
+async fn sync_playlist(
     db: DatabaseClient,
     session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    providers: &Providers,
     user_id: String,
+    access_token: Option<&str>,
     id: &str,
 ) -> Result<Response<Body>, Error> {
-    let token = get_token().await?;
-
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = format!("https://api.spotify.com/v1/albums/{}", id)
-        .parse()
-        .unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .uri(uri)
-                .header("Authorization", format!("Bearer {}", token.access_token))
-                .body(Body::empty())?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let album: songsort_web::Album = serde_json::from_slice(&got)?;
-
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = format!("https://api.spotify.com/v1/albums/{}/tracks", id)
-        .parse()
-        .unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .uri(uri)
-                .header("Authorization", format!("Bearer {}", token.access_token))
-                .body(Body::empty())?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let album_items: songsort_web::AlbumItems = serde_json::from_slice(&got)?;
-    let playlist = Playlist {
-        id: Uuid::new_v4().to_hyphenated().to_string(),
-        user_id: user_id.clone(),
-        playlist_id: id.to_owned(),
-        name: album.name.clone(),
-        tracks: album_items.items.iter().map(|i| i.id.clone()).collect(),
+    let Some(stored) = get_playlist(db.clone(), &user_id, id).await? else {
+        return not_found();
     };
-    let scores: Vec<_> = album_items
-        .items
+    let source = ProviderId::parse(&stored.playlist_id).import_source();
+    let Some(collection) = fetch_source(providers, access_token, source).await? else {
+        return forbidden();
+    };
+    let (current, scores) = collection.into_playlist(&user_id, stored.ttl);
+    let stored_tracks: HashSet<_> = stored.tracks.iter().collect();
+    let current_tracks: HashSet<_> = current.tracks.iter().collect();
+    let sync = PlaylistSync {
+        added: current
+            .tracks
+            .iter()
+            .filter(|t| !stored_tracks.contains(t))
+            .cloned()
+            .collect(),
+        removed: stored
+            .tracks
+            .iter()
+            .filter(|t| !current_tracks.contains(t))
+            .cloned()
+            .collect(),
+    };
+    let mut archived: Vec<_> = stored
+        .archived
         .iter()
-        .map(|i| Score {
-            id: Uuid::new_v4().to_hyphenated().to_string(),
-            track_id: i.id.clone(),
-            track: i.name.clone(),
-            album: album.name.clone(),
-            artists: i.artists.iter().map(|a| a.name.clone()).collect(),
-            user_id: user_id.clone(),
-            score: 1500,
-            wins: 0,
-            losses: 0,
-        })
+        .filter(|t| !current_tracks.contains(t) && !sync.removed.contains(t))
+        .cloned()
+        .collect();
+    archived.extend(sync.removed.iter().cloned());
+    // Tracks that come back after being archived keep their old scores, because existing scores
+    // are never overwritten
+    let scores = scores
+        .into_iter()
+        .filter(|s| !stored_tracks.contains(&s.track_id))
         .collect();
-    create_playlist(db, session, playlist, scores, false).await
+    let playlist = Playlist {
+        name: current.name,
+        tracks: current.tracks,
+        archived,
+        ..stored
+    };
+    save_playlist(db, session, playlist, scores, false).await?;
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&sync)?))
+        .map_err(Error::from)
 }
 
 async fn create_playlist(
@@ -744,6 +750,22 @@ async fn create_playlist(
     scores: Vec<Score>,
     is_upsert: bool,
 ) -> Result<Response<Body>, Error> {
+    save_playlist(db, session, playlist, scores, is_upsert).await?;
+    get_response_builder()
+        .status(StatusCode::CREATED)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+/// Upsert a playlist and create its scores in the playlist's rating scope. Existing scores are only
+/// overwritten if `is_upsert` is set.
+async fn save_playlist(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    playlist: Playlist,
+    scores: Vec<Score>,
+    is_upsert: bool,
+) -> Result<(), Error> {
     let playlist_client = db.clone().into_collection_client("playlists");
     let session_copy = session.read().unwrap().clone();
     let session = if let Some(session) = session_copy {
@@ -764,8 +786,7 @@ async fn create_playlist(
                 &playlist,
                 CreateDocumentOptions::new().is_upsert(true),
             )
-            .await
-            .unwrap();
+            .await?;
         let session_copy = ConsistencyLevel::Session(resp.session_token);
         *session.write().unwrap() = Some(session_copy.clone());
         session_copy
@@ -773,7 +794,8 @@ async fn create_playlist(
     let score_client = db.into_collection_client("scores");
     let score_client = &score_client;
     let session = &session;
-    futures::stream::iter(scores.into_iter().map(async move |score| {
+    let scores = scores.into_iter().map(|s| playlist.scope_score(s));
+    futures::stream::iter(scores.map(async move |score| {
         score_client
             .create_document(
                 Context::new(),
@@ -800,10 +822,7 @@ async fn create_playlist(
     .buffered(5)
     .try_collect::<()>()
     .await?;
-    get_response_builder()
-        .status(StatusCode::CREATED)
-        .body(Body::empty())
-        .map_err(Error::from)
+    Ok(())
 }
 
 async fn get_spotify_playlists(
@@ -822,7 +841,7 @@ async fn get_spotify_playlists(
         )
         .await?;
     let got = hyper::body::to_bytes(resp.into_body()).await?;
-    let playlists: songsort_web::Playlists = serde_json::from_slice(&got).unwrap();
+    let playlists: songsort_web::Playlists = serde_json::from_slice(&got)?;
     let playlists = Playlists {
         items: playlists
             .items
@@ -835,6 +854,10 @@ async fn get_spotify_playlists(
                 name: p.name,
                 user_id: user_id.clone(),
                 tracks: Vec::new(),
+                archived: Vec::new(),
+                published_id: None,
+                rating_scope: RatingScope::Global,
+                ttl: None,
             })
             .collect(),
     };
@@ -843,68 +866,82 @@ async fn get_spotify_playlists(
         .map_err(Error::from)
 }
 
-async fn get_token() -> Result<Token, Error> {
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let uri: Uri = "https://accounts.spotify.com/api/token".parse().unwrap();
-    let resp = client
-        .request(
-            Request::builder()
-                .method(Method::POST)
-                .uri(uri)
-                .header(
-                    "Authorization",
-                    &format!(
-                        "Basic {}",
-                        std::env::var("SPOTIFY_TOKEN").expect("SPOTIFY_TOKEN is missing")
-                    ),
-                )
-                .header("Content-Type", "application/x-www-form-urlencoded")
-                .body(Body::from("grant_type=client_credentials"))?,
-        )
-        .await?;
-    let got = hyper::body::to_bytes(resp.into_body()).await?;
-    serde_json::from_slice(&got).map_err(Error::from)
-}
-
 #[tokio::main]
 async fn main() {
-    // We'll bind to 127.0.0.1:3000
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-
-    // A `Service` is needed for every connection, so this
-    // creates one from our `hello_world` function.
-    let master_key =
-        std::env::var("COSMOS_MASTER_KEY").expect("Set env variable COSMOS_MASTER_KEY first!");
-    let account = std::env::var("COSMOS_ACCOUNT").expect("Set env variable COSMOS_ACCOUNT first!");
-    let authorization_token =
-        AuthorizationToken::primary_from_base64(&master_key).expect("cosmos config");
-    let client = CosmosClient::new(
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let StorageConfig::Cosmos {
+        account,
+        master_key,
+        database,
+    } = &config.storage;
+    let authorization_token = match AuthorizationToken::primary_from_base64(master_key) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("configuration error: invalid storage.master_key: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let db = CosmosClient::new(
         account.clone(),
         authorization_token,
         CosmosOptions::default(),
-    );
+    )
+    .into_database_client(database.clone());
+    // Before serving, so that sorting by games is right from the first request
+    match search::backfill_games(db.clone().into_collection_client("scores")).await {
+        Ok(0) => {}
+        Ok(updated) => eprintln!("stored games on {} scores", updated),
+        Err(e) => eprintln!("games backfill error: {:?}", e),
+    }
     let session = Arc::new(RwLock::new(None));
+    let providers = Arc::new(Providers::new(&config));
+    let scan = Arc::clone(&providers);
+    tokio::spawn(async move {
+        if let Some(library) = scan.library() {
+            if let Err(e) = library.scan().await {
+                eprintln!("library scan error: {:?}", e);
+            }
+        }
+    });
+    let demo = Arc::new(Demo::new(
+        config.demo.sandbox,
+        config.demo.playlist_id.clone(),
+        config.demo.reset_interval,
+    ));
+    tokio::spawn(Arc::clone(&demo).run_resets(
+        db.clone(),
+        Arc::clone(&session),
+        Arc::clone(&providers),
+    ));
 
-    // Reset demo user data during startup in production
-    if cfg!(not(feature = "dev")) {
-        import_playlist(
-            client.clone().into_database_client("songsort"),
-            Arc::clone(&session),
-            String::from(DEMO_USER),
-            "37i9dQZF1DX49jUV2NfGku",
-        )
-        .await
-        .unwrap();
-    }
+    let assets = Arc::new(Assets::new(&config.assets));
 
+    let addr = config.bind;
     let make_svc = make_service_fn(move |_conn| {
-        let client_ref = client.clone();
+        let db = db.clone();
         let session = Arc::clone(&session);
+        let config = Arc::clone(&config);
+        let demo = Arc::clone(&demo);
+        let assets = Arc::clone(&assets);
+        let providers = Arc::clone(&providers);
         async {
             // service_fn converts our function into a `Service`
             Ok::<_, Infallible>(service_fn(move |r| {
-                handle(client_ref.clone(), r, Arc::clone(&session))
+                handle(
+                    db.clone(),
+                    r,
+                    Arc::clone(&session),
+                    Arc::clone(&config),
+                    Arc::clone(&demo),
+                    Arc::clone(&assets),
+                    Arc::clone(&providers),
+                )
             }))
         }
     });
@@ -924,6 +961,34 @@ fn unauthorized() -> Result<Response<Body>, Error> {
         .map_err(Error::from)
 }
 
+fn forbidden() -> Result<Response<Body>, Error> {
+    get_response_builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+fn bad_request() -> Result<Response<Body>, Error> {
+    get_response_builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+fn method_not_allowed() -> Result<Response<Body>, Error> {
+    get_response_builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
+fn not_found() -> Result<Response<Body>, Error> {
+    get_response_builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .map_err(Error::from)
+}
+
 fn get_response_builder() -> Builder {
     Response::builder().header("Access-Control-Allow-Origin", HeaderValue::from_static("*"))
 }
@@ -933,12 +998,51 @@ fn get_response_builder() -> Builder {
 enum Error {
     HyperError(hyper::Error),
     RequestError(hyper::http::Error),
+    UriError(hyper::http::uri::InvalidUri),
     JSONError(serde_json::Error),
+    QueryError(serde_urlencoded::de::Error),
+    BodyError(BodyError),
+    UploadError(multer::Error),
+    CsvError(csv::Error),
     CosmosError(azure_data_cosmos::Error),
-    #[cfg(feature = "dev")]
+    /// The provider doesn't exist or can't import this kind of source
+    UnsupportedSource,
+    /// The provider has no source with this id
+    SourceNotFound,
+    /// The id isn't in the provider's format
+    InvalidSourceId,
+    /// Spotify answered with an error status
+    SpotifyError(StatusCode),
+    MissingRefreshToken,
+    /// Too many demo sandboxes were created recently
+    TooManySandboxes,
     IOError(std::io::Error),
 }
 
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::QueryError(_)
+            | Error::BodyError(BodyError::Json(_))
+            | Error::UploadError(_)
+            | Error::UnsupportedSource
+            | Error::InvalidSourceId => StatusCode::BAD_REQUEST,
+            Error::SourceNotFound | Error::SpotifyError(StatusCode::NOT_FOUND) => {
+                StatusCode::NOT_FOUND
+            }
+            // The user's token expired or was granted before we asked for a scope. This is
+            // songsort::api::SPOTIFY_UNAUTHORIZED, so it isn't mistaken for an expired session.
+            Error::SpotifyError(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                StatusCode::FAILED_DEPENDENCY
+            }
+            Error::SpotifyError(_) => StatusCode::BAD_GATEWAY,
+            Error::BodyError(BodyError::TooLarge) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::TooManySandboxes => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl From<hyper::Error> for Error {
     fn from(e: hyper::Error) -> Error {
         Error::HyperError(e)
@@ -951,19 +1055,48 @@ impl From<hyper::http::Error> for Error {
     }
 }
 
+impl From<hyper::http::uri::InvalidUri> for Error {
+    fn from(e: hyper::http::uri::InvalidUri) -> Error {
+        Error::UriError(e)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Error {
         Error::JSONError(e)
     }
 }
 
+impl From<serde_urlencoded::de::Error> for Error {
+    fn from(e: serde_urlencoded::de::Error) -> Error {
+        Error::QueryError(e)
+    }
+}
+
+impl From<BodyError> for Error {
+    fn from(e: BodyError) -> Error {
+        Error::BodyError(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::CsvError(e)
+    }
+}
+
+impl From<multer::Error> for Error {
+    fn from(e: multer::Error) -> Error {
+        Error::UploadError(e)
+    }
+}
+
 impl From<azure_data_cosmos::Error> for Error {
     fn from(e: azure_data_cosmos::Error) -> Error {
         Error::CosmosError(e)
     }
 }
 
-#[cfg(feature = "dev")]
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
         Error::IOError(e)
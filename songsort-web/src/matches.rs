@@ -0,0 +1,205 @@
+//! Playing matches and taking them back. Storage is behind `Store`, so the server keeps scores
+//! in Cosmos and tests can keep them in memory.
+
+use async_trait::async_trait;
+use hyper::StatusCode;
+use songsort::api::EloQuery;
+use songsort::history::{Match, MatchTrack, UndoState};
+use songsort::{Playlist, RatingScope, Score};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Where a user's playlists, scores and matches are kept.
+#[async_trait]
+pub trait Store: Sync {
+    type Error;
+
+    async fn get_playlist(&self, user_id: &str, id: &str) -> Result<Option<Playlist>, Self::Error>;
+
+    /// Get the scores of tracks in a rating scope, which is a playlist's own scores or the shared
+    /// scores without a playlist.
+    async fn get_scores(
+        &self,
+        user_id: &str,
+        playlist_id: Option<&str>,
+        track_ids: &[&str],
+    ) -> Result<Vec<Score>, Self::Error>;
+
+    async fn replace_score(&self, score: &Score) -> Result<(), Self::Error>;
+
+    async fn get_match(&self, user_id: &str, id: &str) -> Result<Option<Match>, Self::Error>;
+
+    async fn create_match(&self, played: &Match) -> Result<(), Self::Error>;
+
+    async fn delete_match(&self, user_id: &str, id: &str) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum MatchError<E> {
+    /// The playlist or the match doesn't exist
+    NotFound,
+    /// A track of the match has no score to play with
+    MissingScore,
+    /// A track has played since the match, so it can't be taken back
+    PlayedSince,
+    Store(E),
+}
+
+impl<E> MatchError<E> {
+    /// The status that the error is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MatchError::NotFound => StatusCode::NOT_FOUND,
+            MatchError::MissingScore => StatusCode::BAD_REQUEST,
+            MatchError::PlayedSince => StatusCode::CONFLICT,
+            MatchError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Play a match between two tracks and update their scores. Matches in a playlist that rates its
+/// tracks separately change the playlist's own scores.
+pub async fn play<S: Store>(
+    store: &S,
+    user_id: &str,
+    query: &EloQuery,
+) -> Result<Match, MatchError<S::Error>> {
+    let (win, lose) = (query.win.as_str(), query.lose.as_str());
+    let playlist_id = match &query.playlist {
+        Some(id) => match store
+            .get_playlist(user_id, id)
+            .await
+            .map_err(MatchError::Store)?
+        {
+            Some(playlist) if playlist.rating_scope == RatingScope::Playlist => Some(playlist.id),
+            Some(_) => None,
+            None => return Err(MatchError::NotFound),
+        },
+        None => None,
+    };
+    let scores = store
+        .get_scores(user_id, playlist_id.as_deref(), &[win, lose])
+        .await
+        .map_err(MatchError::Store)?;
+    let mut iter = scores.into_iter();
+    let (Some(win_score), Some(lose_score)) = (iter.next(), iter.next()) else {
+        return Err(MatchError::MissingScore);
+    };
+    let (mut win_score, mut lose_score) = if win_score.track_id == win {
+        (win_score, lose_score)
+    } else {
+        (lose_score, win_score)
+    };
+    let expected_win = 1. / (1. + 10f64.powf((lose_score.score - win_score.score) as f64 / 400.));
+    let expected_lose = 1. / (1. + 10f64.powf((win_score.score - lose_score.score) as f64 / 400.));
+    let actual = if query.draw { 0.5 } else { 1. };
+    let win_diff = (32. * (actual - expected_win)) as i32;
+    let lose_diff = (32. * (expected_lose - (1. - actual))) as i32;
+    win_score.score += win_diff;
+    lose_score.score -= lose_diff;
+    if !query.draw {
+        win_score.wins += 1;
+        lose_score.losses += 1;
+    }
+    win_score.games = win_score.wins + win_score.losses;
+    lose_score.games = lose_score.wins + lose_score.losses;
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    let win_previous = win_score.last_match.replace(id.clone());
+    let lose_previous = lose_score.last_match.replace(id.clone());
+    let played = Match {
+        id,
+        user_id: user_id.to_owned(),
+        playlist_id: win_score.playlist_id.clone(),
+        winner: MatchTrack {
+            track_id: win_score.track_id.clone(),
+            track: win_score.track.clone(),
+            score: win_score.score,
+            change: win_diff,
+            previous_match: win_previous,
+        },
+        loser: MatchTrack {
+            track_id: lose_score.track_id.clone(),
+            track: lose_score.track.clone(),
+            score: lose_score.score,
+            change: -lose_diff,
+            previous_match: lose_previous,
+        },
+        draw: query.draw,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be after the epoch")
+            .as_millis() as u64,
+        ttl: win_score.ttl,
+    };
+    futures::future::try_join3(
+        store.replace_score(&win_score),
+        store.replace_score(&lose_score),
+        store.create_match(&played),
+    )
+    .await
+    .map_err(MatchError::Store)?;
+    Ok(played)
+}
+
+/// Take back a match by reversing the changes it made to both scores. Only the latest match of
+/// both tracks can be taken back, since the scores have moved on after a later one. Scores that an
+/// earlier attempt already reverted are left alone, so an undo that failed part way can be sent
+/// again.
+pub async fn undo<S: Store>(
+    store: &S,
+    user_id: &str,
+    id: &str,
+) -> Result<(), MatchError<S::Error>> {
+    let Some(played) = store
+        .get_match(user_id, id)
+        .await
+        .map_err(MatchError::Store)?
+    else {
+        return Err(MatchError::NotFound);
+    };
+    let scores = store
+        .get_scores(
+            user_id,
+            played.playlist_id.as_deref(),
+            &[&played.winner.track_id, &played.loser.track_id],
+        )
+        .await
+        .map_err(MatchError::Store)?;
+    let find = |track: &MatchTrack| scores.iter().find(|s| s.track_id == track.track_id);
+    let (Some(win_score), Some(lose_score)) = (find(&played.winner), find(&played.loser)) else {
+        return Err(MatchError::NotFound);
+    };
+    let states = [
+        played.undo_state(&played.winner, win_score),
+        played.undo_state(&played.loser, lose_score),
+    ];
+    if states.contains(&UndoState::PlayedSince) {
+        return Err(MatchError::PlayedSince);
+    }
+    let reverted: Vec<_> = [(&played.winner, win_score), (&played.loser, lose_score)]
+        .into_iter()
+        .zip(states)
+        .filter(|&(_, state)| state == UndoState::Latest)
+        .map(|((track, score), _)| {
+            let mut score = score.clone();
+            score.score -= track.change;
+            if !played.draw {
+                if track.track_id == played.winner.track_id {
+                    score.wins -= 1;
+                } else {
+                    score.losses -= 1;
+                }
+            }
+            score.games = score.wins + score.losses;
+            score.last_match = track.previous_match.clone();
+            score
+        })
+        .collect();
+    futures::future::try_join_all(reverted.iter().map(|score| store.replace_score(score)))
+        .await
+        .map_err(MatchError::Store)?;
+    store
+        .delete_match(user_id, id)
+        .await
+        .map_err(MatchError::Store)
+}
@@ -0,0 +1,142 @@
+use crate::config::Config;
+use crate::library::Library;
+use crate::spotify::Spotify;
+use crate::Error;
+use async_trait::async_trait;
+use songsort::{Playlist, ProviderId, RatingScope, Score};
+
+/// A catalog that playlists can be imported from.
+///
+/// Providers work with their own ids. Anything that is stored is qualified with the provider's
+/// name, see `ProviderId`.
+#[async_trait]
+pub trait MusicProvider: Send + Sync {
+    /// The prefix of this provider's ids.
+    fn name(&self) -> &'static str;
+
+    async fn playlist(&self, id: &str) -> Result<Collection, Error>;
+
+    async fn album(&self, _id: &str) -> Result<Collection, Error> {
+        Err(Error::UnsupportedSource)
+    }
+
+    /// Every song by an artist.
+    async fn artist(&self, _id: &str) -> Result<Collection, Error> {
+        Err(Error::UnsupportedSource)
+    }
+}
+
+/// A list of tracks from a provider, such as a playlist or an album.
+#[derive(Debug)]
+pub struct Collection {
+    pub id: ProviderId,
+    pub name: String,
+    pub tracks: Vec<Track>,
+}
+
+impl Collection {
+    /// The playlist and fresh scores for a user importing this collection, which expire after
+    /// `ttl` seconds if it's set.
+    pub fn into_playlist(self, user_id: &str, ttl: Option<i32>) -> (Playlist, Vec<Score>) {
+        let id = self.id.to_string();
+        let playlist = Playlist {
+            id: id.clone(),
+            playlist_id: id,
+            name: self.name,
+            user_id: user_id.to_owned(),
+            tracks: self.tracks.iter().map(|t| t.id.to_string()).collect(),
+            archived: Vec::new(),
+            published_id: None,
+            rating_scope: RatingScope::Global,
+            ttl,
+        };
+        let scores = self
+            .tracks
+            .iter()
+            .map(|t| Score {
+                ttl,
+                ..t.score(user_id)
+            })
+            .collect();
+        (playlist, scores)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub id: ProviderId,
+    pub name: String,
+    pub album: String,
+    pub artists: Vec<String>,
+    pub release_year: Option<i32>,
+    pub artwork_url: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub isrc: Option<String>,
+}
+
+impl Track {
+    /// A fresh score for this track. Scores are keyed by track so that importing the same track
+    /// again reuses its rating.
+    pub fn score(&self, user_id: &str) -> Score {
+        let id = self.id.to_string();
+        Score {
+            id: id.clone(),
+            track_id: id,
+            track: self.name.clone(),
+            album: self.album.clone(),
+            artists: self.artists.clone(),
+            user_id: user_id.to_owned(),
+            score: songsort::INITIAL_SCORE,
+            wins: 0,
+            losses: 0,
+            games: 0,
+            release_year: self.release_year,
+            artwork_url: self.artwork_url.clone(),
+            duration_ms: self.duration_ms,
+            isrc: self.isrc.clone(),
+            playlist_id: None,
+            merged: Vec::new(),
+            last_match: None,
+            ttl: None,
+        }
+    }
+}
+
+/// Every configured provider.
+pub struct Providers {
+    spotify: Spotify,
+    library: Option<Library>,
+}
+
+impl Providers {
+    pub fn new(config: &Config) -> Providers {
+        Providers {
+            spotify: Spotify::new(config.spotify.clone()),
+            library: Library::new(&config.library),
+        }
+    }
+
+    /// Spotify also has sources that are tied to a user's account.
+    pub fn spotify(&self) -> &Spotify {
+        &self.spotify
+    }
+
+    pub fn library(&self) -> Option<&Library> {
+        self.library.as_ref()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn MusicProvider> {
+        match name {
+            songsort::SPOTIFY => Some(&self.spotify),
+            songsort::LIBRARY => self.library().map(|l| l as &dyn MusicProvider),
+            _ => None,
+        }
+    }
+
+    /// Find the provider of a qualified id, returning it with the provider's own id.
+    pub fn resolve(&self, qualified: &str) -> Result<(&dyn MusicProvider, String), Error> {
+        let id = ProviderId::parse(qualified);
+        let provider = self.get(&id.provider).ok_or(Error::UnsupportedSource)?;
+        Ok((provider, id.id))
+    }
+}
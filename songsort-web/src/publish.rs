@@ -0,0 +1,153 @@
+use crate::spotify;
+use crate::{get_playlist, get_response_builder, get_score_docs, not_found, save_playlist, Error};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use hyper::{Body, Method, Response, StatusCode};
+use serde::Serialize;
+use songsort::api::PublishQuery;
+use songsort::{Playlist, ProviderId, PublishedPlaylist};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Spotify accepts at most this many tracks per request.
+const TRACKS_PER_REQUEST: usize = 100;
+
+#[derive(Serialize)]
+struct PlaylistDetails<'a> {
+    name: &'a str,
+    description: &'a str,
+    public: bool,
+}
+
+#[derive(Serialize)]
+struct Uris<'a> {
+    uris: &'a [String],
+}
+
+/// Write a playlist's tracks, highest score first, to a Spotify playlist in the user's account.
+///
+/// The first call creates a private Spotify playlist and later calls overwrite it, unless it has
+/// been deleted in the meantime.
+pub async fn publish_playlist(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    access_token: &str,
+    id: &str,
+    query: PublishQuery,
+) -> Result<Response<Body>, Error> {
+    let Some(playlist) = get_playlist(db.clone(), &user_id, id).await? else {
+        return not_found();
+    };
+    // Tracks from other providers can't be added to Spotify
+    let track_ids: Vec<_> = playlist
+        .tracks
+        .iter()
+        .filter(|t| ProviderId::parse(t).is_spotify())
+        .map(String::as_str)
+        .collect();
+    let mut scores = if track_ids.is_empty() {
+        Vec::new()
+    } else {
+        get_score_docs(
+            db.clone().into_collection_client("scores"),
+            &session,
+            user_id.clone(),
+            Some(&playlist),
+            &track_ids,
+        )
+        .await?
+    };
+    // Break ties by the order of the playlist
+    let order: HashMap<_, _> = (0..).zip(&playlist.tracks).map(|(i, t)| (t, i)).collect();
+    scores.sort_by_key(|s| (-s.score, order.get(&s.track_id).copied()));
+    let uris: Vec<_> = scores
+        .iter()
+        .take(query.limit.unwrap_or(usize::MAX))
+        .map(|s| format!("spotify:track:{}", s.track_id))
+        .collect();
+    let details = PlaylistDetails {
+        name: query.name.as_deref().unwrap_or(&playlist.name),
+        description: "Ranked with songsort",
+        public: false,
+    };
+
+    let existing = if let Some(spotify_id) = &playlist.published_id {
+        match overwrite(access_token, spotify_id, &details, &uris).await {
+            Ok(()) => Some(spotify_id.clone()),
+            Err(Error::SpotifyError(StatusCode::NOT_FOUND)) => None,
+            Err(e) => return Err(e),
+        }
+    } else {
+        None
+    };
+    let (status, spotify_id) = match existing {
+        Some(spotify_id) => (StatusCode::OK, spotify_id),
+        None => {
+            // The token decides whose playlist it is, so the user id isn't needed in the path
+            let uri = "https://api.spotify.com/v1/me/playlists".parse()?;
+            let created = spotify::send(Method::POST, uri, access_token, &details).await?;
+            let created: songsort_web::Playlist = serde_json::from_slice(&created)?;
+            replace_tracks(access_token, &created.id, &uris).await?;
+            save_playlist(
+                db,
+                session,
+                Playlist {
+                    published_id: Some(created.id.clone()),
+                    ..playlist
+                },
+                Vec::new(),
+                false,
+            )
+            .await?;
+            (StatusCode::CREATED, created.id)
+        }
+    };
+    let published = PublishedPlaylist {
+        url: format!("https://open.spotify.com/playlist/{}", spotify_id),
+        spotify_id,
+        tracks: uris.len(),
+    };
+    get_response_builder()
+        .status(status)
+        .body(Body::from(serde_json::to_string(&published)?))
+        .map_err(Error::from)
+}
+
+async fn overwrite(
+    access_token: &str,
+    spotify_id: &str,
+    details: &PlaylistDetails<'_>,
+    uris: &[String],
+) -> Result<(), Error> {
+    let uri = format!("https://api.spotify.com/v1/playlists/{}", spotify_id).parse()?;
+    spotify::send(Method::PUT, uri, access_token, details).await?;
+    // Deleting a playlist in Spotify only unfollows it, so make sure the user still follows it
+    let uri = format!(
+        "https://api.spotify.com/v1/playlists/{}/followers",
+        spotify_id
+    )
+    .parse()?;
+    spotify::send(Method::PUT, uri, access_token, &serde_json::json!({})).await?;
+    replace_tracks(access_token, spotify_id, uris).await
+}
+
+/// Replace every track in a Spotify playlist, a page at a time.
+async fn replace_tracks(
+    access_token: &str,
+    spotify_id: &str,
+    uris: &[String],
+) -> Result<(), Error> {
+    let mut method = Method::PUT;
+    let mut chunks = uris.chunks(TRACKS_PER_REQUEST);
+    // The first request replaces the old tracks, even if there is nothing to add
+    let mut chunk = chunks.next().unwrap_or_default();
+    loop {
+        let uri = format!("https://api.spotify.com/v1/playlists/{}/tracks", spotify_id).parse()?;
+        spotify::send(method, uri, access_token, &Uris { uris: chunk }).await?;
+        let Some(next) = chunks.next() else {
+            return Ok(());
+        };
+        method = Method::POST;
+        chunk = next;
+    }
+}
@@ -0,0 +1,293 @@
+//! Recognizing API requests: their route, credentials and query strings. Nothing here touches
+//! storage, so malformed requests can be thrown at it directly.
+
+use crate::auth::Permission;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, COOKIE, REFERER};
+use hyper::{Body, HeaderMap, Method, Uri};
+use serde::de::DeserializeOwned;
+
+/// Every API route, with its path parameters already extracted.
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Preflight,
+    Login,
+    GetPlaylists,
+    // TODO: deprecate
+    ImportPlaylist { id: String },
+    DeletePlaylist { id: String },
+    SyncPlaylist { id: String },
+    ExportPlaylist { id: String },
+    ImportPlaylistFile { id: String },
+    PublishPlaylist { id: String },
+    UploadPlaylists,
+    GetPlaylistScores { id: String },
+    // TODO: deprecate
+    Elo,
+    GetScores,
+    GetArtistStats,
+    GetAlbumStats,
+    GetTrack { id: String },
+    GetTrackHistory { id: String },
+    UndoMatch { id: String },
+    GetDuplicates,
+    MergeScores,
+    GetSpotifyPlaylists,
+    Action,
+    GetLibrary,
+    CreateClip { id: String },
+    GetClip { token: String },
+    ScanLibrary,
+    GetDemoStatus,
+    ResetDemo,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    NotFound,
+    MethodNotAllowed,
+}
+
+impl Route {
+    /// Match a path relative to `/api/`.
+    pub fn recognize(method: &Method, path: &str) -> Result<Route, RouteError> {
+        if method == Method::OPTIONS {
+            return Ok(Route::Preflight);
+        }
+        let segments: Vec<_> = path.split('/').collect();
+        match (&segments[..], method) {
+            (["login"], &Method::POST) => Ok(Route::Login),
+            (["playlists"], &Method::GET) => Ok(Route::GetPlaylists),
+            (["playlists", id], &Method::POST) => Ok(Route::ImportPlaylist { id: param(id)? }),
+            (["playlists", id], &Method::DELETE) => Ok(Route::DeletePlaylist { id: param(id)? }),
+            (["playlists", id, "scores"], &Method::GET) => {
+                Ok(Route::GetPlaylistScores { id: param(id)? })
+            }
+            (["playlists", id, "sync"], &Method::POST) => {
+                Ok(Route::SyncPlaylist { id: param(id)? })
+            }
+            (["playlists", id, "export"], &Method::GET) => {
+                Ok(Route::ExportPlaylist { id: param(id)? })
+            }
+            (["playlists", id, "import"], &Method::POST) => {
+                Ok(Route::ImportPlaylistFile { id: param(id)? })
+            }
+            (["playlists", id, "spotify"], &Method::POST) => {
+                Ok(Route::PublishPlaylist { id: param(id)? })
+            }
+            (["uploads"], &Method::POST) => Ok(Route::UploadPlaylists),
+            (["elo"], &Method::POST) => Ok(Route::Elo),
+            (["scores"], &Method::GET) => Ok(Route::GetScores),
+            (["scores", "duplicates"], &Method::GET) => Ok(Route::GetDuplicates),
+            (["scores", "merge"], &Method::POST) => Ok(Route::MergeScores),
+            (["stats", "artists"], &Method::GET) => Ok(Route::GetArtistStats),
+            (["stats", "albums"], &Method::GET) => Ok(Route::GetAlbumStats),
+            (["tracks", id], &Method::GET) => Ok(Route::GetTrack { id: param(id)? }),
+            (["tracks", id, "history"], &Method::GET) => {
+                Ok(Route::GetTrackHistory { id: param(id)? })
+            }
+            (["matches", id], &Method::DELETE) => Ok(Route::UndoMatch { id: param(id)? }),
+            (["spotify", "playlists"], &Method::GET) => Ok(Route::GetSpotifyPlaylists),
+            ([""], &Method::POST) => Ok(Route::Action),
+            (["library"], &Method::GET) => Ok(Route::GetLibrary),
+            (["library", "tracks", id, "clip"], &Method::POST) => {
+                Ok(Route::CreateClip { id: param(id)? })
+            }
+            (["library", "clips", token], &Method::GET) => Ok(Route::GetClip {
+                token: param(token)?,
+            }),
+            (["admin", "library"], &Method::POST) => Ok(Route::ScanLibrary),
+            (["admin", "demo"], &Method::GET) => Ok(Route::GetDemoStatus),
+            (["admin", "demo"], &Method::POST) => Ok(Route::ResetDemo),
+            (
+                ["login"]
+                | ["playlists"]
+                | ["playlists", _]
+                | ["playlists", _, "scores"]
+                | ["playlists", _, "sync"]
+                | ["playlists", _, "export"]
+                | ["playlists", _, "import"]
+                | ["playlists", _, "spotify"]
+                | ["uploads"]
+                | ["elo"]
+                | ["scores"]
+                | ["scores", "duplicates"]
+                | ["scores", "merge"]
+                | ["stats", "artists"]
+                | ["stats", "albums"]
+                | ["tracks", _]
+                | ["tracks", _, "history"]
+                | ["matches", _]
+                | ["spotify", "playlists"]
+                | [""]
+                | ["library"]
+                | ["library", "tracks", _, "clip"]
+                | ["library", "clips", _]
+                | ["admin", "demo"]
+                | ["admin", "library"],
+                _,
+            ) => Err(RouteError::MethodNotAllowed),
+            _ => Err(RouteError::NotFound),
+        }
+    }
+
+    pub fn permission(&self) -> Permission {
+        match self {
+            Route::Preflight | Route::GetClip { .. } => Permission::Public,
+            Route::GetPlaylists
+            | Route::GetPlaylistScores { .. }
+            | Route::ExportPlaylist { .. }
+            | Route::GetScores
+            | Route::GetArtistStats
+            | Route::GetAlbumStats
+            | Route::GetTrack { .. }
+            | Route::GetTrackHistory { .. }
+            | Route::GetDuplicates
+            | Route::GetLibrary
+            | Route::CreateClip { .. }
+            // Demo visitors log in too, to keep their sandbox in a session
+            | Route::Login => Permission::Read,
+            Route::ImportPlaylist { .. }
+            | Route::DeletePlaylist { .. }
+            | Route::SyncPlaylist { .. }
+            | Route::ImportPlaylistFile { .. }
+            | Route::UploadPlaylists
+            | Route::Elo
+            | Route::UndoMatch { .. }
+            | Route::MergeScores
+            | Route::Action => Permission::Write,
+            Route::GetSpotifyPlaylists | Route::PublishPlaylist { .. } => Permission::Spotify,
+            Route::GetDemoStatus | Route::ResetDemo | Route::ScanLibrary => Permission::Admin,
+        }
+    }
+
+    /// Whether the route uses Spotify on the caller's behalf, which needs their access token.
+    pub fn uses_access_token(&self) -> bool {
+        matches!(
+            self,
+            Route::PublishPlaylist { .. }
+                | Route::SyncPlaylist { .. }
+                | Route::GetSpotifyPlaylists
+                | Route::Action
+        )
+    }
+}
+
+/// Path parameters are ids, which clients percent-encode with `songsort::api::segment`. Reject
+/// empty ids and malformed encodings instead of passing them on.
+fn param(segment: &str) -> Result<String, RouteError> {
+    let mut id = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            id.push(b);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let [Some(high), Some(low)] = hex.map(|b| b.and_then(|b| char::from(b).to_digit(16)))
+        else {
+            return Err(RouteError::NotFound);
+        };
+        id.push((high * 16 + low) as u8);
+    }
+    match String::from_utf8(id) {
+        Ok(id) if !id.is_empty() => Ok(id),
+        _ => Err(RouteError::NotFound),
+    }
+}
+
+/// The cookie that keeps a session from logging in. It's HttpOnly so that scripts can't read it.
+pub const SESSION_COOKIE: &str = "songsort-session";
+
+/// The credentials that a request was sent with.
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    /// A session from logging in, from the session cookie or a bearer token
+    Session(String),
+    /// A Spotify authorization code, or the demo credentials
+    Code(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CredentialsError {
+    Missing,
+    Malformed,
+}
+
+/// Read the Authorization header, which is `Bearer <session>` or `<scheme> <code>`. Without one,
+/// the session cookie is used.
+pub fn credentials(headers: &HeaderMap) -> Result<Credentials, CredentialsError> {
+    let Some(auth) = headers.get(AUTHORIZATION) else {
+        return session_cookie(headers)
+            .map(Credentials::Session)
+            .ok_or(CredentialsError::Missing);
+    };
+    let (scheme, auth) = auth
+        .to_str()
+        .ok()
+        .and_then(|auth| auth.split_once(' '))
+        .filter(|(_, auth)| !auth.is_empty())
+        .ok_or(CredentialsError::Malformed)?;
+    Ok(if scheme == "Bearer" {
+        Credentials::Session(auth.to_owned())
+    } else {
+        Credentials::Code(auth.to_owned())
+    })
+}
+
+/// Find the session cookie among the request's cookies.
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|&(name, value)| name == SESSION_COOKIE && !value.is_empty())
+        .map(|(_, value)| value.to_owned())
+}
+
+/// The origin of the page that sent a request, which is where Spotify sent the user back to after
+/// logging in.
+pub fn origin(headers: &HeaderMap) -> Option<String> {
+    let uri: Uri = headers.get(REFERER)?.to_str().ok()?.parse().ok()?;
+    Some(format!("{}://{}", uri.scheme()?, uri.authority()?))
+}
+
+/// Decode a query string into `T`. A missing query string is treated as empty.
+pub fn query<T: DeserializeOwned>(query: Option<&str>) -> Result<T, serde_urlencoded::de::Error> {
+    serde_urlencoded::from_str(query.unwrap_or_default())
+}
+
+/// The largest body that's read, in bytes. Bodies are lists of ids or exported scores, so this
+/// leaves plenty of room.
+pub const MAX_BODY: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum BodyError {
+    /// The body couldn't be read
+    Read(hyper::Error),
+    /// The body is longer than `MAX_BODY`
+    TooLarge,
+    /// The body isn't the expected JSON
+    Json(serde_json::Error),
+}
+
+/// Decode a JSON request body into `T`.
+pub async fn body<T: DeserializeOwned>(body: Body) -> Result<T, BodyError> {
+    let got = bytes(body).await?;
+    serde_json::from_slice(&got).map_err(BodyError::Json)
+}
+
+/// Read a request body. Reading stops once the body is longer than `MAX_BODY`.
+pub async fn bytes(mut body: Body) -> Result<Vec<u8>, BodyError> {
+    let mut got = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BodyError::Read)?;
+        if got.len() + chunk.len() > MAX_BODY {
+            return Err(BodyError::TooLarge);
+        }
+        got.extend_from_slice(&chunk);
+    }
+    Ok(got)
+}
@@ -0,0 +1,339 @@
+use crate::{query_documents, Error};
+use azure_core::Context;
+use azure_data_cosmos::prelude::{
+    CollectionClient, ConsistencyLevel, Query, ReplaceDocumentOptions,
+};
+use serde::{Deserialize, Serialize};
+use songsort::api::{Order, ScoresQuery, SortKey};
+use songsort::{Score, Scores};
+use std::sync::{Arc, RwLock};
+
+/// Pages are capped so that one request can't read a user's whole library at once. Requests
+/// without a limit still get every score.
+const MAX_LIMIT: usize = 500;
+
+/// Where a page ended: the sort value and id of its last score. Scores that tie are ordered by id,
+/// so the next page starts right after it even if scores were added or rated in between.
+///
+/// Sorting by two properties needs a composite index on the scores container for each sort key,
+/// like `(/score DESC, /id DESC)`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Cursor {
+    /// Only set when the scores are sorted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<SortValue>,
+    id: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+enum SortValue {
+    Number(i32),
+    Text(String),
+}
+
+impl Cursor {
+    /// The cursor of the page that ends with `score`.
+    fn after(score: &Score, sort: Option<SortKey>) -> Cursor {
+        Cursor {
+            value: sort.map(|key| match key {
+                SortKey::Score => SortValue::Number(score.score),
+                SortKey::Wins => SortValue::Number(score.wins),
+                SortKey::Losses => SortValue::Number(score.losses),
+                SortKey::Games => SortValue::Number(score.games),
+                SortKey::Name => SortValue::Text(score.track.clone()),
+            }),
+            id: score.id.clone(),
+        }
+    }
+
+    /// Read a cursor that was made for the same sort.
+    fn parse(cursor: &str, sort: Option<SortKey>) -> Option<Cursor> {
+        let cursor: Cursor = serde_json::from_str(cursor).ok()?;
+        match (sort, &cursor.value) {
+            (None, None)
+            | (Some(SortKey::Name), Some(SortValue::Text(_)))
+            | (
+                Some(SortKey::Score | SortKey::Wins | SortKey::Losses | SortKey::Games),
+                Some(SortValue::Number(_)),
+            ) => Some(cursor),
+            _ => None,
+        }
+    }
+
+    fn to_sql(&self) -> String {
+        match &self.value {
+            Some(SortValue::Number(n)) => n.to_string(),
+            Some(SortValue::Text(text)) => literal(text),
+            None => String::new(),
+        }
+    }
+}
+
+/// Build the SQL for a search. `conditions` are combined with the filters, like the user and
+/// playlist that the scores belong to.
+fn to_sql(query: &ScoresQuery, conditions: Vec<String>, cursor: Option<&Cursor>) -> String {
+    let mut conditions = conditions;
+    if let Some(artist) = &query.artist {
+        conditions.push(format!(
+            "EXISTS(SELECT VALUE a FROM a IN c.artists WHERE CONTAINS(a, {}, true))",
+            literal(artist)
+        ));
+    }
+    if let Some(album) = &query.album {
+        conditions.push(format!("CONTAINS(c.album, {}, true)", literal(album)));
+    }
+    if let Some(q) = &query.q {
+        let q = literal(q);
+        conditions.push(format!(
+            "(CONTAINS(c.track, {q}, true) OR CONTAINS(c.album, {q}, true) OR \
+             EXISTS(SELECT VALUE a FROM a IN c.artists WHERE CONTAINS(a, {q}, true)))",
+            q = q
+        ));
+    }
+    if let Some(min_games) = query.min_games {
+        conditions.push(format!("c.wins + c.losses >= {}", min_games));
+    }
+    let order = match (query.order, query.sort) {
+        (Some(Order::Asc), _) | (None, Some(SortKey::Name) | None) => "ASC",
+        (Some(Order::Desc), _) | (None, Some(_)) => "DESC",
+    };
+    let after = if order == "ASC" { ">" } else { "<" };
+    if let Some(cursor) = cursor {
+        let id = literal(&cursor.id);
+        conditions.push(match query.sort {
+            Some(sort) => format!(
+                "({path} {after} {value} OR ({path} = {value} AND c.id {after} {id}))",
+                path = sort_path(sort),
+                after = after,
+                value = cursor.to_sql(),
+                id = id
+            ),
+            None => format!("c.id {} {}", after, id),
+        });
+    }
+    let mut sql = format!("SELECT * FROM c WHERE {}", conditions.join(" AND "));
+    match query.sort {
+        Some(sort) => sql.push_str(&format!(
+            " ORDER BY {} {}, c.id {}",
+            sort_path(sort),
+            order,
+            order
+        )),
+        // Pages need an order to continue from
+        None if limit(query).is_some() => sql.push_str(&format!(" ORDER BY c.id {}", order)),
+        None => {}
+    }
+    if let Some(limit) = limit(query) {
+        // Fetch one more to know if there's another page
+        sql.push_str(&format!(" OFFSET 0 LIMIT {}", limit + 1));
+    }
+    sql
+}
+
+fn sort_path(key: SortKey) -> &'static str {
+    match key {
+        SortKey::Score => "c.score",
+        SortKey::Wins => "c.wins",
+        SortKey::Losses => "c.losses",
+        SortKey::Games => "c.games",
+        SortKey::Name => "c.track",
+    }
+}
+
+fn limit(query: &ScoresQuery) -> Option<usize> {
+    query.limit.map(|limit| limit.clamp(1, MAX_LIMIT))
+}
+
+/// Find the scores matching `conditions` and the query. Pages are requested by passing the `next`
+/// cursor back.
+pub async fn search_scores(
+    client: CollectionClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    conditions: Vec<String>,
+    query: &ScoresQuery,
+) -> Result<Scores, Error> {
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            Cursor::parse(cursor, query.sort)
+                .ok_or_else(|| Error::QueryError(serde::de::Error::custom("invalid cursor")))?,
+        ),
+        None => None,
+    };
+    let mut scores: Vec<Score> =
+        query_documents(client, session, &to_sql(query, conditions, cursor.as_ref())).await?;
+    let next = match limit(query) {
+        Some(limit) if scores.len() > limit => {
+            scores.truncate(limit);
+            let last = scores.last().expect("limits are at least 1");
+            Some(serde_json::to_string(&Cursor::after(last, query.sort))?)
+        }
+        _ => None,
+    };
+    Ok(Scores { scores, next })
+}
+
+/// Store `games` on the scores from before it was stored, so that sorting by it puts them in
+/// the right place. Returns how many scores were updated.
+pub async fn backfill_games(client: CollectionClient) -> Result<usize, Error> {
+    let query =
+        Query::new("SELECT * FROM c WHERE NOT IS_DEFINED(c.games) OR c.games != c.wins + c.losses");
+    let mut updated = 0;
+    // Each query returns a page of the scores that are left
+    loop {
+        let scores: Vec<Score> = client
+            .query_documents()
+            .query_cross_partition(true)
+            .parallelize_cross_partition_query(true)
+            .execute(&query)
+            .await?
+            .into_documents()?
+            .results
+            .into_iter()
+            .map(|r| r.result)
+            .collect();
+        if scores.is_empty() {
+            return Ok(updated);
+        }
+        for score in scores {
+            let score = Score {
+                games: score.wins + score.losses,
+                ..score
+            };
+            client
+                .clone()
+                .into_document_client(score.id.clone(), &score.user_id)?
+                .replace_document(Context::new(), &score, ReplaceDocumentOptions::new())
+                .await?;
+            updated += 1;
+        }
+    }
+}
+
+/// Quote a string for a query. JSON strings are valid SQL strings in Cosmos.
+pub fn literal(value: &str) -> String {
+    serde_json::to_string(value).expect("strings always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> Vec<String> {
+        vec![String::from("c.user_id = \"user\"")]
+    }
+
+    fn score(id: &str, score: i32, track: &str) -> Score {
+        Score {
+            id: id.to_owned(),
+            track_id: id.to_owned(),
+            track: track.to_owned(),
+            album: String::new(),
+            artists: Vec::new(),
+            user_id: String::from("user"),
+            score,
+            wins: 0,
+            losses: 0,
+            games: 0,
+            release_year: None,
+            artwork_url: None,
+            duration_ms: None,
+            isrc: None,
+            playlist_id: None,
+            merged: Vec::new(),
+            last_match: None,
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn everything_without_a_query() {
+        assert_eq!(
+            to_sql(&ScoresQuery::default(), user(), None),
+            "SELECT * FROM c WHERE c.user_id = \"user\""
+        );
+    }
+
+    #[test]
+    fn filters_are_quoted() {
+        let query = ScoresQuery {
+            artist: Some(String::from("\") OR true OR (\"")),
+            album: Some(String::from("Album")),
+            min_games: Some(3),
+            ..ScoresQuery::default()
+        };
+        assert_eq!(
+            to_sql(&query, user(), None),
+            "SELECT * FROM c WHERE c.user_id = \"user\" AND \
+             EXISTS(SELECT VALUE a FROM a IN c.artists WHERE \
+             CONTAINS(a, \"\\\") OR true OR (\\\"\", true)) AND \
+             CONTAINS(c.album, \"Album\", true) AND c.wins + c.losses >= 3"
+        );
+    }
+
+    #[test]
+    fn sorted_pages() {
+        let query = ScoresQuery {
+            sort: Some(SortKey::Score),
+            limit: Some(10),
+            ..ScoresQuery::default()
+        };
+        assert_eq!(
+            to_sql(&query, user(), None),
+            "SELECT * FROM c WHERE c.user_id = \"user\" \
+             ORDER BY c.score DESC, c.id DESC OFFSET 0 LIMIT 11"
+        );
+        let cursor = Cursor::after(&score("b", 1500, "Song"), query.sort);
+        assert_eq!(
+            to_sql(&query, user(), Some(&cursor)),
+            "SELECT * FROM c WHERE c.user_id = \"user\" AND \
+             (c.score < 1500 OR (c.score = 1500 AND c.id < \"b\")) \
+             ORDER BY c.score DESC, c.id DESC OFFSET 0 LIMIT 11"
+        );
+    }
+
+    #[test]
+    fn names_sort_ascending() {
+        let query = ScoresQuery {
+            sort: Some(SortKey::Name),
+            limit: Some(1),
+            ..ScoresQuery::default()
+        };
+        let cursor = Cursor::after(&score("a", 1500, "\"Quoted\""), query.sort);
+        assert_eq!(
+            to_sql(&query, user(), Some(&cursor)),
+            "SELECT * FROM c WHERE c.user_id = \"user\" AND \
+             (c.track > \"\\\"Quoted\\\"\" OR (c.track = \"\\\"Quoted\\\"\" AND c.id > \"a\")) \
+             ORDER BY c.track ASC, c.id ASC OFFSET 0 LIMIT 2"
+        );
+    }
+
+    #[test]
+    fn unsorted_pages_follow_ids() {
+        let query = ScoresQuery {
+            limit: Some(MAX_LIMIT + 1),
+            ..ScoresQuery::default()
+        };
+        let cursor = Cursor::after(&score("a", 1500, "Song"), None);
+        assert_eq!(
+            to_sql(&query, user(), Some(&cursor)),
+            "SELECT * FROM c WHERE c.user_id = \"user\" AND c.id > \"a\" \
+             ORDER BY c.id ASC OFFSET 0 LIMIT 501"
+        );
+    }
+
+    #[test]
+    fn cursors_only_fit_their_sort() {
+        let cursor = Cursor::after(&score("a", 1500, "Song"), Some(SortKey::Wins));
+        let encoded = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(encoded, r#"{"value":0,"id":"a"}"#);
+        assert_eq!(Cursor::parse(&encoded, Some(SortKey::Score)), Some(cursor));
+        assert_eq!(Cursor::parse(&encoded, Some(SortKey::Name)), None);
+        assert_eq!(Cursor::parse(&encoded, None), None);
+        assert_eq!(Cursor::parse("10", Some(SortKey::Score)), None);
+        assert_eq!(
+            Cursor::parse(r#"{"value":{"a":1},"id":"a"}"#, Some(SortKey::Score)),
+            None
+        );
+    }
+}
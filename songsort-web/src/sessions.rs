@@ -0,0 +1,94 @@
+use crate::Error;
+use azure_core::Context;
+use azure_data_cosmos::prelude::{
+    CosmosEntity, CreateDocumentOptions, DatabaseClient, GetDocumentOptions, GetDocumentResponse,
+};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use songsort_web::auth::{Principal, Role};
+use songsort_web::router::SESSION_COOKIE;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a login lasts before the user has to log in with Spotify again.
+///
+/// Sessions are also deleted after this long if TTL is enabled on the sessions container.
+const SESSION_LIFETIME: u64 = 60 * 60 * 24 * 30;
+
+/// A login that lasts across page reloads, so that the Spotify authorization code is only sent
+/// once.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Session {
+    /// The secret that clients send in the session cookie
+    pub id: String,
+    /// The Spotify user who logged in, or the demo credentials of a demo visitor. Sessions that
+    /// kept the authorization code instead don't have one, so their users log in again.
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub demo: bool,
+    /// Unix timestamp in seconds
+    pub expires: u64,
+    pub ttl: i32,
+}
+
+impl<'a> CosmosEntity<'a> for Session {
+    type Entity = &'a str;
+
+    fn partition_key(&'a self) -> Self::Entity {
+        self.id.as_ref()
+    }
+}
+
+/// Start a session for someone who logged in.
+pub async fn create(db: DatabaseClient, principal: &Principal) -> Result<Session, Error> {
+    let session = Session {
+        id: rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect(),
+        user_id: principal.user_id.clone(),
+        demo: matches!(principal.role, Role::Demo | Role::Sandbox),
+        expires: now() + SESSION_LIFETIME,
+        ttl: SESSION_LIFETIME as i32,
+    };
+    db.into_collection_client("sessions")
+        .create_document(Context::new(), &session, CreateDocumentOptions::new())
+        .await?;
+    Ok(session)
+}
+
+/// Find the session that a token belongs to, unless it has expired.
+pub async fn find(db: DatabaseClient, token: &str) -> Result<Option<Session>, Error> {
+    let client = db
+        .into_collection_client("sessions")
+        .into_document_client(token, &token)?;
+    if let GetDocumentResponse::Found(session) = client
+        .get_document::<Session>(Context::new(), GetDocumentOptions::new())
+        .await?
+    {
+        let session = session.document.document;
+        Ok(Some(session).filter(|s| s.expires > now() && !s.user_id.is_empty()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The Set-Cookie header that keeps a session in the browser. Scripts can't read it, and it's only
+/// sent back to the API.
+pub fn cookie(session: &Session) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/api; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE,
+        session.id,
+        session.expires.saturating_sub(now())
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time should be after the epoch")
+        .as_secs()
+}
@@ -0,0 +1,330 @@
+use crate::config::SpotifyConfig;
+use crate::provider::{Collection, MusicProvider, Track};
+use crate::{Error, Token};
+use async_trait::async_trait;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use songsort::api::TimeRange;
+use songsort::ProviderId;
+use std::collections::HashSet;
+
+/// Public catalog lookups use the app's client credentials. Sources that belong to a user, like
+/// their Liked Songs, need the user's own access token.
+pub struct Spotify {
+    config: SpotifyConfig,
+}
+
+impl Spotify {
+    pub fn new(config: SpotifyConfig) -> Spotify {
+        Spotify { config }
+    }
+
+    /// Get an app token with the client credentials flow.
+    async fn token(&self) -> Result<String, Error> {
+        let token = self
+            .request_token(&[("grant_type", "client_credentials")])
+            .await?;
+        Ok(token.access_token)
+    }
+
+    /// Exchange the authorization code that a user logged in with for their tokens.
+    pub async fn exchange_code(&self, code: &str, redirect_uri: &str) -> Result<Token, Error> {
+        self.request_token(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+        ])
+        .await
+    }
+
+    /// Get a new access token for a user. Spotify answers 400 once the user has taken back the
+    /// app's access, which is reported like any other refused authorization.
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<Token, Error> {
+        self.request_token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .await
+        .map_err(|e| match e {
+            Error::SpotifyError(StatusCode::BAD_REQUEST) => {
+                Error::SpotifyError(StatusCode::UNAUTHORIZED)
+            }
+            e => e,
+        })
+    }
+
+    async fn request_token(&self, form: &[(&str, &str)]) -> Result<Token, Error> {
+        let https = HttpsConnector::new();
+        let client = Client::builder().build::<_, hyper::Body>(https);
+        let uri: Uri = "https://accounts.spotify.com/api/token".parse().unwrap();
+        let resp = client
+            .request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(uri)
+                    .header("Authorization", &format!("Basic {}", self.config.token))
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(Body::from(
+                        serde_urlencoded::to_string(form).expect("forms of strings encode"),
+                    ))?,
+            )
+            .await?;
+        if !resp.status().is_success() {
+            return Err(Error::SpotifyError(resp.status()));
+        }
+        let got = hyper::body::to_bytes(resp.into_body()).await?;
+        serde_json::from_slice(&got).map_err(Error::from)
+    }
+
+    pub async fn saved_tracks(&self, access_token: &str) -> Result<Collection, Error> {
+        let uri = "https://api.spotify.com/v1/me/tracks?limit=50".parse()?;
+        let mut saved_items: songsort_web::PlaylistItems = get(uri, access_token).await?;
+        let mut tracks = Vec::new();
+        loop {
+            tracks.extend(saved_items.items.iter().map(|i| track(&i.track)));
+            let Some(uri) = saved_items.next else {
+                break;
+            };
+            saved_items = get(uri.parse()?, access_token).await?;
+        }
+        Ok(Collection {
+            id: ProviderId::new(songsort::SPOTIFY, songsort::SAVED_TRACKS),
+            name: String::from("Liked Songs"),
+            tracks,
+        })
+    }
+
+    pub async fn top_tracks(
+        &self,
+        access_token: &str,
+        time_range: TimeRange,
+    ) -> Result<Collection, Error> {
+        let name = match time_range {
+            TimeRange::ShortTerm => "Top Tracks (4 Weeks)",
+            TimeRange::MediumTerm => "Top Tracks (6 Months)",
+            TimeRange::LongTerm => "Top Tracks (All Time)",
+        };
+        let uri = format!(
+            "https://api.spotify.com/v1/me/top/tracks?time_range={}&limit=50",
+            time_range.as_str()
+        )
+        .parse()?;
+        let mut top_tracks: songsort_web::TopTracks = get(uri, access_token).await?;
+        let mut tracks = Vec::new();
+        loop {
+            tracks.extend(top_tracks.items.iter().map(track));
+            let Some(uri) = top_tracks.next else {
+                break;
+            };
+            top_tracks = get(uri.parse()?, access_token).await?;
+        }
+        Ok(Collection {
+            id: ProviderId::top_tracks(time_range),
+            name: String::from(name),
+            tracks,
+        })
+    }
+}
+
+#[async_trait]
+impl MusicProvider for Spotify {
+    fn name(&self) -> &'static str {
+        songsort::SPOTIFY
+    }
+
+    async fn playlist(&self, id: &str) -> Result<Collection, Error> {
+        let id = check_id(id)?;
+        let token = self.token().await?;
+        let uri = format!("https://api.spotify.com/v1/playlists/{}", id).parse()?;
+        let playlist: songsort_web::Playlist = get(uri, &token).await?;
+        let uri = format!("https://api.spotify.com/v1/playlists/{}/tracks", id).parse()?;
+        let mut playlist_items: songsort_web::PlaylistItems = get(uri, &token).await?;
+        let mut tracks = Vec::new();
+        loop {
+            tracks.extend(playlist_items.items.iter().map(|i| track(&i.track)));
+            let Some(uri) = playlist_items.next else {
+                break;
+            };
+            playlist_items = get(uri.parse()?, &token).await?;
+        }
+        Ok(Collection {
+            id: ProviderId::new(self.name(), id),
+            name: playlist.name,
+            tracks,
+        })
+    }
+
+    async fn album(&self, id: &str) -> Result<Collection, Error> {
+        let id = check_id(id)?;
+        let token = self.token().await?;
+        let uri = format!("https://api.spotify.com/v1/albums/{}", id).parse()?;
+        let album: songsort_web::Album = get(uri, &token).await?;
+        let uri = format!("https://api.spotify.com/v1/albums/{}/tracks?limit=50", id).parse()?;
+        let mut album_items: songsort_web::AlbumItems = get(uri, &token).await?;
+        let mut tracks = Vec::new();
+        loop {
+            tracks.extend(album_items.items.iter().map(|i| album_track(i, &album)));
+            let Some(uri) = album_items.next else {
+                break;
+            };
+            album_items = get(uri.parse()?, &token).await?;
+        }
+        Ok(Collection {
+            id: ProviderId::new(self.name(), id),
+            name: album.name,
+            tracks,
+        })
+    }
+
+    async fn artist(&self, id: &str) -> Result<Collection, Error> {
+        let id = check_id(id)?;
+        let token = self.token().await?;
+        let uri = format!("https://api.spotify.com/v1/artists/{}", id).parse()?;
+        let artist: songsort_web::Artist = get(uri, &token).await?;
+        let uri = format!(
+            "https://api.spotify.com/v1/artists/{}/albums?include_groups=album,single&limit=50",
+            id
+        )
+        .parse()?;
+        let mut artist_albums: songsort_web::ArtistAlbums = get(uri, &token).await?;
+        let mut albums = Vec::new();
+        loop {
+            albums.append(&mut artist_albums.items);
+            let Some(uri) = artist_albums.next else {
+                break;
+            };
+            artist_albums = get(uri.parse()?, &token).await?;
+        }
+        let mut tracks = Vec::new();
+        // Singles are usually repeated on an album, and albums are listed before singles, so keep
+        // the first copy of each song
+        let mut seen_ids = HashSet::new();
+        let mut seen_songs = HashSet::new();
+        for album in albums {
+            let uri = format!(
+                "https://api.spotify.com/v1/albums/{}/tracks?limit=50",
+                album.id
+            )
+            .parse()?;
+            let mut album_items: songsort_web::AlbumItems = get(uri, &token).await?;
+            loop {
+                for i in &album_items.items {
+                    let artists: Vec<_> = i.artists.iter().map(|a| a.name.to_lowercase()).collect();
+                    if seen_ids.insert(i.id.clone())
+                        && seen_songs.insert((i.name.to_lowercase(), artists))
+                    {
+                        tracks.push(album_track(i, &album.album));
+                    }
+                }
+                let Some(uri) = album_items.next else {
+                    break;
+                };
+                album_items = get(uri.parse()?, &token).await?;
+            }
+        }
+        Ok(Collection {
+            id: ProviderId::new(self.name(), id),
+            name: artist.name,
+            tracks,
+        })
+    }
+}
+
+/// Spotify ids are base62. Anything else would change which endpoint the id is sent to.
+fn check_id(id: &str) -> Result<&str, Error> {
+    if !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        Ok(id)
+    } else {
+        Err(Error::InvalidSourceId)
+    }
+}
+
+fn track(track: &songsort_web::Track) -> Track {
+    Track {
+        id: ProviderId::new(songsort::SPOTIFY, &track.id),
+        name: track.name.clone(),
+        album: track.album.name.clone(),
+        artists: track.artists.iter().map(|a| a.name.clone()).collect(),
+        release_year: track.album.release_year(),
+        artwork_url: track.album.artwork_url(),
+        duration_ms: track.duration_ms,
+        isrc: track.external_ids.isrc.clone(),
+    }
+}
+
+fn album_track(track: &songsort_web::AlbumTrack, album: &songsort_web::Album) -> Track {
+    Track {
+        id: ProviderId::new(songsort::SPOTIFY, &track.id),
+        name: track.name.clone(),
+        album: album.name.clone(),
+        artists: track.artists.iter().map(|a| a.name.clone()).collect(),
+        release_year: album.release_year(),
+        artwork_url: album.artwork_url(),
+        duration_ms: track.duration_ms,
+        isrc: None,
+    }
+}
+
+pub async fn get<T: DeserializeOwned>(uri: Uri, access_token: &str) -> Result<T, Error> {
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let resp = client
+        .request(
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", access_token))
+                .body(Body::empty())?,
+        )
+        .await?;
+    if !resp.status().is_success() {
+        return Err(Error::SpotifyError(resp.status()));
+    }
+    let got = hyper::body::to_bytes(resp.into_body()).await?;
+    serde_json::from_slice(&got).map_err(Error::from)
+}
+
+/// Send a JSON body to Spotify. Some endpoints answer with an empty body, so the response is
+/// returned as is.
+pub async fn send<B: Serialize>(
+    method: Method,
+    uri: Uri,
+    access_token: &str,
+    body: &B,
+) -> Result<hyper::body::Bytes, Error> {
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let resp = client
+        .request(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", access_token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(body)?))?,
+        )
+        .await?;
+    if !resp.status().is_success() {
+        return Err(Error::SpotifyError(resp.status()));
+    }
+    hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_id;
+
+    #[test]
+    fn ids_are_base62() {
+        assert_eq!(
+            check_id("37i9dQZF1DXcBWIGoYBM5M").ok(),
+            Some("37i9dQZF1DXcBWIGoYBM5M")
+        );
+        for id in ["", "a/tracks", "a?market=US", "a#b", "a b", "a%2F", "ä"] {
+            assert!(check_id(id).is_err(), "{}", id);
+        }
+    }
+}
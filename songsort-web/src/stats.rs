@@ -0,0 +1,47 @@
+use crate::{
+    get_playlist, get_response_builder, not_found, playlist_score_conditions, query_documents,
+    score_scope, search, Error,
+};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use hyper::{Body, Response};
+use songsort::api::PlaylistQuery;
+use songsort::stats::Leaderboard;
+use songsort::Score;
+use std::sync::{Arc, RwLock};
+
+/// Rank the user's artists or albums with `rank`, over all their scores or a playlist's.
+pub async fn get_leaderboard(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    rank: fn(&[Score]) -> Leaderboard,
+    query: PlaylistQuery,
+) -> Result<Response<Body>, Error> {
+    let conditions = match &query.playlist {
+        Some(id) => {
+            let Some(playlist) = get_playlist(db.clone(), &user_id, id).await? else {
+                return not_found();
+            };
+            if playlist.tracks.is_empty() {
+                return get_response_builder()
+                    .body(Body::from(serde_json::to_string(&Leaderboard::default())?))
+                    .map_err(Error::from);
+            }
+            playlist_score_conditions(&user_id, &playlist)
+        }
+        // Like the scores page, only the shared scores
+        None => vec![
+            format!("c.user_id = {}", search::literal(&user_id)),
+            score_scope(None),
+        ],
+    };
+    let scores: Vec<Score> = query_documents(
+        db.into_collection_client("scores"),
+        &session,
+        &format!("SELECT * FROM c WHERE {}", conditions.join(" AND ")),
+    )
+    .await?;
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&rank(&scores))?))
+        .map_err(Error::from)
+}
@@ -0,0 +1,112 @@
+use crate::{delete_document, get_playlist, query_documents, scope_condition, search, Error};
+use async_trait::async_trait;
+use azure_core::Context;
+use azure_data_cosmos::prelude::{
+    ConsistencyLevel, CreateDocumentOptions, DatabaseClient, GetDocumentOptions,
+    GetDocumentResponse, ReplaceDocumentOptions,
+};
+use songsort::history::Match;
+use songsort::{Playlist, Score};
+use songsort_web::matches::Store;
+use std::sync::{Arc, RwLock};
+
+/// Keeps playlists, scores and matches in their Cosmos containers.
+pub struct CosmosStore {
+    pub db: DatabaseClient,
+    pub session: Arc<RwLock<Option<ConsistencyLevel>>>,
+}
+
+impl CosmosStore {
+    fn session(&self) -> ConsistencyLevel {
+        self.session
+            .read()
+            .unwrap()
+            .clone()
+            .expect("session should be set by get_scores")
+    }
+}
+
+#[async_trait]
+impl Store for CosmosStore {
+    type Error = Error;
+
+    async fn get_playlist(&self, user_id: &str, id: &str) -> Result<Option<Playlist>, Error> {
+        get_playlist(self.db.clone(), user_id, id).await
+    }
+
+    async fn get_scores(
+        &self,
+        user_id: &str,
+        playlist_id: Option<&str>,
+        track_ids: &[&str],
+    ) -> Result<Vec<Score>, Error> {
+        query_documents(
+            self.db.clone().into_collection_client("scores"),
+            &self.session,
+            &format!(
+                "SELECT * FROM c WHERE c.user_id = {} AND {} AND c.track_id IN ({})",
+                search::literal(user_id),
+                scope_condition(playlist_id),
+                track_ids
+                    .iter()
+                    .map(|t| search::literal(t))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
+        .await
+    }
+
+    async fn replace_score(&self, score: &Score) -> Result<(), Error> {
+        self.db
+            .clone()
+            .into_collection_client("scores")
+            .into_document_client(score.id.clone(), &score.user_id)?
+            .replace_document(
+                Context::new(),
+                score,
+                ReplaceDocumentOptions::new().consistency_level(self.session()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_match(&self, user_id: &str, id: &str) -> Result<Option<Match>, Error> {
+        let client = self
+            .db
+            .clone()
+            .into_collection_client("matches")
+            .into_document_client(id, &user_id)?;
+        if let GetDocumentResponse::Found(played) = client
+            .get_document::<Match>(Context::new(), GetDocumentOptions::new())
+            .await?
+        {
+            Ok(Some(played.document.document))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn create_match(&self, played: &Match) -> Result<(), Error> {
+        self.db
+            .clone()
+            .into_collection_client("matches")
+            .create_document(
+                Context::new(),
+                played,
+                CreateDocumentOptions::new().consistency_level(self.session()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_match(&self, user_id: &str, id: &str) -> Result<(), Error> {
+        delete_document(
+            self.db.clone().into_collection_client("matches"),
+            &self.session,
+            id,
+            user_id,
+        )
+        .await
+    }
+}
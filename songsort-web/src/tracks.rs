@@ -0,0 +1,193 @@
+use crate::{
+    get_playlist, get_response_builder, get_score_docs, not_found, query_documents, score_scope,
+    search, Error,
+};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use hyper::{Body, Response};
+use serde::Deserialize;
+use songsort::api::PlaylistQuery;
+use songsort::history::{self, Match};
+use songsort::{Playlist, PlaylistRank, RatingScope, Score, TrackDetails};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// How many opponents are listed on a track's page.
+const MAX_OPPONENTS: usize = 10;
+
+/// Just enough of a score to rank it.
+#[derive(Debug, Deserialize)]
+struct Rating {
+    track_id: String,
+    score: i32,
+    #[serde(default)]
+    playlist_id: Option<String>,
+}
+
+/// Get a track's score, its rank in each playlist that has it and its most played opponents.
+/// With a playlist, the score and opponents are the track's in that playlist.
+pub async fn get_track(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    track_id: &str,
+    query: PlaylistQuery,
+) -> Result<Response<Body>, Error> {
+    let found = get_track_score(&db, &session, &user_id, track_id, &query).await?;
+    let Some((scope, score)) = found else {
+        return not_found();
+    };
+    let playlists: Vec<Playlist> = query_documents(
+        db.clone().into_collection_client("playlists"),
+        &session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {} AND ARRAY_CONTAINS(c.tracks, {})",
+            search::literal(&user_id),
+            search::literal(track_id)
+        ),
+    )
+    .await?;
+    let ratings = get_ratings(&db, &session, &user_id, &playlists).await?;
+    let mut ranks = Vec::new();
+    for playlist in playlists {
+        let scope = match playlist.rating_scope {
+            RatingScope::Global => None,
+            RatingScope::Playlist => Some(&playlist.id),
+        };
+        let tracks: HashSet<_> = playlist.tracks.iter().collect();
+        let ratings: Vec<_> = ratings
+            .iter()
+            .filter(|r| r.playlist_id.as_ref() == scope && tracks.contains(&r.track_id))
+            .collect();
+        let Some(own) = ratings.iter().find(|r| r.track_id == track_id) else {
+            continue;
+        };
+        ranks.push(PlaylistRank {
+            rank: 1 + ratings.iter().filter(|r| r.score > own.score).count(),
+            tracks: ratings.len(),
+            score: own.score,
+            id: playlist.id,
+            name: playlist.name,
+        });
+    }
+    ranks.sort_by(|a, b| a.name.cmp(&b.name));
+    let matches = get_matches(&db, &session, &user_id, scope.as_ref(), track_id).await?;
+    let mut opponents = history::head_to_head(track_id, &matches);
+    opponents.truncate(MAX_OPPONENTS);
+    let details = TrackDetails {
+        score,
+        playlists: ranks,
+        opponents,
+    };
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&details)?))
+        .map_err(Error::from)
+}
+
+/// Get a track's rating after each of its matches. With a playlist, the track's rating in that
+/// playlist is followed.
+pub async fn get_history(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    track_id: &str,
+    query: PlaylistQuery,
+) -> Result<Response<Body>, Error> {
+    let found = get_track_score(&db, &session, &user_id, track_id, &query).await?;
+    let Some((playlist, score)) = found else {
+        return not_found();
+    };
+    let matches = get_matches(&db, &session, &user_id, playlist.as_ref(), track_id).await?;
+    let history = history::rating_history(score, &matches);
+    get_response_builder()
+        .body(Body::from(serde_json::to_string(&history)?))
+        .map_err(Error::from)
+}
+
+/// Get the playlist that a request is scoped to and the track's score in that scope, if both
+/// exist.
+async fn get_track_score(
+    db: &DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+    track_id: &str,
+    query: &PlaylistQuery,
+) -> Result<Option<(Option<Playlist>, Score)>, Error> {
+    let playlist = match &query.playlist {
+        Some(id) => match get_playlist(db.clone(), user_id, id).await? {
+            Some(playlist) => Some(playlist),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    let scores = get_score_docs(
+        db.clone().into_collection_client("scores"),
+        session,
+        user_id.to_owned(),
+        playlist.as_ref(),
+        &[track_id],
+    )
+    .await?;
+    Ok(scores.into_iter().next().map(|score| (playlist, score)))
+}
+
+/// Get the ratings of every track in the playlists, in one query across their rating scopes.
+async fn get_ratings(
+    db: &DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+    playlists: &[Playlist],
+) -> Result<Vec<Rating>, Error> {
+    if playlists.is_empty() {
+        return Ok(Vec::new());
+    }
+    let tracks: HashSet<_> = playlists.iter().flat_map(|p| &p.tracks).collect();
+    let scoped: Vec<_> = playlists
+        .iter()
+        .filter(|p| p.rating_scope == RatingScope::Playlist)
+        .map(|p| search::literal(&p.id))
+        .collect();
+    let mut scopes = String::from("NOT IS_DEFINED(c.playlist_id)");
+    if !scoped.is_empty() {
+        scopes.push_str(&format!(" OR c.playlist_id IN ({})", scoped.join(",")));
+    }
+    query_documents(
+        db.clone().into_collection_client("scores"),
+        session,
+        &format!(
+            "SELECT c.track_id, c.score, c.playlist_id FROM c WHERE c.user_id = {} AND \
+             c.track_id IN ({}) AND ({})",
+            search::literal(user_id),
+            tracks
+                .into_iter()
+                .map(|t| search::literal(t))
+                .collect::<Vec<_>>()
+                .join(","),
+            scopes
+        ),
+    )
+    .await
+}
+
+/// Get the matches that a track played in a playlist's rating scope, or with its shared score.
+async fn get_matches(
+    db: &DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+    playlist: Option<&Playlist>,
+    track_id: &str,
+) -> Result<Vec<Match>, Error> {
+    let track_id = search::literal(track_id);
+    query_documents(
+        db.clone().into_collection_client("matches"),
+        session,
+        &format!(
+            "SELECT * FROM c WHERE c.user_id = {} AND {} AND \
+             (c.winner.track_id = {} OR c.loser.track_id = {})",
+            search::literal(user_id),
+            score_scope(playlist),
+            track_id,
+            track_id
+        ),
+    )
+    .await
+}
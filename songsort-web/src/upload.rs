@@ -0,0 +1,425 @@
+use crate::provider::{Collection, Track};
+use crate::{bad_request, create_playlist, get_response_builder, Error};
+use azure_data_cosmos::prelude::{ConsistencyLevel, DatabaseClient};
+use hyper::{Body, Request, Response, StatusCode};
+use multer::{Constraints, Multipart, SizeLimit};
+use songsort::{Playlists, ProviderId};
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+/// The provider of tracks and playlists read from files.
+pub const LOCAL: &str = "local";
+
+/// Playlist files are small, so anything bigger than this is probably a mistake.
+const MAX_UPLOAD: u64 = 4 * 1024 * 1024;
+
+/// A track read from a playlist file.
+#[derive(Debug, Default, PartialEq)]
+struct LocalTrack {
+    title: String,
+    artists: Vec<String>,
+    album: Option<String>,
+}
+
+impl LocalTrack {
+    fn into_track(self) -> Track {
+        Track {
+            id: local_track_id(&self.title, &self.artists, self.album.as_deref()),
+            name: self.title,
+            album: self.album.unwrap_or_default(),
+            artists: self.artists,
+            release_year: None,
+            artwork_url: None,
+            duration_ms: None,
+            isrc: None,
+        }
+    }
+}
+
+/// Create a playlist for every file in a `multipart/form-data` upload. Nothing is saved unless
+/// every file can be read.
+pub async fn upload_playlists(
+    db: DatabaseClient,
+    session: Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: String,
+    ttl: Option<i32>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    let Some(boundary) = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| multer::parse_boundary(v).ok())
+    else {
+        return bad_request();
+    };
+    let mut multipart = Multipart::with_constraints(
+        req.into_body(),
+        boundary,
+        Constraints::new().size_limit(SizeLimit::new().whole_stream(MAX_UPLOAD)),
+    );
+    let mut collections = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        let Some(file_name) = field.file_name().map(str::to_owned) else {
+            continue;
+        };
+        let contents = field.bytes().await?;
+        let contents = String::from_utf8_lossy(&contents);
+        let Some(collection) = read_playlist(&file_name, &contents) else {
+            return bad_request();
+        };
+        collections.push(collection);
+    }
+    if collections.is_empty() {
+        return bad_request();
+    }
+    let mut playlists = Vec::new();
+    for collection in collections {
+        let (playlist, scores) = collection.into_playlist(&user_id, ttl);
+        create_playlist(
+            db.clone(),
+            Arc::clone(&session),
+            playlist.clone(),
+            scores,
+            false,
+        )
+        .await?;
+        playlists.push(playlist);
+    }
+    get_response_builder()
+        .status(StatusCode::CREATED)
+        .body(Body::from(serde_json::to_string(&Playlists {
+            items: playlists,
+        })?))
+        .map_err(Error::from)
+}
+
+/// Read a playlist file with at least one track. Every upload gets its own id, because files from
+/// different folders often have the same name.
+fn read_playlist(file_name: &str, contents: &str) -> Option<Collection> {
+    let (name, tracks) = parse(file_name, contents)?;
+    if tracks.is_empty() {
+        return None;
+    }
+    Some(Collection {
+        id: ProviderId::new(LOCAL, &Uuid::new_v4().to_simple().to_string()),
+        name,
+        tracks: tracks.into_iter().map(LocalTrack::into_track).collect(),
+    })
+}
+
+/// Read a playlist file based on its extension, returning its title and tracks.
+fn parse(file_name: &str, contents: &str) -> Option<(String, Vec<LocalTrack>)> {
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    let (title, tracks) = match extension.to_lowercase().as_str() {
+        "m3u" | "m3u8" => parse_m3u(contents),
+        "pls" => parse_pls(contents),
+        "xspf" => parse_xspf(contents)?,
+        _ => return None,
+    };
+    Some((title.unwrap_or_else(|| stem.to_owned()), tracks))
+}
+
+/// Extended M3U files describe each entry with `#EXTINF:<seconds>,<artist> - <title>`. Plain M3U
+/// files only list paths, so the metadata has to come from the file name.
+fn parse_m3u(contents: &str) -> (Option<String>, Vec<LocalTrack>) {
+    let mut title = None;
+    let mut tracks = Vec::new();
+    let mut info = None;
+    let mut album = None;
+    for line in contents.lines().map(str::trim) {
+        if let Some(playlist) = line.strip_prefix("#PLAYLIST:") {
+            title = Some(playlist.trim().to_owned());
+        } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = extinf
+                .split_once(',')
+                .map(|(_, info)| info.trim().to_owned());
+        } else if let Some(extalb) = line.strip_prefix("#EXTALB:") {
+            album = Some(extalb.trim().to_owned());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let info = info.take().unwrap_or_else(|| file_stem(line).to_owned());
+            tracks.push(LocalTrack {
+                album: album.take(),
+                ..split_artist(&info)
+            });
+        }
+    }
+    (title, tracks)
+}
+
+/// PLS files are INI files with numbered `FileN` and `TitleN` keys.
+fn parse_pls(contents: &str) -> (Option<String>, Vec<LocalTrack>) {
+    let mut files = Vec::new();
+    let mut titles = std::collections::HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        if let Some(n) = key.strip_prefix("file") {
+            files.push((n.to_owned(), value.trim().to_owned()));
+        } else if let Some(n) = key.strip_prefix("title") {
+            titles.insert(n.to_owned(), value.trim().to_owned());
+        }
+    }
+    let tracks = files
+        .into_iter()
+        .map(|(n, location)| {
+            let info = titles
+                .remove(&n)
+                .unwrap_or_else(|| file_stem(&location).to_owned());
+            split_artist(&info)
+        })
+        .collect();
+    (None, tracks)
+}
+
+/// XSPF is the only format with separate title, creator and album fields.
+fn parse_xspf(contents: &str) -> Option<(Option<String>, Vec<LocalTrack>)> {
+    let document = roxmltree::Document::parse(contents).ok()?;
+    let root = document.root_element();
+    if root.tag_name().name() != "playlist" {
+        return None;
+    }
+    let text = |node: roxmltree::Node, name: &str| {
+        node.children()
+            .find(|n| n.tag_name().name() == name)
+            .and_then(|n| n.text())
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_owned)
+    };
+    let title = text(root, "title");
+    let tracks = root
+        .children()
+        .filter(|n| n.tag_name().name() == "trackList")
+        .flat_map(|n| n.children())
+        .filter(|n| n.tag_name().name() == "track")
+        .filter_map(|track| {
+            let title = text(track, "title").or_else(|| {
+                text(track, "location").map(|l| file_stem(&percent_decode(&l)).to_owned())
+            })?;
+            Some(LocalTrack {
+                title,
+                artists: text(track, "creator").into_iter().collect(),
+                album: text(track, "album"),
+            })
+        })
+        .collect();
+    Some((title, tracks))
+}
+
+/// Split `Artist - Title`, which is how most players label tracks.
+fn split_artist(info: &str) -> LocalTrack {
+    match info.split_once(" - ") {
+        Some((artist, title)) if !artist.trim().is_empty() && !title.trim().is_empty() => {
+            LocalTrack {
+                title: title.trim().to_owned(),
+                artists: vec![artist.trim().to_owned()],
+                ..LocalTrack::default()
+            }
+        }
+        _ => LocalTrack {
+            title: info.trim().to_owned(),
+            ..LocalTrack::default()
+        },
+    }
+}
+
+/// XSPF locations are URIs, so `Song%20B.flac` is the file `Song B.flac`. Malformed escapes are
+/// kept as they are.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(hex)) => {
+                decoded.push(u8::from_str_radix(hex, 16).expect("hex digits parse"));
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn file_stem(location: &str) -> &str {
+    let file_name = location
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or(location);
+    file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem)
+}
+
+/// Tracks are identified by their metadata rather than their location, so the same song keeps its
+/// score across playlists and machines.
+pub fn local_track_id(title: &str, artists: &[String], album: Option<&str>) -> ProviderId {
+    let mut key = title.trim().to_lowercase();
+    for artist in artists {
+        key.push('\u{1f}');
+        key.push_str(&artist.trim().to_lowercase());
+    }
+    key.push('\u{1f}');
+    if let Some(album) = album {
+        key.push_str(&album.trim().to_lowercase());
+    }
+    ProviderId::new(LOCAL, &hash(&key))
+}
+
+/// Ids have to be the same across processes and releases, so use FNV-1a instead of the standard
+/// library's hasher.
+pub fn hash(key: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, artist: Option<&str>, album: Option<&str>) -> LocalTrack {
+        LocalTrack {
+            title: title.to_owned(),
+            artists: artist.into_iter().map(str::to_owned).collect(),
+            album: album.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn extended_m3u() {
+        let contents = "#EXTM3U\n\
+            #PLAYLIST:Road Trip\n\
+            #EXTINF:215,Queen - Don't Stop Me Now\n\
+            #EXTALB:Jazz\n\
+            music/queen/dont_stop_me_now.mp3\n\
+            \n\
+            #EXTINF:-1,Interlude\n\
+            C:\\Music\\interlude.flac\n";
+        assert_eq!(
+            parse("trip.m3u8", contents),
+            Some((
+                String::from("Road Trip"),
+                vec![
+                    track("Don't Stop Me Now", Some("Queen"), Some("Jazz")),
+                    track("Interlude", None, None),
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn plain_m3u_uses_file_names() {
+        let contents = "/music/Daft Punk - One More Time.mp3\r\nC:\\Music\\Intro.ogg\r\n";
+        assert_eq!(
+            parse("Mix.M3U", contents),
+            Some((
+                String::from("Mix"),
+                vec![
+                    track("One More Time", Some("Daft Punk"), None),
+                    track("Intro", None, None),
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn pls() {
+        let contents = "[playlist]\n\
+            File1=/music/a.mp3\n\
+            Title1=Artist A - Song A\n\
+            File2=/music/Song B.mp3\n\
+            NumberOfEntries=2\n";
+        assert_eq!(
+            parse("list.pls", contents),
+            Some((
+                String::from("list"),
+                vec![
+                    track("Song A", Some("Artist A"), None),
+                    track("Song B", None, None),
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn xspf() {
+        let contents = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <title>Favourites</title>
+              <trackList>
+                <track>
+                  <title>Song A</title>
+                  <creator>Artist A</creator>
+                  <album>Album A</album>
+                </track>
+                <track>
+                  <location>file:///music/Song%20B.flac</location>
+                </track>
+                <track><creator>No Title</creator></track>
+              </trackList>
+            </playlist>"#;
+        assert_eq!(
+            parse("favs.xspf", contents),
+            Some((
+                String::from("Favourites"),
+                vec![
+                    track("Song A", Some("Artist A"), Some("Album A")),
+                    track("Song B", None, None),
+                ]
+            ))
+        );
+        assert_eq!(parse("favs.xspf", "<html></html>"), None);
+        assert_eq!(parse("favs.xspf", "not xml"), None);
+    }
+
+    #[test]
+    fn locations_are_percent_decoded() {
+        assert_eq!(percent_decode("Caf%C3%A9%20Song"), "Café Song");
+        assert_eq!(percent_decode("100%25"), "100%");
+        assert_eq!(percent_decode("50% off%2"), "50% off%2");
+        assert_eq!(percent_decode("%zz%"), "%zz%");
+    }
+
+    #[test]
+    fn unknown_files() {
+        assert_eq!(parse("notes.txt", "a - b"), None);
+        assert_eq!(parse("no_extension", "a - b"), None);
+        assert!(read_playlist("empty.m3u", "#EXTM3U\n").is_none());
+    }
+
+    #[test]
+    fn uploads_with_the_same_name_are_separate() {
+        let a = read_playlist("mix.m3u", "a.mp3").unwrap();
+        let b = read_playlist("mix.m3u", "b.mp3").unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.id.provider, LOCAL);
+    }
+
+    #[test]
+    fn track_ids_ignore_case_and_location() {
+        let a = track("Song", Some("Artist"), None).into_track();
+        let b = track("song ", Some("ARTIST"), None).into_track();
+        assert_eq!(a.id, b.id);
+        assert_ne!(
+            a.id,
+            track("Song", Some("Artist"), Some("Album")).into_track().id
+        );
+        // Ids are stored, so the hash can never change
+        assert_eq!(hash(""), "cbf29ce484222325");
+        assert_eq!(hash("a"), "af63dc4c8601ec8c");
+    }
+}
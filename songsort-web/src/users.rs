@@ -0,0 +1,138 @@
+use crate::spotify::{self, Spotify};
+use crate::{query_documents, search, Error};
+use azure_core::Context;
+use azure_data_cosmos::prelude::{
+    ConsistencyLevel, CosmosEntity, CreateDocumentOptions, DatabaseClient,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Access tokens are refreshed this many seconds before Spotify says they expire, so that they
+/// don't expire during a request.
+const EXPIRY_MARGIN: u64 = 60;
+
+/// A Spotify user's tokens.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct User {
+    pub id: String,
+    pub user_id: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Unix timestamp in seconds. Users saved before tokens were refreshed don't have one, so
+    /// their token is refreshed the first time it's used.
+    #[serde(default)]
+    pub expires: u64,
+}
+
+impl<'a> CosmosEntity<'a> for User {
+    type Entity = &'a str;
+
+    fn partition_key(&'a self) -> Self::Entity {
+        self.user_id.as_ref()
+    }
+}
+
+/// Exchange the authorization code that a user logged in with and keep their tokens. The code
+/// itself isn't kept, since later requests use the session from logging in.
+pub async fn login(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    spotify: &Spotify,
+    code: &str,
+    origin: &str,
+) -> Result<User, Error> {
+    let token = spotify.exchange_code(code, origin).await?;
+    let me: songsort_web::User = spotify::get(
+        "https://api.spotify.com/v1/me".parse()?,
+        &token.access_token,
+    )
+    .await?;
+    let existing = find(db.clone(), session, &me.id).await?;
+    let user = User {
+        id: existing.map_or_else(|| Uuid::new_v4().to_hyphenated().to_string(), |u| u.id),
+        user_id: me.id,
+        access_token: token.access_token,
+        refresh_token: token.refresh_token.ok_or(Error::MissingRefreshToken)?,
+        expires: now() + token.expires_in,
+    };
+    save(db, session, &user).await?;
+    Ok(user)
+}
+
+/// Get a user's access token, refreshing it with their refresh token once it has expired.
+///
+/// Fails with `Error::SpotifyError(StatusCode::UNAUTHORIZED)` if the user has to log in with
+/// Spotify again.
+pub async fn access_token(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    spotify: &Spotify,
+    user_id: &str,
+) -> Result<String, Error> {
+    let Some(mut user) = find(db.clone(), session, user_id).await? else {
+        return Err(Error::SpotifyError(StatusCode::UNAUTHORIZED));
+    };
+    if user.expires > now() + EXPIRY_MARGIN {
+        return Ok(user.access_token);
+    }
+    let token = spotify.refresh_token(&user.refresh_token).await?;
+    user.access_token = token.access_token;
+    // Spotify may hand out a new refresh token, otherwise the old one keeps working
+    if let Some(refresh_token) = token.refresh_token {
+        user.refresh_token = refresh_token;
+    }
+    user.expires = now() + token.expires_in;
+    save(db, session, &user).await?;
+    Ok(user.access_token)
+}
+
+/// Find a user's tokens. Users who logged in before their tokens were kept by user have a
+/// document for every login, so the newest one is used.
+async fn find(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user_id: &str,
+) -> Result<Option<User>, Error> {
+    let users: Vec<User> = query_documents(
+        db.into_collection_client("users"),
+        session,
+        &format!(
+            "SELECT TOP 1 * FROM c WHERE c.user_id = {} ORDER BY c._ts DESC",
+            search::literal(user_id)
+        ),
+    )
+    .await?;
+    Ok(users.into_iter().next())
+}
+
+async fn save(
+    db: DatabaseClient,
+    session: &Arc<RwLock<Option<ConsistencyLevel>>>,
+    user: &User,
+) -> Result<(), Error> {
+    let session_copy = session
+        .read()
+        .unwrap()
+        .clone()
+        .expect("session should be set by query_documents");
+    db.into_collection_client("users")
+        .create_document(
+            Context::new(),
+            user,
+            CreateDocumentOptions::new()
+                .is_upsert(true)
+                .consistency_level(session_copy),
+        )
+        .await?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time should be after the epoch")
+        .as_secs()
+}
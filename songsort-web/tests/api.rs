@@ -0,0 +1,401 @@
+//! Plays and takes back matches through the typed API client that the frontend uses. Requests are
+//! sent over HTTP to a server that routes and authenticates them with the server's router and
+//! plays them with its match handlers, with scores kept in memory.
+
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use songsort::api::{self, Api, ApiError, EloQuery};
+use songsort::history::Match;
+use songsort::{Playlist, RatingScope, Score};
+use songsort_web::matches::{self, Store};
+use songsort_web::router::{self, Credentials, Route};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+const USER: &str = "user";
+const SESSION: &str = "session";
+
+/// Keeps a user's playlists, scores and matches in memory.
+#[derive(Default)]
+struct MemoryStore {
+    playlists: Vec<Playlist>,
+    scores: Mutex<Vec<Score>>,
+    matches: Mutex<HashMap<String, Match>>,
+    /// How many more scores can be replaced before replacing one fails, like when the connection
+    /// drops part way through a request
+    failing_after: Mutex<Option<usize>>,
+}
+
+#[derive(Debug)]
+struct Unavailable;
+
+#[async_trait]
+impl Store for MemoryStore {
+    type Error = Unavailable;
+
+    async fn get_playlist(&self, user_id: &str, id: &str) -> Result<Option<Playlist>, Unavailable> {
+        Ok(self
+            .playlists
+            .iter()
+            .find(|p| p.user_id == user_id && p.id == id)
+            .cloned())
+    }
+
+    async fn get_scores(
+        &self,
+        user_id: &str,
+        playlist_id: Option<&str>,
+        track_ids: &[&str],
+    ) -> Result<Vec<Score>, Unavailable> {
+        Ok(self
+            .scores
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.user_id == user_id && s.playlist_id.as_deref() == playlist_id)
+            .filter(|s| track_ids.contains(&s.track_id.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    async fn replace_score(&self, score: &Score) -> Result<(), Unavailable> {
+        let mut failing_after = self.failing_after.lock().unwrap();
+        match *failing_after {
+            Some(0) => {
+                *failing_after = None;
+                return Err(Unavailable);
+            }
+            Some(n) => *failing_after = Some(n - 1),
+            None => {}
+        }
+        let mut scores = self.scores.lock().unwrap();
+        let stored = scores
+            .iter_mut()
+            .find(|s| s.id == score.id)
+            .expect("only stored scores are replaced");
+        *stored = score.clone();
+        Ok(())
+    }
+
+    async fn get_match(&self, user_id: &str, id: &str) -> Result<Option<Match>, Unavailable> {
+        let matches = self.matches.lock().unwrap();
+        Ok(matches.get(id).filter(|m| m.user_id == user_id).cloned())
+    }
+
+    async fn create_match(&self, played: &Match) -> Result<(), Unavailable> {
+        let mut matches = self.matches.lock().unwrap();
+        matches.insert(played.id.clone(), played.clone());
+        Ok(())
+    }
+
+    async fn delete_match(&self, _: &str, id: &str) -> Result<(), Unavailable> {
+        self.matches.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+impl MemoryStore {
+    /// A playlist of three tracks that rates them separately, with shared scores of the same
+    /// tracks that its matches must leave alone.
+    fn new() -> MemoryStore {
+        let tracks = ["a", "b", "c"];
+        let playlist = Playlist {
+            id: String::from("playlist"),
+            playlist_id: String::from("spotify:playlist"),
+            name: String::from("Playlist"),
+            user_id: String::from(USER),
+            tracks: tracks.iter().map(|&t| t.to_owned()).collect(),
+            archived: Vec::new(),
+            published_id: None,
+            rating_scope: RatingScope::Playlist,
+            ttl: None,
+        };
+        let mut scores = Vec::new();
+        for track in tracks {
+            let shared = score(track);
+            scores.push(playlist.scope_score(shared.clone()));
+            scores.push(shared);
+        }
+        MemoryStore {
+            playlists: vec![playlist],
+            scores: Mutex::new(scores),
+            ..MemoryStore::default()
+        }
+    }
+
+    fn score(&self, track_id: &str, playlist_id: Option<&str>) -> Score {
+        self.scores
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.track_id == track_id && s.playlist_id.as_deref() == playlist_id)
+            .cloned()
+            .expect("every track has a score")
+    }
+}
+
+fn score(track_id: &str) -> Score {
+    Score {
+        id: track_id.to_owned(),
+        track_id: track_id.to_owned(),
+        track: track_id.to_uppercase(),
+        album: String::new(),
+        artists: Vec::new(),
+        user_id: String::from(USER),
+        score: 1500,
+        wins: 0,
+        losses: 0,
+        games: 0,
+        release_year: None,
+        artwork_url: None,
+        duration_ms: None,
+        isrc: None,
+        playlist_id: None,
+        merged: Vec::new(),
+        last_match: None,
+        ttl: None,
+    }
+}
+
+/// Answers the match endpoints the way the server does. Only the session `SESSION` is signed in,
+/// as `USER`.
+async fn handle(store: &MemoryStore, req: Request<Body>) -> Response<Body> {
+    match router::credentials(req.headers()) {
+        Ok(Credentials::Session(session)) if session == SESSION => {}
+        _ => return status(StatusCode::UNAUTHORIZED),
+    }
+    let Some(path) = req.uri().path().strip_prefix("/api/") else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let handled = match Route::recognize(req.method(), path) {
+        Ok(Route::Elo) => match router::query::<EloQuery>(req.uri().query()) {
+            Ok(query) => matches::play(store, USER, &query)
+                .await
+                .map(|played| Body::from(serde_json::to_string(&played).unwrap())),
+            Err(_) => return status(StatusCode::BAD_REQUEST),
+        },
+        Ok(Route::UndoMatch { id }) => matches::undo(store, USER, &id)
+            .await
+            .map(|()| Body::empty()),
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+    match handled {
+        Ok(body) => Response::new(body),
+        Err(e) => status(e.status()),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Start a server for `store` on a free port.
+fn serve(store: Arc<MemoryStore>) -> SocketAddr {
+    let make_service = make_service_fn(move |_| {
+        let store = Arc::clone(&store);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let store = Arc::clone(&store);
+                async move { Ok::<_, Infallible>(handle(&store, req).await) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// Sends API requests over HTTP, the way the browser does for the frontend.
+struct HyperClient {
+    client: Client<HttpConnector>,
+    /// Where the server is, like `http://127.0.0.1:3000`
+    base: String,
+    /// The Authorization header
+    auth: String,
+}
+
+#[async_trait(?Send)]
+impl Api for HyperClient {
+    type Error = hyper::Error;
+
+    async fn send(&self, request: api::Request) -> Result<api::Response, hyper::Error> {
+        let method = match request.method {
+            api::Method::Get => Method::GET,
+            api::Method::Post => Method::POST,
+            api::Method::Delete => Method::DELETE,
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, request.url))
+            .header("Authorization", &self.auth)
+            .body(request.body.map_or_else(Body::empty, Body::from))
+            .expect("API requests are valid HTTP requests");
+        let response = self.client.request(request).await?;
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(api::Response {
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
+
+/// Start a server with the playlist of `MemoryStore::new` and a client that's signed in to it.
+fn start() -> (HyperClient, Arc<MemoryStore>) {
+    let store = Arc::new(MemoryStore::new());
+    let addr = serve(Arc::clone(&store));
+    let api = HyperClient {
+        client: Client::new(),
+        base: format!("http://{}", addr),
+        auth: format!("Bearer {}", SESSION),
+    };
+    (api, store)
+}
+
+fn elo(win: &str, lose: &str) -> EloQuery {
+    EloQuery {
+        win: win.to_owned(),
+        lose: lose.to_owned(),
+        playlist: Some(String::from("playlist")),
+        draw: false,
+    }
+}
+
+#[tokio::test]
+async fn play_and_undo_match() {
+    let (api, store) = start();
+    let played = api.elo(&elo("a", "b")).await.unwrap();
+    let (win, lose) = (
+        store.score("a", Some("playlist")),
+        store.score("b", Some("playlist")),
+    );
+    assert_eq!((win.wins, win.games, win.score), (1, 1, 1516));
+    assert_eq!((lose.losses, lose.games, lose.score), (1, 1, 1484));
+    assert_eq!(win.last_match.as_ref(), Some(&played.id));
+    // The playlist rates its tracks separately
+    assert_eq!(store.score("a", None).wins, 0);
+
+    api.undo_match(&played.id).await.unwrap();
+    for track in ["a", "b"] {
+        let undone = store.score(track, Some("playlist"));
+        assert_eq!((undone.score, undone.games), (1500, 0));
+        assert_eq!(undone.last_match, None);
+    }
+    assert!(store.matches.lock().unwrap().is_empty());
+    assert_eq!(
+        api.undo_match(&played.id).await.unwrap_err().status(),
+        Some(404)
+    );
+}
+
+#[tokio::test]
+async fn later_matches_are_undone_first() {
+    let (api, store) = start();
+    let first = api.elo(&elo("a", "b")).await.unwrap();
+    let second = api.elo(&elo("c", "a")).await.unwrap();
+    assert!(matches!(
+        api.undo_match(&first.id).await,
+        Err(ApiError::Status(409))
+    ));
+    assert_eq!(store.score("b", Some("playlist")).losses, 1);
+
+    api.undo_match(&second.id).await.unwrap();
+    api.undo_match(&first.id).await.unwrap();
+    let a = store.score("a", Some("playlist"));
+    assert_eq!((a.score, a.wins, a.losses), (1500, 0, 0));
+}
+
+#[tokio::test]
+async fn failed_undos_can_be_sent_again() {
+    let (api, store) = start();
+    let played = api.elo(&elo("a", "b")).await.unwrap();
+    // Only the winner is reverted before the request fails
+    *store.failing_after.lock().unwrap() = Some(1);
+    assert_eq!(
+        api.undo_match(&played.id).await.unwrap_err().status(),
+        Some(500)
+    );
+    assert_eq!(store.score("a", Some("playlist")).wins, 0);
+    assert_eq!(store.score("b", Some("playlist")).losses, 1);
+
+    api.undo_match(&played.id).await.unwrap();
+    for track in ["a", "b"] {
+        let undone = store.score(track, Some("playlist"));
+        assert_eq!((undone.score, undone.wins, undone.losses), (1500, 0, 0));
+    }
+}
+
+#[tokio::test]
+async fn ids_are_encoded_in_paths() {
+    let (api, store) = start();
+    let mut played = api.elo(&elo("a", "b")).await.unwrap();
+    // Give the match an id that only survives the path if it's encoded
+    let id = String::from("a/b c?d%");
+    {
+        let mut matches = store.matches.lock().unwrap();
+        matches.remove(&played.id);
+        played.id = id.clone();
+        matches.insert(id.clone(), played);
+        for score in store.scores.lock().unwrap().iter_mut() {
+            if score.last_match.is_some() {
+                score.last_match = Some(id.clone());
+            }
+        }
+    }
+    api.undo_match(&id).await.unwrap();
+    assert_eq!(store.score("a", Some("playlist")).wins, 0);
+}
+
+#[tokio::test]
+async fn ids_are_encoded_in_queries() {
+    let (api, store) = start();
+    // A track id that only survives the query if it's encoded
+    let id = "x&lose=a #";
+    store.scores.lock().unwrap().push(Score {
+        playlist_id: Some(String::from("playlist")),
+        ..score(id)
+    });
+    let played = api.elo(&elo(id, "b")).await.unwrap();
+    assert_eq!(played.winner.track_id, id);
+    assert_eq!(store.score(id, Some("playlist")).wins, 1);
+}
+
+#[tokio::test]
+async fn requests_need_a_session() {
+    let (mut api, store) = start();
+    api.auth = String::from("Bearer expired");
+    assert_eq!(
+        api.elo(&elo("a", "b")).await.unwrap_err().status(),
+        Some(401)
+    );
+    assert!(store.matches.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn malformed_requests_are_refused() {
+    let (api, _store) = start();
+    // Both tracks need a score
+    assert_eq!(
+        api.elo(&elo("a", "z")).await.unwrap_err().status(),
+        Some(400)
+    );
+    assert_eq!(
+        api.elo(&EloQuery {
+            playlist: Some(String::from("missing")),
+            ..elo("a", "b")
+        })
+        .await
+        .unwrap_err()
+        .status(),
+        Some(404)
+    );
+    // Routes that this server doesn't answer aren't found
+    assert_eq!(api.get_playlists().await.unwrap_err().status(), Some(404));
+}
@@ -0,0 +1,265 @@
+//! Malformed paths, headers, query strings and bodies are turned away with an error instead of a
+//! panic.
+
+use hyper::header::{HeaderValue, AUTHORIZATION, COOKIE, REFERER};
+use hyper::{Body, HeaderMap, Method};
+use proptest::prelude::*;
+use songsort::api::{self, Action, ActionQuery, EloQuery, ImportSource, ScoresQuery};
+use songsort_web::router::{
+    self, BodyError, Credentials, CredentialsError, Route, RouteError, SESSION_COOKIE,
+};
+
+fn method() -> impl Strategy<Value = Method> {
+    prop_oneof![
+        Just(Method::GET),
+        Just(Method::POST),
+        Just(Method::DELETE),
+        Just(Method::PUT),
+        Just(Method::PATCH),
+        Just(Method::OPTIONS),
+    ]
+}
+
+fn header(name: hyper::header::HeaderName, value: &[u8]) -> Option<HeaderMap> {
+    let value = HeaderValue::from_bytes(value).ok()?;
+    let mut headers = HeaderMap::new();
+    headers.insert(name, value);
+    Some(headers)
+}
+
+proptest! {
+    #[test]
+    fn paths_never_panic(method in method(), path in ".*") {
+        let _ = Route::recognize(&method, &path);
+    }
+
+    #[test]
+    fn path_params_are_whole_segments(id in "[^/%]+") {
+        prop_assert_eq!(
+            Route::recognize(&Method::GET, &format!("tracks/{}", id)),
+            Ok(Route::GetTrack { id: id.clone() })
+        );
+        prop_assert_eq!(
+            Route::recognize(&Method::GET, &format!("playlists/{}/scores", id)),
+            Ok(Route::GetPlaylistScores { id })
+        );
+    }
+
+    #[test]
+    fn encoded_path_params_round_trip(id in ".+") {
+        prop_assert_eq!(
+            Route::recognize(&Method::GET, &format!("tracks/{}/history", api::segment(&id))),
+            Ok(Route::GetTrackHistory { id: id.clone() })
+        );
+        prop_assert_eq!(
+            Route::recognize(&Method::DELETE, &format!("matches/{}", api::segment(&id))),
+            Ok(Route::UndoMatch { id })
+        );
+    }
+
+    #[test]
+    fn unknown_segments_are_not_found(id in "[^/]*", rest in "[^/]+") {
+        prop_assume!(rest != "history");
+        prop_assert_eq!(
+            Route::recognize(&Method::GET, &format!("tracks/{}/{}", id, rest)),
+            Err(RouteError::NotFound)
+        );
+    }
+
+    #[test]
+    fn authorization_never_panics(value in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Some(headers) = header(AUTHORIZATION, &value) {
+            let _ = router::credentials(&headers);
+        }
+    }
+
+    #[test]
+    fn bearer_tokens_are_sessions(token in "[!-~]+") {
+        let headers = header(AUTHORIZATION, format!("Bearer {}", token).as_bytes()).unwrap();
+        prop_assert_eq!(router::credentials(&headers), Ok(Credentials::Session(token)));
+    }
+
+    #[test]
+    fn other_schemes_are_codes(scheme in "[A-Za-z]+", code in "[!-~]+") {
+        prop_assume!(scheme != "Bearer");
+        let headers = header(AUTHORIZATION, format!("{} {}", scheme, code).as_bytes()).unwrap();
+        prop_assert_eq!(router::credentials(&headers), Ok(Credentials::Code(code)));
+    }
+
+    #[test]
+    fn cookies_never_panic(value in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Some(headers) = header(COOKIE, &value) {
+            let _ = router::credentials(&headers);
+        }
+    }
+
+    #[test]
+    fn session_cookies_are_sessions(token in "[!-:<-~]+") {
+        let cookies = format!("theme=dark; {}={}", SESSION_COOKIE, token);
+        let headers = header(COOKIE, cookies.as_bytes()).unwrap();
+        prop_assert_eq!(router::credentials(&headers), Ok(Credentials::Session(token)));
+    }
+
+    #[test]
+    fn referers_never_panic(value in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Some(headers) = header(REFERER, &value) {
+            let _ = router::origin(&headers);
+        }
+    }
+
+    #[test]
+    fn queries_never_panic(query in ".*") {
+        let _ = router::query::<ScoresQuery>(Some(&query));
+        let _ = router::query::<EloQuery>(Some(&query));
+        let _ = router::query::<ActionQuery>(Some(&query));
+    }
+
+    #[test]
+    fn elo_queries_round_trip(
+        win in ".+",
+        lose in ".+",
+        playlist in proptest::option::of(".+"),
+        draw: bool,
+    ) {
+        let sent = EloQuery { win, lose, playlist, draw };
+        let encoded = serde_urlencoded::to_string(&sent).unwrap();
+        let got: EloQuery = router::query(Some(&encoded)).unwrap();
+        prop_assert_eq!(got.win, sent.win);
+        prop_assert_eq!(got.lose, sent.lose);
+        prop_assert_eq!(got.playlist, sent.playlist);
+        prop_assert_eq!(got.draw, sent.draw);
+    }
+}
+
+#[test]
+fn empty_params_are_not_found() {
+    assert_eq!(
+        Route::recognize(&Method::GET, "tracks/"),
+        Err(RouteError::NotFound)
+    );
+    assert_eq!(
+        Route::recognize(&Method::GET, "playlists//scores"),
+        Err(RouteError::NotFound)
+    );
+}
+
+#[test]
+fn malformed_encodings_are_not_found() {
+    for path in [
+        "tracks/%",
+        "tracks/a%2",
+        "tracks/%zz",
+        "tracks/%+1",
+        "tracks/%FF",
+    ] {
+        assert_eq!(
+            Route::recognize(&Method::GET, path),
+            Err(RouteError::NotFound),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        Route::recognize(&Method::GET, "tracks/a%2Fb%20c"),
+        Ok(Route::GetTrack {
+            id: String::from("a/b c")
+        })
+    );
+}
+
+#[test]
+fn wrong_methods_are_not_allowed() {
+    assert_eq!(
+        Route::recognize(&Method::PUT, "playlists"),
+        Err(RouteError::MethodNotAllowed)
+    );
+    assert_eq!(
+        Route::recognize(&Method::OPTIONS, "anything"),
+        Ok(Route::Preflight)
+    );
+}
+
+#[test]
+fn malformed_authorization() {
+    assert_eq!(
+        router::credentials(&HeaderMap::new()),
+        Err(CredentialsError::Missing)
+    );
+    for value in [&b"Bearer"[..], b"Bearer ", b"Basic \xff"] {
+        let headers = header(AUTHORIZATION, value).unwrap();
+        assert_eq!(
+            router::credentials(&headers),
+            Err(CredentialsError::Malformed)
+        );
+    }
+}
+
+#[test]
+fn authorization_comes_before_the_session_cookie() {
+    let mut headers = header(AUTHORIZATION, b"Basic code").unwrap();
+    let cookie = format!("{}=session", SESSION_COOKIE);
+    headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+    assert_eq!(
+        router::credentials(&headers),
+        Ok(Credentials::Code(String::from("code")))
+    );
+    headers.remove(AUTHORIZATION);
+    assert_eq!(
+        router::credentials(&headers),
+        Ok(Credentials::Session(String::from("session")))
+    );
+}
+
+#[test]
+fn other_cookies_are_missing_credentials() {
+    let cookie = format!(
+        "theme=dark; {}=; {}x=session",
+        SESSION_COOKIE, SESSION_COOKIE
+    );
+    let headers = header(COOKIE, cookie.as_bytes()).unwrap();
+    assert_eq!(
+        router::credentials(&headers),
+        Err(CredentialsError::Missing)
+    );
+}
+
+#[test]
+fn origin_of_referer() {
+    let headers = header(
+        REFERER,
+        b"http://localhost:3000/?code=abc#/playlists/1/match",
+    )
+    .unwrap();
+    assert_eq!(
+        router::origin(&headers).as_deref(),
+        Some("http://localhost:3000")
+    );
+    let headers = header(REFERER, b"/relative").unwrap();
+    assert_eq!(router::origin(&headers), None);
+}
+
+#[test]
+fn query_order_does_not_matter() {
+    let query: ActionQuery = router::query(Some("playlist=abc&action=import")).unwrap();
+    assert!(matches!(query.action, Action::Import));
+    assert!(matches!(query.source, ImportSource::Playlist(id) if id == "abc"));
+    assert!(router::query::<ActionQuery>(Some("action=import")).is_err());
+    assert!(router::query::<ActionQuery>(None).is_err());
+}
+
+#[tokio::test]
+async fn bodies_up_to_the_limit_are_read() {
+    // JSON allows any amount of whitespace around a value
+    let mut json = vec![b' '; router::MAX_BODY - 2];
+    json.extend_from_slice(b"[]");
+    let got: Vec<String> = router::body(Body::from(json)).await.unwrap();
+    assert!(got.is_empty());
+}
+
+#[tokio::test]
+async fn longer_bodies_are_too_large() {
+    let mut json = vec![b' '; router::MAX_BODY - 1];
+    json.extend_from_slice(b"[]");
+    let got = router::body::<Vec<String>>(Body::from(json)).await;
+    assert!(matches!(got, Err(BodyError::TooLarge)));
+}
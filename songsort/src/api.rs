@@ -0,0 +1,379 @@
+//! The HTTP API: the queries its endpoints take and a typed client for them.
+//!
+//! The client is shared by the frontend and native tests. Each only provides a way to send a
+//! request, so a change to an endpoint's shape breaks their build instead of a page.
+
+use crate::dedupe::{Duplicates, MergeRequest};
+use crate::history::{Match, RatingHistory};
+use crate::stats::Leaderboard;
+use crate::{
+    Clip, Library, Login, PlaylistSync, Playlists, ProviderId, PublishedPlaylist, RatingScope,
+    Score, Scores, TrackDetails,
+};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EloQuery {
+    pub win: String,
+    pub lose: String,
+    /// The playlist the match was played in, which decides whose scores change
+    pub playlist: Option<String>,
+    /// Whether neither track won, in which case `win` and `lose` are just the two tracks
+    #[serde(default)]
+    pub draw: bool,
+}
+
+/// Scopes a request to a playlist's tracks and their ratings in the playlist.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PlaylistQuery {
+    pub playlist: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Import,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ActionQuery {
+    pub action: Action,
+    /// Whether the imported tracks share their ratings with other playlists
+    #[serde(default)]
+    pub scope: RatingScope,
+    #[serde(flatten)]
+    pub source: ImportSource,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportSource {
+    Playlist(String),
+    Album(String),
+    Artist(String),
+    /// `saved=tracks` imports the user's Liked Songs
+    Saved(SavedItems),
+    /// `top=short_term` imports the user's top tracks over a time range
+    Top(TimeRange),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SavedItems {
+    Tracks,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeRange {
+    ShortTerm,
+    MediumTerm,
+    LongTerm,
+}
+
+impl TimeRange {
+    /// The name of the time range in queries and in the Spotify API.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimeRange::ShortTerm => "short_term",
+            TimeRange::MediumTerm => "medium_term",
+            TimeRange::LongTerm => "long_term",
+        }
+    }
+}
+
+/// Sorting, filters and pagination for the scores endpoints. Everything is done by the storage
+/// query.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ScoresQuery {
+    pub sort: Option<SortKey>,
+    /// Defaults to descending, or ascending when sorting by name
+    pub order: Option<Order>,
+    /// Only scores with an artist containing this, ignoring case
+    pub artist: Option<String>,
+    /// Only scores with an album containing this, ignoring case
+    pub album: Option<String>,
+    /// Only scores with a title, album or artist containing this, ignoring case
+    pub q: Option<String>,
+    /// Only scores that have played at least this many matches
+    pub min_games: Option<i32>,
+    pub limit: Option<usize>,
+    /// The `next` cursor of the previous page
+    pub cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Score,
+    Wins,
+    Losses,
+    Games,
+    Name,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PublishQuery {
+    /// Only write the top `limit` tracks.
+    pub limit: Option<usize>,
+    /// Name of the Spotify playlist, defaults to the playlist's name.
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+/// A request for an `Api` to send.
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    /// The path and query string, like `/api/playlists`
+    pub url: String,
+    /// JSON
+    pub body: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+/// The status of requests that Spotify refused the user's authorization for, like when it was
+/// granted before a scope was needed. Logging in again fixes it. 401 means that the session is
+/// gone instead, and 403 that the demo doesn't allow the request.
+pub const SPOTIFY_UNAUTHORIZED: u16 = 424;
+
+#[derive(Debug)]
+pub enum ApiError<E> {
+    /// The server answered with a status that isn't a success
+    Status(u16),
+    /// The response didn't have the expected shape
+    Json(serde_json::Error),
+    /// The request couldn't be sent or its response couldn't be read
+    Transport(E),
+}
+
+impl<E> ApiError<E> {
+    /// The status that the server answered with, if it answered with an error.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Status(status) => Some(*status),
+            ApiError::Json(_) | ApiError::Transport(_) => None,
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for ApiError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Status(status) => write!(f, "server answered with status {}", status),
+            ApiError::Json(e) => write!(f, "unexpected response: {}", e),
+            ApiError::Transport(e) => write!(f, "request failed: {:?}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for ApiError<E> {}
+
+/// A client for the API. Implementations send requests with their credentials, and the endpoints
+/// are built on top.
+///
+/// Futures aren't `Send` since the browser's aren't.
+#[async_trait(?Send)]
+pub trait Api {
+    type Error;
+
+    async fn send(&self, request: Request) -> Result<Response, Self::Error>;
+
+    /// Start a session. The client's credentials are the authorization code from logging in with
+    /// Spotify, or the demo credentials.
+    async fn login(&self) -> Result<Login, ApiError<Self::Error>> {
+        json(self, Method::Post, "/api/login", None).await
+    }
+
+    async fn get_playlists(&self) -> Result<Playlists, ApiError<Self::Error>> {
+        json(self, Method::Get, "/api/playlists", None).await
+    }
+
+    async fn delete_playlist(&self, id: &str) -> Result<(), ApiError<Self::Error>> {
+        let url = format!("/api/playlists/{}", segment(id));
+        call(self, Method::Delete, &url, None).await.map(drop)
+    }
+
+    /// Get a playlist's scores, which are paged if there's a limit.
+    async fn get_playlist_scores(
+        &self,
+        id: &str,
+        query: &ScoresQuery,
+    ) -> Result<Scores, ApiError<Self::Error>> {
+        let url = with_query(&format!("/api/playlists/{}/scores", segment(id)), query);
+        json(self, Method::Get, &url, None).await
+    }
+
+    async fn sync_playlist(&self, id: &str) -> Result<PlaylistSync, ApiError<Self::Error>> {
+        let url = format!("/api/playlists/{}/sync", segment(id));
+        json(self, Method::Post, &url, None).await
+    }
+
+    async fn publish_playlist(
+        &self,
+        id: &str,
+        query: &PublishQuery,
+    ) -> Result<PublishedPlaylist, ApiError<Self::Error>> {
+        let url = with_query(&format!("/api/playlists/{}/spotify", segment(id)), query);
+        json(self, Method::Post, &url, None).await
+    }
+
+    async fn import(&self, query: &ActionQuery) -> Result<(), ApiError<Self::Error>> {
+        let url = with_query("/api/", query);
+        call(self, Method::Post, &url, None).await.map(drop)
+    }
+
+    async fn elo(&self, query: &EloQuery) -> Result<Match, ApiError<Self::Error>> {
+        let url = with_query("/api/elo", query);
+        json(self, Method::Post, &url, None).await
+    }
+
+    /// Take back a match. Fails with 409 Conflict if its tracks have played since.
+    async fn undo_match(&self, id: &str) -> Result<(), ApiError<Self::Error>> {
+        let url = format!("/api/matches/{}", segment(id));
+        call(self, Method::Delete, &url, None).await.map(drop)
+    }
+
+    async fn get_artist_stats(
+        &self,
+        query: &PlaylistQuery,
+    ) -> Result<Leaderboard, ApiError<Self::Error>> {
+        let url = with_query("/api/stats/artists", query);
+        json(self, Method::Get, &url, None).await
+    }
+
+    async fn get_album_stats(
+        &self,
+        query: &PlaylistQuery,
+    ) -> Result<Leaderboard, ApiError<Self::Error>> {
+        let url = with_query("/api/stats/albums", query);
+        json(self, Method::Get, &url, None).await
+    }
+
+    async fn get_track(
+        &self,
+        id: &str,
+        query: &PlaylistQuery,
+    ) -> Result<TrackDetails, ApiError<Self::Error>> {
+        let url = with_query(&format!("/api/tracks/{}", segment(id)), query);
+        json(self, Method::Get, &url, None).await
+    }
+
+    async fn get_track_history(
+        &self,
+        id: &str,
+        query: &PlaylistQuery,
+    ) -> Result<RatingHistory, ApiError<Self::Error>> {
+        let url = with_query(&format!("/api/tracks/{}/history", segment(id)), query);
+        json(self, Method::Get, &url, None).await
+    }
+
+    async fn get_duplicates(&self) -> Result<Duplicates, ApiError<Self::Error>> {
+        json(self, Method::Get, "/api/scores/duplicates", None).await
+    }
+
+    /// Merge duplicate scores, returning the kept score.
+    async fn merge_scores(&self, request: &MergeRequest) -> Result<Score, ApiError<Self::Error>> {
+        let body = serde_json::to_string(request).map_err(ApiError::Json)?;
+        json(self, Method::Post, "/api/scores/merge", Some(body)).await
+    }
+
+    async fn get_spotify_playlists(&self) -> Result<Playlists, ApiError<Self::Error>> {
+        json(self, Method::Get, "/api/spotify/playlists", None).await
+    }
+
+    async fn get_library(&self) -> Result<Library, ApiError<Self::Error>> {
+        json(self, Method::Get, "/api/library", None).await
+    }
+
+    /// Get a clip of a library track, given its `ProviderId`.
+    async fn create_clip(&self, track_id: &str) -> Result<Clip, ApiError<Self::Error>> {
+        let url = format!(
+            "/api/library/tracks/{}/clip",
+            segment(&ProviderId::parse(track_id).id)
+        );
+        json(self, Method::Post, &url, None).await
+    }
+}
+
+/// Percent-encode an id as a single path segment. Everything but unreserved characters is
+/// encoded, so ids with slashes or spaces reach the server intact.
+pub fn segment(id: &str) -> String {
+    id.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Append a query string, unless every field of `query` is empty.
+fn with_query(path: &str, query: &impl Serialize) -> String {
+    let query = serde_urlencoded::to_string(query).expect("queries always serialize");
+    if query.is_empty() {
+        path.to_owned()
+    } else {
+        format!("{}?{}", path, query)
+    }
+}
+
+/// Send a request and check that it succeeded.
+async fn call<A: Api + ?Sized>(
+    api: &A,
+    method: Method,
+    url: &str,
+    body: Option<String>,
+) -> Result<Response, ApiError<A::Error>> {
+    let url = url.to_owned();
+    let request = Request { method, url, body };
+    let response = api.send(request).await.map_err(ApiError::Transport)?;
+    if (200..300).contains(&response.status) {
+        Ok(response)
+    } else {
+        Err(ApiError::Status(response.status))
+    }
+}
+
+/// Send a request and decode its JSON response.
+async fn json<A: Api + ?Sized, T: DeserializeOwned>(
+    api: &A,
+    method: Method,
+    url: &str,
+    body: Option<String>,
+) -> Result<T, ApiError<A::Error>> {
+    let response = call(api, method, url, body).await?;
+    serde_json::from_str(&response.body).map_err(ApiError::Json)
+}
@@ -0,0 +1,521 @@
+//! Finding and merging scores of the same recording, like an album track and its single or a
+//! remaster.
+
+use crate::history::Match;
+use crate::{Playlist, Score};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Releases of the same recording can differ by a few seconds of silence.
+const DURATION_TOLERANCE_MS: u32 = 5_000;
+
+/// Words that mark another release of the same recording, like "Remastered 2011".
+const RELEASE_DETAILS: &[&str] = &[
+    "remaster",
+    "remastered",
+    "version",
+    "edit",
+    "mono",
+    "stereo",
+    "single",
+    "deluxe",
+    "bonus",
+    "feat",
+    "ft",
+    "featuring",
+    "with",
+];
+
+/// Words that mark a different recording, even if the detail also has `RELEASE_DETAILS`.
+const RECORDING_DETAILS: &[&str] = &[
+    "live",
+    "acoustic",
+    "remix",
+    "remixed",
+    "instrumental",
+    "demo",
+];
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Duplicates {
+    pub groups: Vec<DuplicateGroup>,
+}
+
+/// Scores that are probably the same recording, most played first.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DuplicateGroup {
+    pub reason: DuplicateReason,
+    pub scores: Vec<Score>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateReason {
+    /// Every score has the same ISRC
+    Isrc,
+    /// The titles, artists and durations match
+    Metadata,
+}
+
+/// Merge the scores in `merge` into `keep`. Scores are ids of the same user and rating scope.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MergeRequest {
+    pub keep: String,
+    pub merge: Vec<String>,
+}
+
+/// Group scores that are probably the same recording. Only scores in the same rating scope are
+/// grouped, since they are the ones that can be merged.
+pub fn find_duplicates(scores: &[Score]) -> Vec<DuplicateGroup> {
+    let mut sets = DisjointSets::new(scores.len());
+    let mut by_isrc = HashMap::new();
+    let mut by_metadata: HashMap<_, Vec<usize>> = HashMap::new();
+    for (i, score) in scores.iter().enumerate() {
+        if let Some(isrc) = &score.isrc {
+            let key = (&score.playlist_id, isrc.to_uppercase());
+            match by_isrc.get(&key) {
+                Some(&j) => sets.union(i, j),
+                None => {
+                    by_isrc.insert(key, i);
+                }
+            }
+        }
+        let artist = score
+            .artists
+            .first()
+            .map(|a| normalize(a))
+            .unwrap_or_default();
+        by_metadata
+            .entry((&score.playlist_id, normalize_title(&score.track), artist))
+            .or_default()
+            .push(i);
+    }
+    for same in by_metadata.values() {
+        for (n, &i) in same.iter().enumerate() {
+            for &j in &same[n + 1..] {
+                if same_length(&scores[i], &scores[j]) {
+                    sets.union(i, j);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<&Score>> = HashMap::new();
+    for (i, score) in scores.iter().enumerate() {
+        groups.entry(sets.find(i)).or_default().push(score);
+    }
+    let mut groups: Vec<_> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort_by_key(|s| (-(s.wins + s.losses), -s.score));
+            let isrc = group[0].isrc.as_deref();
+            let same_isrc = |s: &&Score| match (s.isrc.as_deref(), isrc) {
+                (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                _ => false,
+            };
+            let reason = if group.iter().all(same_isrc) {
+                DuplicateReason::Isrc
+            } else {
+                DuplicateReason::Metadata
+            };
+            DuplicateGroup {
+                reason,
+                scores: group.into_iter().cloned().collect(),
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.scores[0].track.cmp(&b.scores[0].track));
+    groups
+}
+
+/// Combine the match records of duplicates. The rating is the average of the scores weighted by
+/// how many matches they've played, and missing details are taken from the duplicates. Scores that
+/// were already merged into `keep` are skipped, so a merge can be retried.
+pub fn merge_scores(keep: Score, merge: &[Score]) -> Score {
+    let merge: Vec<_> = merge
+        .iter()
+        .filter(|s| !keep.merged.contains(&s.id))
+        .collect();
+    let all = || std::iter::once(&keep).chain(merge.iter().copied());
+    let matches: i64 = all().map(|s| i64::from(s.wins + s.losses)).sum();
+    let score = if matches == 0 {
+        keep.score
+    } else {
+        let total: i64 = all()
+            .map(|s| i64::from(s.score) * i64::from(s.wins + s.losses))
+            .sum();
+        (total / matches) as i32
+    };
+    let wins = all().map(|s| s.wins).sum();
+    let losses = all().map(|s| s.losses).sum();
+    let release_year = all().find_map(|s| s.release_year);
+    let artwork_url = all().find_map(|s| s.artwork_url.clone());
+    let duration_ms = all().find_map(|s| s.duration_ms);
+    let isrc = all().find_map(|s| s.isrc.clone());
+    let mut merged = keep.merged.clone();
+    merged.extend(merge.iter().map(|s| s.id.clone()));
+    Score {
+        score,
+        wins,
+        losses,
+        games: wins + losses,
+        release_year,
+        artwork_url,
+        duration_ms,
+        isrc,
+        merged,
+        ..keep
+    }
+}
+
+impl Playlist {
+    /// Replace merged tracks with the track that was kept, returning whether anything changed.
+    pub fn replace_tracks(&mut self, merged: &[String], keep: &str) -> bool {
+        if !self
+            .tracks
+            .iter()
+            .chain(&self.archived)
+            .any(|t| merged.contains(t))
+        {
+            return false;
+        }
+        let mut has_keep = self.tracks.iter().any(|t| t == keep);
+        let mut tracks = Vec::with_capacity(self.tracks.len());
+        for track in self.tracks.drain(..) {
+            if !merged.contains(&track) {
+                tracks.push(track);
+            } else if !has_keep {
+                // The first merged track takes the place of the kept one
+                tracks.push(keep.to_owned());
+                has_keep = true;
+            }
+        }
+        self.tracks = tracks;
+        // A track that was only archived stays archived under the kept id
+        let merged_archived = self.archived.iter().any(|t| merged.contains(t));
+        self.archived.retain(|t| !merged.contains(t));
+        if merged_archived && !has_keep && !self.archived.iter().any(|t| t == keep) {
+            self.archived.push(keep.to_owned());
+        }
+        true
+    }
+}
+
+impl Match {
+    /// Replace merged tracks with the track that was kept, returning whether anything changed.
+    pub fn replace_track(&mut self, merged: &[String], keep: &Score) -> bool {
+        let mut changed = false;
+        for track in [&mut self.winner, &mut self.loser] {
+            if merged.contains(&track.track_id) {
+                track.track_id = keep.track_id.clone();
+                track.track = keep.track.clone();
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn same_length(a: &Score, b: &Score) -> bool {
+    match (a.duration_ms, b.duration_ms) {
+        (Some(a), Some(b)) => a.max(b) - a.min(b) <= DURATION_TOLERANCE_MS,
+        // Tracks from playlist files don't have a duration
+        _ => true,
+    }
+}
+
+/// Strip release details from a title, so "Song - Remastered 2011" and "Song (Single Version)"
+/// both become "song".
+pub fn normalize_title(title: &str) -> String {
+    let mut title = title.to_lowercase();
+    // Spotify appends details after a dash
+    if let Some((head, detail)) = title.split_once(" - ") {
+        if is_release_detail(detail) {
+            title = head.to_owned();
+        }
+    }
+    // Other sources put them in brackets
+    let mut stripped = String::with_capacity(title.len());
+    let mut rest = title.as_str();
+    while let Some(start) = rest.find(|c| c == '(' || c == '[') {
+        let close = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        let end = match rest[start..].find(close) {
+            Some(end) => start + end,
+            None => break,
+        };
+        stripped.push_str(&rest[..start]);
+        if !is_release_detail(&rest[start + 1..end]) {
+            stripped.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    stripped.push_str(rest);
+    normalize(&stripped)
+}
+
+fn is_release_detail(detail: &str) -> bool {
+    let detail = normalize(detail);
+    let has = |words: &[&str]| detail.split(' ').any(|word| words.contains(&word));
+    has(RELEASE_DETAILS) && !has(RECORDING_DETAILS)
+}
+
+/// Lowercase letters and digits separated by single spaces.
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> DisjointSets {
+        DisjointSets {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::MatchTrack;
+    use crate::RatingScope;
+
+    fn score(id: &str, track: &str, artist: &str, duration_ms: Option<u32>) -> Score {
+        Score {
+            id: id.to_owned(),
+            track_id: id.to_owned(),
+            track: track.to_owned(),
+            album: String::new(),
+            artists: vec![artist.to_owned()],
+            user_id: String::from("user"),
+            score: 1500,
+            wins: 0,
+            losses: 0,
+            games: 0,
+            release_year: None,
+            artwork_url: None,
+            duration_ms,
+            isrc: None,
+            playlist_id: None,
+            merged: Vec::new(),
+            last_match: None,
+            ttl: None,
+        }
+    }
+
+    fn played(mut score: Score, rating: i32, wins: i32, losses: i32) -> Score {
+        score.score = rating;
+        score.wins = wins;
+        score.losses = losses;
+        score.games = wins + losses;
+        score
+    }
+
+    fn ids(group: &DuplicateGroup) -> Vec<&str> {
+        let mut ids: Vec<_> = group.scores.iter().map(|s| s.id.as_str()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn strings(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|&id| String::from(id)).collect()
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(normalize_title("Song - Remastered 2011"), "song");
+        assert_eq!(normalize_title("Song (Single Version)"), "song");
+        assert_eq!(normalize_title("Song [feat. Someone]"), "song");
+        assert_eq!(normalize_title("Song - Live"), "song live");
+        assert_eq!(normalize_title("Song (Live)"), "song live");
+        assert_eq!(normalize_title("Song (Live Version)"), "song live version");
+        assert_eq!(
+            normalize_title("Song (Acoustic Edit)"),
+            "song acoustic edit"
+        );
+    }
+
+    #[test]
+    fn details_are_whole_words() {
+        // "olive" isn't "live" and "credit" isn't "edit"
+        assert_eq!(normalize_title("Song (Olive Version)"), "song");
+        assert_eq!(normalize_title("Song - Credit"), "song credit");
+        assert_eq!(normalize_title("Song (Withering)"), "song withering");
+        assert_eq!(normalize_title("Song (Demolition)"), "song demolition");
+        assert_eq!(normalize_title("Song (Unclosed"), "song unclosed");
+    }
+
+    #[test]
+    fn duplicates_by_isrc() {
+        let mut a = score("a", "Song", "Artist", None);
+        let mut b = score("b", "Another Title", "Someone", None);
+        a.isrc = Some(String::from("usabc1234567"));
+        b.isrc = Some(String::from("USABC1234567"));
+        let groups = find_duplicates(&[a, b, score("c", "Other", "Artist", None)]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reason, DuplicateReason::Isrc);
+        assert_eq!(ids(&groups[0]), ["a", "b"]);
+    }
+
+    #[test]
+    fn duplicates_by_metadata() {
+        let scores = [
+            played(score("a", "Song", "Artist", Some(200_000)), 1500, 1, 1),
+            played(
+                score("b", "Song - 2011 Remaster", "artist", Some(203_000)),
+                1600,
+                5,
+                0,
+            ),
+            score("c", "Song - Live", "Artist", Some(200_000)),
+            score("d", "Song", "Artist", Some(260_000)),
+            score("e", "Song", "Other Artist", Some(200_000)),
+        ];
+        let groups = find_duplicates(&scores);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reason, DuplicateReason::Metadata);
+        // Most played first
+        assert_eq!(groups[0].scores[0].id, "b");
+        assert_eq!(ids(&groups[0]), ["a", "b"]);
+    }
+
+    #[test]
+    fn duplicates_without_durations() {
+        let groups = find_duplicates(&[
+            score("a", "Song", "Artist", None),
+            score("b", "Song (Deluxe)", "Artist", Some(200_000)),
+        ]);
+        assert_eq!(groups.len(), 1);
+    }
+
+    #[test]
+    fn duplicates_stay_in_their_scope() {
+        let mut b = score("b", "Song", "Artist", None);
+        b.playlist_id = Some(String::from("playlist"));
+        let groups = find_duplicates(&[score("a", "Song", "Artist", None), b]);
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn merging() {
+        let mut b = played(score("b", "Song", "Artist", Some(200_000)), 1600, 2, 1);
+        b.isrc = Some(String::from("USABC1234567"));
+        let merged = merge_scores(
+            played(score("a", "Song", "Artist", None), 1500, 1, 0),
+            &[b, played(score("c", "Song", "Artist", None), 1200, 0, 0)],
+        );
+        assert_eq!(merged.id, "a");
+        // (1500 * 1 + 1600 * 3) / 4
+        assert_eq!(merged.score, 1575);
+        assert_eq!((merged.wins, merged.losses, merged.games), (3, 1, 4));
+        assert_eq!(merged.duration_ms, Some(200_000));
+        assert_eq!(merged.isrc.as_deref(), Some("USABC1234567"));
+        assert_eq!(merged.merged, ["b", "c"]);
+    }
+
+    #[test]
+    fn merging_unplayed_scores_keeps_the_rating() {
+        let merged = merge_scores(
+            score("a", "Song", "Artist", None),
+            &[played(score("b", "Song", "Artist", None), 1700, 0, 0)],
+        );
+        assert_eq!(merged.score, 1500);
+    }
+
+    #[test]
+    fn merging_again_skips_merged_scores() {
+        let b = played(score("b", "Song", "Artist", None), 1600, 1, 0);
+        let merged = merge_scores(
+            played(score("a", "Song", "Artist", None), 1500, 1, 0),
+            std::slice::from_ref(&b),
+        );
+        let again = merge_scores(merged.clone(), &[b]);
+        assert_eq!(again.score, merged.score);
+        assert_eq!((again.wins, again.games), (2, 2));
+        assert_eq!(again.merged, ["b"]);
+    }
+
+    #[test]
+    fn replacing_tracks() {
+        let mut playlist = Playlist {
+            id: String::from("playlist"),
+            playlist_id: String::from("playlist"),
+            name: String::from("Playlist"),
+            user_id: String::from("user"),
+            tracks: strings(&["x", "b", "y", "c"]),
+            archived: strings(&["d"]),
+            published_id: None,
+            rating_scope: RatingScope::Global,
+            ttl: None,
+        };
+        assert!(!playlist.replace_tracks(&strings(&["z"]), "a"));
+        assert!(playlist.replace_tracks(&strings(&["b", "c", "d"]), "a"));
+        assert_eq!(playlist.tracks, ["x", "a", "y"]);
+        assert!(playlist.archived.is_empty());
+
+        // A track that stays in the playlist isn't duplicated
+        playlist.tracks = strings(&["b", "a"]);
+        assert!(playlist.replace_tracks(&strings(&["b"]), "a"));
+        assert_eq!(playlist.tracks, ["a"]);
+
+        // An archived track stays archived
+        playlist.tracks = strings(&["x"]);
+        playlist.archived = strings(&["b"]);
+        assert!(playlist.replace_tracks(&strings(&["b"]), "a"));
+        assert_eq!(playlist.tracks, ["x"]);
+        assert_eq!(playlist.archived, ["a"]);
+    }
+
+    #[test]
+    fn replacing_match_tracks() {
+        let track = |id: &str| MatchTrack {
+            track_id: id.to_owned(),
+            track: id.to_uppercase(),
+            score: 1500,
+            change: 0,
+            previous_match: None,
+        };
+        let mut game = Match {
+            id: String::from("match"),
+            user_id: String::from("user"),
+            playlist_id: None,
+            winner: track("b"),
+            loser: track("x"),
+            draw: false,
+            timestamp: 0,
+            ttl: None,
+        };
+        let keep = score("a", "Song", "Artist", None);
+        assert!(!game.replace_track(&strings(&["c"]), &keep));
+        assert!(game.replace_track(&strings(&["b", "c"]), &keep));
+        assert_eq!(
+            (game.winner.track_id.as_str(), game.winner.track.as_str()),
+            ("a", "Song")
+        );
+        assert_eq!(game.loser.track_id, "x");
+    }
+}
@@ -0,0 +1,243 @@
+//! The log of matches, and how a score's rating changed over them.
+
+use crate::Score;
+use azure_data_cosmos::prelude::CosmosEntity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A match that was played, stored in the matches container.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Match {
+    pub id: String,
+    pub user_id: String,
+    /// The playlist whose own scores were changed, if they aren't shared. See `RatingScope`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_id: Option<String>,
+    pub winner: MatchTrack,
+    pub loser: MatchTrack,
+    /// Whether the match was a tie, in which case the winner is just the first track. Ties change
+    /// ratings but not records.
+    #[serde(default)]
+    pub draw: bool,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Seconds until the document expires, used for demo sandboxes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i32>,
+}
+
+impl<'a> CosmosEntity<'a> for Match {
+    type Entity = &'a str;
+
+    fn partition_key(&'a self) -> Self::Entity {
+        self.user_id.as_ref()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MatchTrack {
+    pub track_id: String,
+    pub track: String,
+    /// The rating after the match
+    pub score: i32,
+    pub change: i32,
+    /// The score's latest match before this one, which becomes its latest again if this one is
+    /// undone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_match: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RatingHistory {
+    pub score: Score,
+    /// The rating after each match, oldest first
+    pub points: Vec<RatingPoint>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RatingPoint {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub score: i32,
+    pub change: i32,
+    pub won: bool,
+    #[serde(default)]
+    pub draw: bool,
+    pub opponent: MatchTrack,
+}
+
+/// Where a score stands with a match that's being undone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UndoState {
+    /// The match is still the score's latest, so it can be reverted
+    Latest,
+    /// The score was already reverted by an undo that failed part way
+    Reverted,
+    /// The score has played since, so the match can't be undone
+    PlayedSince,
+}
+
+impl Match {
+    /// Where `score`, the score of `track` in this match, stands with undoing this match.
+    pub fn undo_state(&self, track: &MatchTrack, score: &Score) -> UndoState {
+        let reverted = score.score == track.score - track.change;
+        match &score.last_match {
+            Some(id) if *id == self.id => UndoState::Latest,
+            Some(_) if score.last_match == track.previous_match && reverted => UndoState::Reverted,
+            // Scores from before matches were tracked can only be compared by rating
+            None if score.score == track.score => UndoState::Latest,
+            None if track.previous_match.is_none() && reverted => UndoState::Reverted,
+            _ => UndoState::PlayedSince,
+        }
+    }
+
+    /// The rating of `track_id` after this match and who it played, if it played in this match.
+    pub fn point(&self, track_id: &str) -> Option<RatingPoint> {
+        let (own, opponent, won) = if self.winner.track_id == track_id {
+            (&self.winner, &self.loser, !self.draw)
+        } else if self.loser.track_id == track_id {
+            (&self.loser, &self.winner, false)
+        } else {
+            return None;
+        };
+        Some(RatingPoint {
+            timestamp: self.timestamp,
+            score: own.score,
+            change: own.change,
+            won,
+            draw: self.draw,
+            opponent: opponent.clone(),
+        })
+    }
+}
+
+/// Follow a score's rating through the matches it played, in the order they were played.
+pub fn rating_history(score: Score, matches: &[Match]) -> RatingHistory {
+    let mut points: Vec<_> = matches
+        .iter()
+        .filter(|m| m.playlist_id == score.playlist_id)
+        .filter_map(|m| m.point(&score.track_id))
+        .collect();
+    points.sort_by_key(|p| p.timestamp);
+    RatingHistory { score, points }
+}
+
+/// A track's record against one opponent.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HeadToHead {
+    pub track_id: String,
+    pub track: String,
+    pub wins: i32,
+    pub losses: i32,
+    #[serde(default)]
+    pub draws: i32,
+}
+
+/// Count a track's wins, losses and draws against each of its opponents, most played first.
+pub fn head_to_head(track_id: &str, matches: &[Match]) -> Vec<HeadToHead> {
+    let mut opponents: Vec<HeadToHead> = Vec::new();
+    let mut index = HashMap::new();
+    for point in matches.iter().filter_map(|m| m.point(track_id)) {
+        let i = *index
+            .entry(point.opponent.track_id.clone())
+            .or_insert_with(|| {
+                opponents.push(HeadToHead {
+                    track_id: point.opponent.track_id.clone(),
+                    track: point.opponent.track.clone(),
+                    wins: 0,
+                    losses: 0,
+                    draws: 0,
+                });
+                opponents.len() - 1
+            });
+        if point.draw {
+            opponents[i].draws += 1;
+        } else if point.won {
+            opponents[i].wins += 1;
+        } else {
+            opponents[i].losses += 1;
+        }
+    }
+    opponents.sort_by(|a, b| {
+        (b.wins + b.losses + b.draws)
+            .cmp(&(a.wins + a.losses + a.draws))
+            .then_with(|| a.track.cmp(&b.track))
+    });
+    opponents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(rating: i32, last_match: Option<&str>) -> Score {
+        Score {
+            id: String::from("track"),
+            track_id: String::from("track"),
+            track: String::from("Track"),
+            album: String::new(),
+            artists: Vec::new(),
+            user_id: String::from("user"),
+            score: rating,
+            wins: 1,
+            losses: 0,
+            games: 1,
+            release_year: None,
+            artwork_url: None,
+            duration_ms: None,
+            isrc: None,
+            playlist_id: None,
+            merged: Vec::new(),
+            last_match: last_match.map(String::from),
+            ttl: None,
+        }
+    }
+
+    fn played(previous_match: Option<&str>) -> Match {
+        let track = |track_id: &str, score, change| MatchTrack {
+            track_id: track_id.to_owned(),
+            track: track_id.to_owned(),
+            score,
+            change,
+            previous_match: previous_match.map(String::from),
+        };
+        Match {
+            id: String::from("match"),
+            user_id: String::from("user"),
+            playlist_id: None,
+            winner: track("track", 1516, 16),
+            loser: track("other", 1484, -16),
+            draw: false,
+            timestamp: 0,
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn latest_matches_can_be_undone() {
+        let played = played(Some("earlier"));
+        let state = |s| played.undo_state(&played.winner, &s);
+        assert_eq!(state(score(1516, Some("match"))), UndoState::Latest);
+        // The id decides, even if a later match left the same rating
+        assert_eq!(state(score(1516, Some("later"))), UndoState::PlayedSince);
+        assert_eq!(state(score(1500, Some("earlier"))), UndoState::Reverted);
+        assert_eq!(state(score(1532, Some("earlier"))), UndoState::PlayedSince);
+    }
+
+    #[test]
+    fn first_matches_revert_to_no_match() {
+        let played = played(None);
+        let state = |s| played.undo_state(&played.winner, &s);
+        assert_eq!(state(score(1516, Some("match"))), UndoState::Latest);
+        assert_eq!(state(score(1500, None)), UndoState::Reverted);
+        assert_eq!(state(score(1500, Some("later"))), UndoState::PlayedSince);
+    }
+
+    #[test]
+    fn untracked_scores_are_compared_by_rating() {
+        let played = played(None);
+        let state = |s| played.undo_state(&played.winner, &s);
+        assert_eq!(state(score(1516, None)), UndoState::Latest);
+        assert_eq!(state(score(1540, None)), UndoState::PlayedSince);
+    }
+}
@@ -1,14 +1,140 @@
 use azure_data_cosmos::prelude::CosmosEntity;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub mod api;
+pub mod dedupe;
+pub mod history;
+pub mod stats;
+
+/// The provider of ids without a prefix.
+pub const SPOTIFY: &str = "spotify";
+/// The provider of tracks from the server's music library.
+pub const LIBRARY: &str = "library";
+/// The rating of a track that hasn't played any matches.
+pub const INITIAL_SCORE: i32 = 1500;
+/// The Spotify id that a user's Liked Songs are imported with.
+pub const SAVED_TRACKS: &str = "saved-tracks";
+
+/// A track or playlist id qualified by the music provider it comes from, written as
+/// `<provider>:<id>`.
+///
+/// Spotify ids predate other providers and are stored without a prefix, so existing scores keep
+/// matching when their tracks are imported again.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ProviderId {
+    pub provider: String,
+    pub id: String,
+}
+
+impl ProviderId {
+    pub fn new(provider: &str, id: &str) -> ProviderId {
+        ProviderId {
+            provider: provider.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    pub fn parse(qualified: &str) -> ProviderId {
+        match qualified.split_once(':') {
+            Some((provider, id)) => ProviderId::new(provider, id),
+            None => ProviderId::new(SPOTIFY, qualified),
+        }
+    }
+
+    pub fn is_spotify(&self) -> bool {
+        self.provider == SPOTIFY
+    }
+
+    /// The Spotify id that a user's top tracks over a time range are imported with.
+    pub fn top_tracks(time_range: api::TimeRange) -> ProviderId {
+        ProviderId::new(SPOTIFY, &format!("top-{}", time_range.as_str()))
+    }
+
+    /// The source that a playlist imported from this id is synced with.
+    pub fn import_source(&self) -> api::ImportSource {
+        use api::{ImportSource, SavedItems, TimeRange};
+        if self.is_spotify() {
+            if self.id == SAVED_TRACKS {
+                return ImportSource::Saved(SavedItems::Tracks);
+            }
+            for time_range in [
+                TimeRange::ShortTerm,
+                TimeRange::MediumTerm,
+                TimeRange::LongTerm,
+            ] {
+                if *self == ProviderId::top_tracks(time_range) {
+                    return ImportSource::Top(time_range);
+                }
+            }
+        }
+        ImportSource::Playlist(self.to_string())
+    }
+
+    /// Where an imported Spotify playlist can be opened. Top tracks only exist in the API, so
+    /// they have no page to link to.
+    pub fn spotify_url(&self) -> Option<String> {
+        if !self.is_spotify() || self.id.starts_with("top-") {
+            None
+        } else if self.id == SAVED_TRACKS {
+            Some(String::from("https://open.spotify.com/collection/tracks"))
+        } else {
+            Some(format!("https://open.spotify.com/playlist/{}", self.id))
+        }
+    }
+}
+
+impl fmt::Display for ProviderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_spotify() {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{}:{}", self.provider, self.id)
+        }
+    }
+}
+
+/// The response to logging in. The session itself is kept in an HttpOnly cookie, out of reach of
+/// scripts.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Login {
+    /// The user who logged in
+    pub user_id: String,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Scores {
     pub scores: Vec<Score>,
+    /// The cursor of the next page, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// A track's score with where it ranks and who it has played.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TrackDetails {
+    pub score: Score,
+    /// The playlists with the track, rated in their own scope
+    pub playlists: Vec<PlaylistRank>,
+    /// The most played opponents
+    pub opponents: Vec<history::HeadToHead>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlaylistRank {
+    pub id: String,
+    pub name: String,
+    /// 1 is the highest rated track
+    pub rank: usize,
+    pub tracks: usize,
+    pub score: i32,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Score {
     pub id: String,
+    /// A `ProviderId`
     pub track_id: String,
     pub track: String,
     pub album: String,
@@ -17,6 +143,32 @@ pub struct Score {
     pub score: i32,
     pub wins: i32,
     pub losses: i32,
+    /// Wins plus losses, stored so that scores can be sorted by it. Scores from before it was
+    /// stored count as 0 until their next match.
+    #[serde(default)]
+    pub games: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_year: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artwork_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u32>,
+    /// International Standard Recording Code, which identifies a recording across releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isrc: Option<String>,
+    /// The only playlist that uses this score, if it isn't shared. See `RatingScope`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_id: Option<String>,
+    /// The ids of the duplicates that were merged into this score, so that retrying a merge
+    /// doesn't count them twice
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<String>,
+    /// The id of the latest match that changed this score, which is the one that can be undone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_match: Option<String>,
+    /// Seconds until the document expires, used for demo sandboxes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i32>,
 }
 
 impl<'a> CosmosEntity<'a> for Score {
@@ -35,10 +187,22 @@ pub struct Playlists {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Playlist {
     pub id: String,
+    /// The `ProviderId` of the source playlist
     pub playlist_id: String,
     pub name: String,
     pub user_id: String,
     pub tracks: Vec<String>,
+    /// Tracks that have been removed from the source playlist. Their scores are kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archived: Vec<String>,
+    /// The Spotify playlist that the ranking was last written to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_id: Option<String>,
+    #[serde(default, skip_serializing_if = "RatingScope::is_global")]
+    pub rating_scope: RatingScope,
+    /// Seconds until the document expires, used for demo sandboxes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<i32>,
 }
 
 impl<'a> CosmosEntity<'a> for Playlist {
@@ -48,3 +212,227 @@ impl<'a> CosmosEntity<'a> for Playlist {
         self.user_id.as_ref()
     }
 }
+
+impl Playlist {
+    /// The id of the score that rates a track in this playlist.
+    pub fn score_id(&self, track_id: &str) -> String {
+        match self.rating_scope {
+            RatingScope::Global => track_id.to_owned(),
+            RatingScope::Playlist => format!("{}|{}", self.id, track_id),
+        }
+    }
+
+    /// Move a score into this playlist's rating scope.
+    pub fn scope_score(&self, score: Score) -> Score {
+        Score {
+            id: self.score_id(&score.track_id),
+            playlist_id: match self.rating_scope {
+                RatingScope::Global => None,
+                RatingScope::Playlist => Some(self.id.clone()),
+            },
+            ..score
+        }
+    }
+
+    /// The ids of every score this playlist uses, including the scores of archived tracks.
+    pub fn score_ids(&self) -> HashSet<String> {
+        self.tracks
+            .iter()
+            .chain(&self.archived)
+            .map(|t| self.score_id(t))
+            .collect()
+    }
+}
+
+/// Whether a track's rating is shared by every playlist it's in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatingScope {
+    /// A user has one rating per track. Matches in any playlist change it.
+    Global,
+    /// Each playlist rates its tracks separately.
+    Playlist,
+}
+
+impl RatingScope {
+    pub fn is_global(&self) -> bool {
+        *self == RatingScope::Global
+    }
+}
+
+impl Default for RatingScope {
+    fn default() -> RatingScope {
+        RatingScope::Global
+    }
+}
+
+/// Count the playlists that use each score, keyed by score id.
+pub fn score_references<'a>(
+    playlists: impl IntoIterator<Item = &'a Playlist>,
+) -> HashMap<String, usize> {
+    let mut references = HashMap::new();
+    for playlist in playlists {
+        for id in playlist.score_ids() {
+            *references.entry(id).or_insert(0) += 1;
+        }
+    }
+    references
+}
+
+/// The scores that can be deleted along with a playlist, because none of the remaining playlists
+/// use them.
+pub fn orphaned_scores(deleted: &Playlist, remaining: &[Playlist]) -> Vec<String> {
+    let references = score_references(remaining.iter().filter(|p| p.id != deleted.id));
+    deleted
+        .score_ids()
+        .into_iter()
+        .filter(|id| !references.contains_key(id))
+        .collect()
+}
+
+/// The tracks that changed when a playlist was synced with its source.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PlaylistSync {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A Spotify playlist that a playlist's ranking was written to.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PublishedPlaylist {
+    pub spotify_id: String,
+    pub url: String,
+    pub tracks: usize,
+}
+
+/// The albums and folders of the server's music library.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Library {
+    pub albums: Vec<LibraryEntry>,
+    pub folders: Vec<LibraryEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LibraryEntry {
+    /// A `ProviderId` that can be imported as an album or playlist
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    pub tracks: usize,
+}
+
+/// A short-lived link to part of a library track.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Clip {
+    /// Ends with a media fragment, like `#t=60,90`, that selects the part to play
+    pub url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::TimeRange;
+
+    fn playlist(id: &str, tracks: &[&str], archived: &[&str], scope: RatingScope) -> Playlist {
+        let ids = |tracks: &[&str]| tracks.iter().map(|&t| String::from(t)).collect();
+        Playlist {
+            id: id.to_owned(),
+            playlist_id: id.to_owned(),
+            name: id.to_owned(),
+            user_id: String::from("user"),
+            tracks: ids(tracks),
+            archived: ids(archived),
+            published_id: None,
+            rating_scope: scope,
+            ttl: None,
+        }
+    }
+
+    fn sorted(ids: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut ids: Vec<_> = ids.into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn score_ids_include_archived_tracks() {
+        let global = playlist("a", &["1", "2"], &["3"], RatingScope::Global);
+        assert_eq!(sorted(global.score_ids()), ["1", "2", "3"]);
+        let scoped = playlist("b", &["1"], &["3"], RatingScope::Playlist);
+        assert_eq!(sorted(scoped.score_ids()), ["b|1", "b|3"]);
+    }
+
+    #[test]
+    fn references_count_playlists() {
+        let playlists = [
+            playlist("a", &["1", "2"], &[], RatingScope::Global),
+            playlist("b", &["2"], &["3"], RatingScope::Global),
+            playlist("c", &["2"], &[], RatingScope::Playlist),
+        ];
+        let references = score_references(&playlists);
+        assert_eq!(references["1"], 1);
+        assert_eq!(references["2"], 2);
+        assert_eq!(references["3"], 1);
+        assert_eq!(references["c|2"], 1);
+        assert_eq!(references.len(), 4);
+    }
+
+    #[test]
+    fn orphans_are_only_used_by_the_deleted_playlist() {
+        let deleted = playlist("a", &["1", "2"], &["3"], RatingScope::Global);
+        let remaining = [
+            deleted.clone(),
+            playlist("b", &["2"], &[], RatingScope::Global),
+            playlist("c", &["3"], &[], RatingScope::Playlist),
+        ];
+        // The deleted playlist is ignored if it's still listed
+        assert_eq!(sorted(orphaned_scores(&deleted, &remaining)), ["1", "3"]);
+        let scoped = playlist("c", &["3"], &[], RatingScope::Playlist);
+        assert_eq!(orphaned_scores(&scoped, &remaining), ["c|3"]);
+        assert_eq!(orphaned_scores(&scoped, &[]), ["c|3"]);
+    }
+
+    #[test]
+    fn spotify_urls() {
+        assert_eq!(
+            ProviderId::parse("37i9dQZF1DX49jUV2NfGku").spotify_url(),
+            Some(String::from(
+                "https://open.spotify.com/playlist/37i9dQZF1DX49jUV2NfGku"
+            ))
+        );
+        assert_eq!(
+            ProviderId::parse(SAVED_TRACKS).spotify_url(),
+            Some(String::from("https://open.spotify.com/collection/tracks"))
+        );
+        assert_eq!(
+            ProviderId::top_tracks(TimeRange::LongTerm).to_string(),
+            "top-long_term"
+        );
+        assert_eq!(
+            ProviderId::top_tracks(TimeRange::ShortTerm).spotify_url(),
+            None
+        );
+        assert_eq!(ProviderId::parse("library:albums/a").spotify_url(), None);
+    }
+
+    #[test]
+    fn import_sources() {
+        assert!(matches!(
+            ProviderId::parse(SAVED_TRACKS).import_source(),
+            api::ImportSource::Saved(api::SavedItems::Tracks)
+        ));
+        assert!(matches!(
+            ProviderId::parse("top-medium_term").import_source(),
+            api::ImportSource::Top(TimeRange::MediumTerm)
+        ));
+        assert!(matches!(
+            ProviderId::parse("top-next_year").import_source(),
+            api::ImportSource::Playlist(id) if id == "top-next_year"
+        ));
+        assert!(matches!(
+            ProviderId::parse("library:folders/a").import_source(),
+            api::ImportSource::Playlist(id) if id == "library:folders/a"
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+//! Leaderboards of artists and albums, built from the scores of their tracks.
+
+use crate::{Score, INITIAL_SCORE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How many matches at the initial rating every artist or album starts with, so that a single
+/// lucky win doesn't put them at the top.
+const PRIOR_GAMES: i64 = 10;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Leaderboard {
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+/// The combined scores of an artist's or album's tracks.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    /// The album's artist, for albums
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    pub tracks: usize,
+    pub games: i32,
+    pub mean: i32,
+    pub median: i32,
+    /// The rating of every match the tracks played, pulled towards the initial rating when
+    /// there are only a few
+    pub weighted: i32,
+}
+
+/// Rank artists by their tracks. A track with several artists counts for each of them.
+pub fn artists(scores: &[Score]) -> Leaderboard {
+    let mut groups: HashMap<&str, Vec<&Score>> = HashMap::new();
+    for score in scores {
+        for artist in &score.artists {
+            groups.entry(artist.as_str()).or_default().push(score);
+        }
+    }
+    leaderboard(
+        groups
+            .into_iter()
+            .map(|(artist, scores)| entry(artist.to_owned(), None, &scores)),
+    )
+}
+
+/// Rank albums by their tracks. Albums are told apart by their first artist, so that albums with
+/// a common name, like "Greatest Hits", aren't combined.
+pub fn albums(scores: &[Score]) -> Leaderboard {
+    let mut groups: HashMap<(&str, Option<&str>), Vec<&Score>> = HashMap::new();
+    for score in scores {
+        let artist = score.artists.first().map(String::as_str);
+        groups
+            .entry((score.album.as_str(), artist))
+            .or_default()
+            .push(score);
+    }
+    leaderboard(groups.into_iter().map(|((album, artist), scores)| {
+        entry(album.to_owned(), artist.map(str::to_owned), &scores)
+    }))
+}
+
+/// Sort entries by their weighted rating, highest first.
+fn leaderboard(entries: impl Iterator<Item = LeaderboardEntry>) -> Leaderboard {
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort_by(|a, b| {
+        b.weighted
+            .cmp(&a.weighted)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Leaderboard { entries }
+}
+
+fn entry(name: String, artist: Option<String>, scores: &[&Score]) -> LeaderboardEntry {
+    let mut ratings: Vec<_> = scores.iter().map(|s| s.score).collect();
+    ratings.sort_unstable();
+    let middle = ratings.len() / 2;
+    let median = if ratings.len() % 2 == 0 {
+        (ratings[middle - 1] + ratings[middle]) / 2
+    } else {
+        ratings[middle]
+    };
+    let mean = ratings.iter().map(|&r| i64::from(r)).sum::<i64>() / ratings.len() as i64;
+    let games: i64 = scores.iter().map(|s| i64::from(s.wins + s.losses)).sum();
+    let total: i64 = scores
+        .iter()
+        .map(|s| i64::from(s.score) * i64::from(s.wins + s.losses))
+        .sum();
+    let weighted = (total + PRIOR_GAMES * i64::from(INITIAL_SCORE)) / (games + PRIOR_GAMES);
+    LeaderboardEntry {
+        name,
+        artist,
+        tracks: scores.len(),
+        games: games as i32,
+        mean: mean as i32,
+        median,
+        weighted: weighted as i32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(track: &str, album: &str, artists: &[&str], score: i32, games: i32) -> Score {
+        Score {
+            id: track.to_owned(),
+            track_id: track.to_owned(),
+            track: track.to_owned(),
+            album: album.to_owned(),
+            artists: artists.iter().map(|&a| String::from(a)).collect(),
+            user_id: String::from("user"),
+            score,
+            wins: games,
+            losses: 0,
+            games,
+            release_year: None,
+            artwork_url: None,
+            duration_ms: None,
+            isrc: None,
+            playlist_id: None,
+            merged: Vec::new(),
+            last_match: None,
+            ttl: None,
+        }
+    }
+
+    fn names(leaderboard: &Leaderboard) -> Vec<&str> {
+        leaderboard
+            .entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn tracks_count_for_every_artist() {
+        let scores = [
+            score("1", "X", &["A", "B"], 1600, 10),
+            score("2", "Y", &["B"], 1400, 10),
+        ];
+        let leaderboard = artists(&scores);
+        assert_eq!(names(&leaderboard), ["A", "B"]);
+        let b = &leaderboard.entries[1];
+        assert_eq!((b.tracks, b.games, b.mean, b.median), (2, 20, 1500, 1500));
+        assert_eq!(leaderboard.entries[0].artist, None);
+    }
+
+    #[test]
+    fn albums_are_told_apart_by_artist() {
+        let scores = [
+            score("1", "Greatest Hits", &["A"], 1600, 1),
+            score("2", "Greatest Hits", &["B"], 1400, 1),
+            score("3", "Greatest Hits", &["A", "B"], 1500, 1),
+        ];
+        let leaderboard = albums(&scores);
+        assert_eq!(leaderboard.entries.len(), 2);
+        assert_eq!(leaderboard.entries[0].artist.as_deref(), Some("A"));
+        assert_eq!(leaderboard.entries[0].tracks, 2);
+    }
+
+    #[test]
+    fn few_games_are_pulled_towards_the_initial_rating() {
+        let scores = [
+            score("1", "X", &["Lucky"], 1800, 1),
+            score("2", "Y", &["Steady"], 1600, 10),
+            score("3", "Z", &["Unplayed"], 1700, 0),
+        ];
+        let leaderboard = artists(&scores);
+        assert_eq!(names(&leaderboard), ["Steady", "Lucky", "Unplayed"]);
+        let weighted: Vec<_> = leaderboard.entries.iter().map(|e| e.weighted).collect();
+        // (1600 * 10 + 1500 * 10) / 20 and (1800 + 1500 * 10) / 11
+        assert_eq!(weighted, [1550, 1527, INITIAL_SCORE]);
+    }
+
+    #[test]
+    fn medians() {
+        let scores = [
+            score("1", "X", &["A"], 1000, 1),
+            score("2", "X", &["A"], 2000, 1),
+            score("3", "X", &["A"], 1100, 1),
+            score("4", "X", &["A"], 1300, 1),
+        ];
+        assert_eq!(artists(&scores).entries[0].median, 1200);
+        assert_eq!(artists(&scores[..3]).entries[0].median, 1100);
+    }
+
+    #[test]
+    fn ties_are_sorted_by_name() {
+        let scores = [
+            score("1", "X", &["B"], 1500, 0),
+            score("2", "X", &["A"], 1500, 0),
+        ];
+        assert_eq!(names(&artists(&scores)), ["A", "B"]);
+        assert!(artists(&[]).entries.is_empty());
+    }
+}